@@ -1,7 +1,7 @@
 
+use crate::float::Float;
 use crate::ray::Ray;
 use crate::vec3::Point3;
-use std::cmp::Ordering;
 
 #[derive(Debug, Default, Copy, Clone)]
 pub struct AABB {
@@ -14,27 +14,51 @@ impl AABB {
         AABB { min, max }
     }
 
-    pub fn hit(&self, ray: Ray, t_min: f64, t_max: f64) -> bool {
-        let min = self.min.as_array();
-        let max = self.max.as_array();
-        let direction = ray.direction.as_array();
-        let origin = ray.origin.as_array();
+    /// The slab test, with the reciprocal of the ray's direction worked out once per ray rather
+    /// than once per box.
+    pub fn hit(&self, origin: [Float; 3], inv_d: [Float; 3], t_min: Float, t_max: Float) -> bool {
+        let (min, max) = (self.min.as_array(), self.max.as_array());
+        let (mut t0, mut t1) = (t_min, t_max);
+        for a in 0..3 {
+            let mut near = (min[a] - origin[a]) * inv_d[a];
+            let mut far = (max[a] - origin[a]) * inv_d[a];
+            if inv_d[a] < 0.0 {
+                std::mem::swap(&mut near, &mut far);
+            }
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+            if t1 <= t0 {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// What the surface area heuristic weighs boxes by.
+    pub fn surface_area(&self) -> Float {
+        let d = self.max - self.min;
+        2.0 * (d.x*d.y + d.y*d.z + d.z*d.x)
+    }
 
+    /// The part of [t_min, t_max] that the ray spends inside the box, if any.
+    pub fn clip(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<(Float, Float)> {
+        let (min, max) = (self.min.as_array(), self.max.as_array());
+        let (origin, direction) = (ray.origin.as_array(), ray.direction.as_array());
+        let (mut t0, mut t1) = (t_min, t_max);
         for a in 0..3 {
             let inv_d = 1.0 / direction[a];
-            let mut t0 = (min[a] - origin[a]) * inv_d;
-            let mut t1 = (max[a] - origin[a]) * inv_d;
+            let mut near = (min[a] - origin[a]) * inv_d;
+            let mut far = (max[a] - origin[a]) * inv_d;
             if inv_d < 0.0 {
-                std::mem::swap(&mut t0, &mut t1);
+                std::mem::swap(&mut near, &mut far);
             }
-            let t_min = if t0 > t_min { t0 } else { t_min };
-            let t_max = if t1 < t_max { t1 } else { t_max };
-
-            if t_max <= t_min {
-                return false;
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+            if t1 < t0 {
+                return None;
             }
         }
-        return true;
+        Some((t0, t1))
     }
 }
 
@@ -51,16 +75,3 @@ pub fn surrounding_box(box0: AABB, box1: AABB) -> AABB {
     );
     AABB::new(small, big)
 }
-
-pub fn aabb_compare(a: AABB, b: AABB, axis: usize) -> Ordering {
-        let a = a.min.as_array();
-        let b = b.min.as_array();
-
-        if a[axis] < b[axis] {
-            Ordering::Less
-        } else if a[axis] > b[axis] {
-            Ordering::Greater
-        } else {
-            Ordering::Equal
-        }
-}
@@ -0,0 +1,323 @@
+use crate::bvh::BVHNode;
+use crate::aabb::AABB;
+use crate::float::Float;
+use crate::grid::Grid;
+use crate::hit::{Hit, HitArc, HitList, HitRecord, sort_hits};
+use crate::kdtree::KdTree;
+use crate::qbvh::Qbvh;
+use crate::ray::Ray;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/**
+ * The structures that gather objects up so that a ray only tests the few it might hit. Each is a
+ * `Hit` built over a list of objects, so they nest in one another and in anything else, and each
+ * adds its work to the counts `bvh::take_counts` returns, so heatmaps compare them fairly. Which
+ * is fastest depends on the objects: trees adapt to clumps and to objects of any size, while a
+ * grid suits many small objects spread evenly. A scene can pick for each group of its objects.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Accelerator {
+    Bvh,
+    Qbvh,
+    Grid,
+    KdTree,
+}
+
+impl Accelerator {
+    pub const ALL: [Accelerator; 4] = [Accelerator::Bvh, Accelerator::Qbvh, Accelerator::Grid, Accelerator::KdTree];
+
+    /// Gathers the objects up. While groups are being recorded, what comes back keeps the rays
+    /// that reach it as well.
+    pub fn build(self, list: &HitList, time0: Float, time1: Float) -> HitArc {
+        let built = self.build_only(list, time0, time1);
+        match RECORDED.lock().unwrap().as_mut() {
+            Some(recorded) => {
+                let objects = HitList{ objects: list.objects.clone() };
+                let group = Arc::new(Group{ objects, time0, time1, built, rays: Mutex::new(Vec::new()) });
+                recorded.push(Arc::clone(&group));
+                group
+            }
+            None => built,
+        }
+    }
+
+    fn build_only(self, list: &HitList, time0: Float, time1: Float) -> HitArc {
+        match self {
+            Accelerator::Bvh => Arc::new(BVHNode::from_hitlist(list, time0, time1)),
+            Accelerator::Qbvh => Arc::new(Qbvh::from_hitlist(list, time0, time1)),
+            Accelerator::Grid => Arc::new(Grid::from_hitlist(list, time0, time1)),
+            Accelerator::KdTree => Arc::new(KdTree::from_hitlist(list, time0, time1)),
+        }
+    }
+}
+
+/**
+ * How each accelerator walks a ray through its leaves, which is all that `hit`, `occluded` and
+ * `hit_all` need to know about it. `traverse` calls `visit` with every leaf the ray reaches
+ * between t_min and the current t_max, nearest first as far as the structure can tell. `visit`
+ * returns a new t_max, so a hit can rule out whatever lies beyond it, and one below t_min ends the
+ * walk.
+ */
+pub trait Traverse {
+    type Leaf<'a>: Leaf<'a> where Self: 'a;
+
+    /// The objects without boxes, kept out of the leaves and tested against every ray.
+    fn unbounded(&self) -> &HitList;
+
+    fn traverse<'a, F>(&'a self, ray: Ray, t_min: Float, t_max: Float, visit: F)
+        where F: FnMut(Self::Leaf<'a>, Float) -> Float;
+}
+
+/// What `Traverse::traverse` hands over for each leaf.
+pub trait Leaf<'a>: Copy {
+    fn objects(self) -> impl Iterator<Item = &'a HitArc>;
+
+    /// Calls `f` once with every object in the leaves that `walk` passes to its argument.
+    fn each_once(walk: impl FnOnce(&mut dyn FnMut(Self)), mut f: impl FnMut(&'a HitArc)) {
+        walk(&mut |leaf| leaf.objects().for_each(&mut f));
+    }
+}
+
+/// The leaves of a tree whose objects are each in one leaf, laid out one leaf after another.
+impl<'a> Leaf<'a> for &'a [HitArc] {
+    fn objects(self) -> impl Iterator<Item = &'a HitArc> {
+        self.iter()
+    }
+}
+
+/// A leaf of a structure where an object can be in several leaves, as the indices in `objects`
+/// of the objects in it.
+#[derive(Copy, Clone)]
+pub struct Indexed<'a> {
+    pub indices: &'a [u32],
+    pub objects: &'a [HitArc],
+}
+
+impl<'a> Leaf<'a> for Indexed<'a> {
+    fn objects(self) -> impl Iterator<Item = &'a HitArc> {
+        self.indices.iter().map(move |&i| &self.objects[i as usize])
+    }
+
+    /// Objects in several leaves along the ray are only passed to `f` once.
+    fn each_once(walk: impl FnOnce(&mut dyn FnMut(Self)), mut f: impl FnMut(&'a HitArc)) {
+        let mut objects: &[HitArc] = &[];
+        let mut passed = Vec::new();
+        walk(&mut |leaf| {
+            objects = leaf.objects;
+            passed.extend_from_slice(leaf.indices);
+        });
+        passed.sort_unstable();
+        passed.dedup();
+        for i in passed {
+            f(&objects[i as usize]);
+        }
+    }
+}
+
+/// Splits the objects into those with boxes, alongside their boxes, and those without.
+pub fn split(list: &HitList, time0: Float, time1: Float) -> (Vec<HitArc>, Vec<AABB>, HitList) {
+    let mut objects = Vec::new();
+    let mut boxes = Vec::new();
+    let mut unbounded = HitList::default();
+    for object in &list.objects {
+        match object.bounding_box(time0, time1) {
+            Some(aabb) => {
+                objects.push(Arc::clone(object));
+                boxes.push(aabb);
+            }
+            None => unbounded.add(Arc::clone(object)),
+        }
+    }
+    (objects, boxes, unbounded)
+}
+
+/// `Hit::hit` for any accelerator.
+pub fn hit<T: Traverse>(accelerator: &T, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+    let mut nearest = accelerator.unbounded().hit(ray, t_min, t_max);
+
+    accelerator.traverse(ray, t_min, nearest.map_or(t_max, |hitrec| hitrec.t), |leaf, mut closest| {
+        for object in leaf.objects() {
+            if let Some(hitrec) = object.hit(ray, t_min, closest) {
+                closest = hitrec.t;
+                nearest = Some(hitrec);
+            }
+        }
+        closest
+    });
+    nearest
+}
+
+/// `Hit::occluded` for any accelerator.
+pub fn occluded<T: Traverse>(accelerator: &T, ray: Ray, t_min: Float, t_max: Float) -> bool {
+    if accelerator.unbounded().occluded(ray, t_min, t_max) {
+        return true;
+    }
+    let mut occluded = false;
+    accelerator.traverse(ray, t_min, t_max, |leaf, t_max| {
+        if leaf.objects().any(|object| object.occluded(ray, t_min, t_max)) {
+            occluded = true;
+            Float::NEG_INFINITY
+        } else {
+            t_max
+        }
+    });
+    occluded
+}
+
+/// `Hit::hit_all` for any accelerator.
+pub fn hit_all<'a, T: Traverse>(accelerator: &'a T, ray: Ray, t_min: Float, t_max: Float,
+                                 hits: &mut Vec<HitRecord<'a>>) {
+    let start = hits.len();
+    accelerator.unbounded().hit_all(ray, t_min, t_max, hits);
+    T::Leaf::each_once(|visit| accelerator.traverse(ray, t_min, t_max, |leaf, t_max| {
+        visit(leaf);
+        t_max
+    }), |object| object.hit_all(ray, t_min, t_max, hits));
+    sort_hits(&mut hits[start..]);
+}
+
+/// Every group built since `record_groups`, or None when they aren't being recorded.
+static RECORDED: Mutex<Option<Vec<Arc<Group>>>> = Mutex::new(None);
+
+/// Starts recording every group that `Accelerator::build` builds from here on, along with the rays
+/// each is asked about, so that `Group::compare` can time the accelerators on real work.
+pub fn record_groups() {
+    RECORDED.lock().unwrap().get_or_insert_with(Vec::new);
+}
+
+/// The groups recorded so far, in the order they were built, and forgets them.
+pub fn recorded_groups() -> Vec<Arc<Group>> {
+    RECORDED.lock().unwrap().as_mut().map_or_else(Vec::new, std::mem::take)
+}
+
+/// A group of objects built while recording, which stands in for what it was built into and
+/// keeps every ray passed to `hit`, in the group's own space, with its t_min and t_max.
+pub struct Group {
+    objects: HitList,
+    time0: Float,
+    time1: Float,
+    built: HitArc,
+    rays: Mutex<Vec<(Ray, Float, Float)>>,
+}
+
+impl Group {
+    pub fn objects(&self) -> usize {
+        self.objects.objects.len()
+    }
+
+    pub fn rays(&self) -> usize {
+        self.rays.lock().unwrap().len()
+    }
+
+    /// How long each accelerator takes to build over the group, and then to find the nearest hits
+    /// of the rays it was asked about.
+    pub fn compare(&self) -> Vec<(Accelerator, Duration, Duration)> {
+        let rays = self.rays.lock().unwrap().clone();
+        Accelerator::ALL.iter().map(|&accelerator| {
+            let start = Instant::now();
+            let built = accelerator.build_only(&self.objects, self.time0, self.time1);
+            let building = start.elapsed();
+            let start = Instant::now();
+            for &(ray, t_min, t_max) in &rays {
+                std::hint::black_box(built.hit(ray, t_min, t_max));
+            }
+            (accelerator, building, start.elapsed())
+        }).collect()
+    }
+}
+
+impl Hit for Group {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        self.rays.lock().unwrap().push((ray, t_min, t_max));
+        self.built.hit(ray, t_min, t_max)
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        self.built.occluded(ray, t_min, t_max)
+    }
+
+    fn hit_all<'a>(&'a self, ray: Ray, t_min: Float, t_max: Float, hits: &mut Vec<HitRecord<'a>>) {
+        self.built.hit_all(ray, t_min, t_max, hits);
+    }
+
+    fn bounding_box(&self, time0: Float, time1: Float) -> Option<AABB> {
+        self.built.bounding_box(time0, time1)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use crate::accelerator::Accelerator;
+    use crate::box3d::Box3D;
+    use crate::float::Float;
+    use crate::hit::{Hit, HitList};
+    use crate::material::Lambertian;
+    use crate::quad::{Plane, Quad};
+    use crate::rand::{randrange, rfloat};
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+    use crate::vec3::{Color, Point3, Vec3};
+    use std::fmt::Debug;
+
+    /// Randomly placed spheres with objects of every size among them, ones in the same place, flat
+    /// ones lying in the same plane and a plane without a box, for accelerators to be checked on.
+    pub fn objects(spheres: usize) -> HitList {
+        let material = || Lambertian::from_color(Color::default());
+        let mut list = HitList::default();
+        list.add(Plane::new(Point3::new(0.0, -12.0, 0.0), Vec3::new(0.0, 1.0, 0.0), material()));
+        for _ in 0..spheres {
+            list.add(Sphere::new(Vec3::randrange(-10.0, 10.0), randrange(0.1, 1.5), material()));
+        }
+        list.add(Sphere::new(Point3::new(3.0, 2.0, 1.0), 8.0, material()));
+        // A stack of identical spheres, which no split can separate.
+        for _ in 0..10 {
+            list.add(Sphere::new(Point3::new(20.0, 0.0, 0.0), 1.0, material()));
+        }
+        for i in 0..10 {
+            let corner = Point3::new(i as Float - 5.0, 11.0, -5.0);
+            list.add(Quad::new(corner, Vec3::new(0.8, 0.0, 0.0), Vec3::new(0.0, 0.0, 10.0), material()));
+        }
+        list.add(Box3D::new(Point3::new(-12.0, -12.0, -12.0), Point3::new(-8.0, 12.0, 12.0), material()));
+        list
+    }
+
+    /// A ray from somewhere around `objects` in any direction.
+    pub fn random_ray() -> Ray {
+        Ray::new(Vec3::randrange(-15.0, 25.0), Vec3::randrange(-1.0, 1.0), 0.0)
+    }
+
+    /// Asserts that `built` finds the same hits along the ray as the list it was built over does.
+    pub fn assert_same_hits(list: &HitList, built: &dyn Hit, ray: Ray, t_min: Float, t_max: Float,
+                            what: impl Debug) {
+        let expected = list.hit(ray, t_min, t_max).map(|hitrec| hitrec.t);
+        assert_eq!(built.hit(ray, t_min, t_max).map(|hitrec| hitrec.t), expected, "{:?}", what);
+        assert_eq!(built.occluded(ray, t_min, t_max), expected.is_some(), "{:?}", what);
+
+        let (mut expected, mut found) = (Vec::new(), Vec::new());
+        list.hit_all(ray, t_min, t_max, &mut expected);
+        built.hit_all(ray, t_min, t_max, &mut found);
+        assert!(expected.iter().map(|h| h.t).eq(found.iter().map(|h| h.t)), "{:?}", what);
+    }
+
+    /// `assert_same_hits` for random rays, some of them cut short and some reaching back behind
+    /// their origins, as `ConstantMedium` and `Csg` ask for.
+    pub fn assert_same_hits_for_random_rays(list: &HitList, built: &dyn Hit, what: impl Debug + Copy) {
+        for _ in 0..500 {
+            let t_min = if rfloat() < 0.25 { Float::NEG_INFINITY } else { 0.001 };
+            let t_max = if rfloat() < 0.5 { Float::INFINITY } else { randrange(0.5, 20.0) };
+            assert_same_hits(list, built, random_ray(), t_min, t_max, what);
+        }
+    }
+
+    #[test]
+    fn same_hits_as_a_list() {
+        let list = objects(300);
+        for accelerator in Accelerator::ALL {
+            let built = accelerator.build(&list, 0.0, 1.0);
+            assert!(built.bounding_box(0.0, 1.0).is_none());
+            assert_same_hits_for_random_rays(&list, &*built, accelerator);
+        }
+    }
+}
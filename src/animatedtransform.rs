@@ -0,0 +1,205 @@
+use crate::aabb::{AABB, surrounding_box};
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitRecord};
+use crate::matrix::Matrix4;
+use crate::quaternion::Quaternion;
+use crate::ray::Ray;
+use crate::vec3::{unit_vector, Vec3};
+use std::sync::Arc;
+
+/// How many steps each stretch between keyframes is cut into when working out the motion bounds.
+const BOUND_STEPS: usize = 8;
+
+/// Where an object is at one moment: scaled first, then rotated, then moved.
+#[derive(Debug, Copy, Clone)]
+pub struct Keyframe {
+    pub time: Float,
+    pub translation: Vec3,
+    pub rotation: Quaternion,
+    pub scale: Vec3,
+}
+
+impl Keyframe {
+    pub fn new(time: Float, translation: Vec3, rotation: Quaternion, scale: Vec3) -> Keyframe {
+        Keyframe{ time, translation, rotation, scale }
+    }
+
+    fn matrix(&self) -> Matrix4 {
+        Matrix4::translate(self.translation) * Matrix4::from_quaternion(self.rotation) * Matrix4::scale(self.scale)
+    }
+
+    /// Built from the parts in reverse rather than by inverting the whole matrix, which is much
+    /// cheaper when it has to be done for every ray.
+    fn inverse(&self) -> Matrix4 {
+        let s = self.scale;
+        Matrix4::scale(Vec3::new(1.0 / s.x, 1.0 / s.y, 1.0 / s.z))
+            * Matrix4::from_quaternion(self.rotation.conjugate())
+            * Matrix4::translate(-self.translation)
+    }
+}
+
+/**
+ * Moves any object through a series of keyframes, for motion blur. Each ray sees the object where
+ * it was at the ray's time, with translation and scale interpolated linearly between keyframes and
+ * rotation by slerp. Before the first keyframe and after the last the object holds still.
+ */
+pub struct AnimatedTransform {
+    object: HitArc,
+    keyframes: Vec<Keyframe>,
+}
+
+impl AnimatedTransform {
+    /// Panics without any keyframes, or if a scale is zero. They needn't be in order.
+    pub fn new(object: HitArc, mut keyframes: Vec<Keyframe>) -> HitArc {
+        assert!(!keyframes.is_empty(), "AnimatedTransform needs at least one keyframe");
+        assert!(keyframes.iter().all(|k| k.scale.x * k.scale.y * k.scale.z != 0.0),
+            "AnimatedTransform can't scale by zero");
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Arc::new(AnimatedTransform{ object, keyframes })
+    }
+
+    fn at(&self, time: Float) -> Keyframe {
+        let next = self.keyframes.partition_point(|k| k.time <= time);
+        if next == 0 {
+            return self.keyframes[0];
+        }
+        if next == self.keyframes.len() {
+            return self.keyframes[next - 1];
+        }
+
+        let (a, b) = (self.keyframes[next - 1], self.keyframes[next]);
+        let t = (time - a.time) / (b.time - a.time);
+        Keyframe::new(
+            time,
+            a.translation + t*(b.translation - a.translation),
+            a.rotation.slerp(b.rotation, t),
+            a.scale + t*(b.scale - a.scale),
+        )
+    }
+
+    fn to_world(&self, frame: &Keyframe, hitrec: &mut HitRecord) {
+        let matrix = frame.matrix();
+        hitrec.point = matrix.transform_point(hitrec.point);
+        if hitrec.error > 0.0 {
+            hitrec.error *= matrix.transform_vector(hitrec.normal).length();
+        }
+        hitrec.normal = unit_vector(frame.inverse().transform_normal(hitrec.normal));
+        if !hitrec.tangent.near_zero() {
+            hitrec.tangent = unit_vector(matrix.transform_vector(hitrec.tangent));
+        }
+    }
+}
+
+fn to_local(frame: &Keyframe, ray: Ray) -> Ray {
+    let inverse = frame.inverse();
+    Ray::new(inverse.transform_point(ray.origin), inverse.transform_vector(ray.direction), ray.time)
+}
+
+impl Hit for AnimatedTransform {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let frame = self.at(ray.time);
+        let mut hitrec = self.object.hit(to_local(&frame, ray), t_min, t_max)?;
+        self.to_world(&frame, &mut hitrec);
+        Some(hitrec)
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        self.object.occluded(to_local(&self.at(ray.time), ray), t_min, t_max)
+    }
+
+    fn hit_all<'a>(&'a self, ray: Ray, t_min: Float, t_max: Float, hits: &mut Vec<HitRecord<'a>>) {
+        let frame = self.at(ray.time);
+        let local = to_local(&frame, ray);
+        let start = hits.len();
+        self.object.hit_all(local, t_min, t_max, hits);
+        for hitrec in &mut hits[start..] {
+            self.to_world(&frame, hitrec);
+        }
+    }
+
+    fn span(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<(Float, Float)> {
+        self.object.span(to_local(&self.at(ray.time), ray), t_min, t_max)
+    }
+
+    /**
+     * The placed box is sampled at every keyframe in the shutter interval and a few times between
+     * each. Rotation carries corners along arcs that can bulge out between samples, so each step's
+     * boxes are also grown by the furthest any point of the object could travel in half a step,
+     * which makes the bound conservative rather than just close.
+     */
+    fn bounding_box(&self, time0: Float, time1: Float) -> Option<AABB> {
+        let local = self.object.bounding_box(time0, time1)?;
+        let far = |a: Float, b: Float| a.abs().max(b.abs());
+        let reach = Vec3::new(far(local.min.x, local.max.x), far(local.min.y, local.max.y),
+            far(local.min.z, local.max.z)).length();
+
+        // Every time the interpolation changes direction, plus steps between.
+        let mut times = vec![time0];
+        let inside: Vec<Float> = self.keyframes.iter().map(|k| k.time).filter(|t| *t > time0 && *t < time1).collect();
+        for end in inside.iter().cloned().chain(std::iter::once(time1)) {
+            let start = *times.last().unwrap();
+            for step in 1..=BOUND_STEPS {
+                times.push(start + (end - start) * step as Float / BOUND_STEPS as Float);
+            }
+        }
+
+        let frames: Vec<Keyframe> = times.iter().map(|t| self.at(*t)).collect();
+        let mut bounds = frames[0].matrix().transform_box(local);
+        for pair in frames.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            let biggest = |s: Vec3| s.x.abs().max(s.y.abs()).max(s.z.abs());
+            let travel = (b.translation - a.translation).length()
+                + a.rotation.angle_to(b.rotation) * reach * biggest(a.scale).max(biggest(b.scale))
+                + biggest(b.scale - a.scale) * reach;
+            let step = surrounding_box(a.matrix().transform_box(local), b.matrix().transform_box(local));
+            let pad = Vec3::new(0.5*travel, 0.5*travel, 0.5*travel);
+            bounds = surrounding_box(bounds, AABB::new(step.min - pad, step.max + pad));
+        }
+
+        Some(bounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::animatedtransform::{AnimatedTransform, Keyframe};
+    use crate::box3d::Box3D;
+    use crate::float::Float;
+    use crate::material::Lambertian;
+    use crate::quaternion::Quaternion;
+    use crate::ray::Ray;
+    use crate::vec3::{Color, Point3, Vec3};
+    use crate::float::consts::PI;
+
+    #[test]
+    fn keyframes() {
+        let cube = Box3D::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0),
+            Lambertian::from_color(Color::default()));
+        let still = Quaternion::identity();
+        let one = Vec3::new(1.0, 1.0, 1.0);
+        let spin = Quaternion::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), PI / 2.0);
+        let moving = AnimatedTransform::new(cube, vec![
+            Keyframe::new(1.0, Vec3::new(4.0, 0.0, 0.0), spin, 2.0*one),
+            Keyframe::new(0.0, Vec3::default(), still, one),
+        ]);
+
+        // Halfway, the cube is at x = 2, half as big again and turned 45 degrees, so a ray down
+        // the middle meets its edge at sqrt(2) * 1.5 from the center.
+        let down = Ray::new(Point3::new(2.0, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.5);
+        let t = moving.hit(down, 0.001, Float::INFINITY).unwrap().t;
+        assert!((t - 8.5).abs() < 1e-9);
+        let across = Ray::new(Point3::new(2.0, 0.0, 10.0), Vec3::new(0.0, 0.0, -1.0), 0.5);
+        let t = moving.hit(across, 0.001, Float::INFINITY).unwrap().t;
+        assert!((t - (10.0 - 1.5*Float::sqrt(2.0))).abs() < 1e-9);
+
+        // Before the first keyframe it stays put.
+        let early = Ray::new(Point3::new(0.0, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0), -1.0);
+        let t = moving.hit(early, 0.001, Float::INFINITY).unwrap().t;
+        assert!((t - 9.0).abs() < 1e-9);
+
+        // The bounds hold the cube at every moment of the shutter.
+        let aabb = moving.bounding_box(0.0, 1.0).unwrap();
+        assert!(aabb.min.x <= -1.0 && aabb.max.x >= 6.0 && aabb.max.z >= 1.5*Float::sqrt(2.0));
+    }
+}
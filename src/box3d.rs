@@ -1,42 +1,164 @@
 use crate::aabb::AABB;
-use crate::aarect::{XYRect, XZRect, YZRect};
-use crate::hit::{Hit, HitArc, HitList, HitRecord};
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitRecord};
 use crate::material::Material;
 use crate::ray::Ray;
-use crate::vec3::Point3;
+use crate::vec3::{Point3, Vec3};
 
 use std::sync::Arc;
 
+/**
+ * An axis-aligned box, intersected with the slab test: where the ray is between each pair of
+ * parallel faces is worked out axis by axis, and the ray is inside the box where all three
+ * overlap. That gives both the way in and the way out at once, along with which face each is on.
+ * Each face is textured like the rectangle it replaces, with u and v running along the other two
+ * axes in xyz order.
+ */
 pub struct Box3D {
     box_min: Point3,
     box_max: Point3,
-    sides: HitList,
+    material: Material,
+}
+
+/// Where a ray crosses the box's surface, and which face that's on as axis * 2, plus one for the
+/// face at the high end of the axis.
+#[derive(Copy, Clone)]
+struct Crossing {
+    t: Float,
+    face: usize,
 }
 
 impl Box3D {
     pub fn new(p0: Point3, p1: Point3, material: Material) -> HitArc {
-        let mut sides = HitList::default();
+        let box_min = Point3::new(p0.x.min(p1.x), p0.y.min(p1.y), p0.z.min(p1.z));
+        let box_max = Point3::new(p0.x.max(p1.x), p0.y.max(p1.y), p0.z.max(p1.z));
+        Arc::new(Box3D{ box_min, box_max, material })
+    }
 
-        sides.add(XYRect::new(p0.x, p1.x, p0.y, p1.y, p1.z, Arc::clone(&material)));
-        sides.add(XYRect::new(p0.x, p1.x, p0.y, p1.y, p0.z, Arc::clone(&material)));
+    /// Where the whole line of the ray goes in and out of the box, if it meets it at all.
+    fn slabs(&self, ray: Ray) -> Option<(Crossing, Crossing)> {
+        let (min, max) = (self.box_min.as_array(), self.box_max.as_array());
+        let (origin, direction) = (ray.origin.as_array(), ray.direction.as_array());
+        let mut enter = Crossing{ t: Float::NEG_INFINITY, face: 0 };
+        let mut exit = Crossing{ t: Float::INFINITY, face: 0 };
 
-        sides.add(XZRect::new(p0.x, p1.x, p0.z, p1.z, p1.y, Arc::clone(&material)));
-        sides.add(XZRect::new(p0.x, p1.x, p0.z, p1.z, p0.y, Arc::clone(&material)));
+        for a in 0..3 {
+            if direction[a] == 0.0 {
+                // Parallel to this pair of faces, so the ray is either always between them or never.
+                if origin[a] < min[a] || origin[a] > max[a] {
+                    return None;
+                }
+                continue;
+            }
+
+            let inv_d = 1.0 / direction[a];
+            let mut near = Crossing{ t: (min[a] - origin[a]) * inv_d, face: 2*a };
+            let mut far = Crossing{ t: (max[a] - origin[a]) * inv_d, face: 2*a + 1 };
+            if inv_d < 0.0 {
+                std::mem::swap(&mut near, &mut far);
+            }
+            if near.t > enter.t {
+                enter = near;
+            }
+            if far.t < exit.t {
+                exit = far;
+            }
+            if exit.t < enter.t {
+                return None;
+            }
+        }
+
+        Some((enter, exit))
+    }
 
-        sides.add(YZRect::new(p0.y, p1.y, p0.z, p1.z, p1.x, Arc::clone(&material)));
-        sides.add(YZRect::new(p0.y, p1.y, p0.z, p1.z, p0.x, Arc::clone(&material)));
+    fn record(&self, ray: Ray, crossing: Crossing) -> HitRecord<'_> {
+        let axis = crossing.face / 2;
+        let mut outward = [0.0; 3];
+        outward[axis] = if crossing.face % 2 == 1 { 1.0 } else { -1.0 };
 
-        Arc::new(Box3D{ box_min: p0, box_max: p1, sides })
+        let (p, min, max) = (ray.at(crossing.t).as_array(), self.box_min.as_array(), self.box_max.as_array());
+        let (a, b) = match axis {
+            0 => (1, 2),
+            1 => (0, 2),
+            _ => (0, 1),
+        };
+        let u = (p[a] - min[a]) / (max[a] - min[a]);
+        let v = (p[b] - min[b]) / (max[b] - min[b]);
+        HitRecord::new(ray, crossing.t, Vec3::from_array(outward), u, v, &self.material)
     }
 }
 
 impl Hit for Box3D {
-    fn hit(&self, ray: Ray, t_min: f64, t_max: f64, hitrec: &mut HitRecord) -> bool {
-        self.sides.hit(ray, t_min, t_max, hitrec)
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let (enter, exit) = self.slabs(ray)?;
+
+        // From inside, or with the way in cut off by t_min, the way out is the first crossing.
+        [enter, exit].iter()
+            .find(|crossing| t_min <= crossing.t && crossing.t <= t_max)
+            .map(|crossing| self.record(ray, *crossing))
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        match self.slabs(ray) {
+            Some((enter, exit)) => [enter, exit].iter().any(|c| t_min <= c.t && c.t <= t_max),
+            None => false,
+        }
+    }
+
+    fn hit_all<'a>(&'a self, ray: Ray, t_min: Float, t_max: Float, hits: &mut Vec<HitRecord<'a>>) {
+        if let Some((enter, exit)) = self.slabs(ray) {
+            for crossing in [enter, exit] {
+                if t_min <= crossing.t && crossing.t <= t_max {
+                    hits.push(self.record(ray, crossing));
+                }
+            }
+        }
     }
 
-    fn bounding_box(&self, _time0: f64, _time1: f64, aabb: &mut AABB) -> bool {
-        *aabb = AABB::new(self.box_min, self.box_max);
-        true
+    fn span(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<(Float, Float)> {
+        let (enter, exit) = self.slabs(ray)?;
+        if t_min <= enter.t && exit.t <= t_max { Some((enter.t, exit.t)) } else { None }
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        Some(AABB::new(self.box_min, self.box_max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::box3d::Box3D;
+    use crate::float::Float;
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+    use crate::vec3::{Color, Point3, Vec3};
+
+    #[test]
+    fn slabs() {
+        let cube = Box3D::new(Point3::new(1.0, 0.0, 0.0), Point3::new(-1.0, 2.0, 4.0),
+            Lambertian::from_color(Color::default()));
+
+        // In through the low x face, which faces -x, and out through the high one.
+        let across = Ray::new(Point3::new(-5.0, 1.5, 1.0), Vec3::new(2.0, 0.0, 0.0), 0.0);
+        let hitrec = cube.hit(across, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 2.0).abs() < 1e-12 && hitrec.front_face);
+        assert!((hitrec.normal - Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-12);
+        assert!((hitrec.u - 0.75).abs() < 1e-12 && (hitrec.v - 0.25).abs() < 1e-12);
+
+        let mut hits = Vec::new();
+        cube.hit_all(across, 0.001, Float::INFINITY, &mut hits);
+        assert_eq!(hits.len(), 2);
+        assert!((hits[1].t - 3.0).abs() < 1e-12 && !hits[1].front_face);
+        assert!((hits[1].normal - Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-12);
+
+        // Starting inside, parallel to two pairs of faces.
+        let up = Ray::new(Point3::new(0.0, 1.0, 1.0), Vec3::new(0.0, 1.0, 0.0), 0.0);
+        let hitrec = cube.hit(up, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 1.0).abs() < 1e-12 && !hitrec.front_face);
+
+        // Parallel and outside misses.
+        let beside = Ray::new(Point3::new(0.0, 1.0, 5.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        assert!(cube.hit(beside, Float::NEG_INFINITY, Float::INFINITY).is_none());
     }
 }
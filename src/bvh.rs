@@ -1,91 +1,851 @@
 use crate::aabb::{AABB, surrounding_box};
-use crate::hit::{Hit, HitArc, hit_compare, HitList};
-use crate::rand::randrange;
-use std::cmp::Ordering;
-use std::sync::Arc;
+use crate::accelerator::{self, Traverse};
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitList, HitRecord};
+use crate::ray::Ray;
+use crate::vec3::Point3;
+use std::cell::Cell;
+use std::convert::TryInto;
+use std::{fmt, fs, mem, ops};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
+/// How many objects a leaf may hold before it has to be split, however good keeping it whole looks.
+const LEAF_SIZE: usize = 4;
+
+/// Split positions are only considered at the edges of this many equal bins along each axis.
+const BINS: usize = 16;
+
+/// The cost of visiting one more node, relative to testing one object. Testing a node's box
+/// through a virtual call costs about as much as testing a sphere or a triangle.
+const TRAVERSAL_COST: Float = 1.0;
+
+/// Splits of at least this many objects have their two halves built on different threads.
+const PARALLEL_BUILD: usize = 4096;
+
+/// Starts every cached tree.
+const CACHE_MAGIC: &[u8] = b"shirley bvh";
+
+/// Bumped whenever the layout of cached trees changes.
+const CACHE_VERSION: u64 = 1;
+
+/// Past this depth splits just halve the objects, which keeps the traversal stack bounded.
+const MAX_SAH_DEPTH: usize = 64;
+
+/// Deep enough for MAX_SAH_DEPTH levels of SAH splits and then halving any number of objects.
+const STACK_SIZE: usize = 128;
+
+/**
+ * A bounding volume hierarchy. Objects without a bounding box, like infinite planes, can't be
+ * sorted into the tree, so they're kept to one side and tested before the tree, where a hit on a
+ * ground plane cuts short the search through everything beyond it. A tree holding any of them has
+ * no box of its own.
+ *
+ * The tree is built with the surface area heuristic: the chance a ray that hits a box also hits a
+ * box inside it is the ratio of their surface areas, so each split is chosen to make the expected
+ * cost of tracing a ray through the two halves as small as possible, and objects are only split
+ * up while that's cheaper than testing them all. Building is deterministic, so the same scene
+ * always gets the same tree, however many threads build it.
+ *
+ * Once built, the tree is laid out flat in one array in depth-first order, so that a node's first
+ * child comes right after it, and each leaf is a range of the objects, which are stored in the
+ * same order. Rays walk it with a stack instead of recursing, visiting the near child first by
+ * the sign of the ray's direction along the axis the node was split on.
+ */
 pub struct BVHNode {
-    left: HitArc,
-    right: HitArc,
+    nodes: Vec<LinearNode>,
+    objects: Vec<HitArc>,
+    unbounded: HitList,
+    bounds: Option<AABB>,
+    /// Where each of `objects` and then each unbounded object was in the list the tree was built
+    /// over, so that `refit` can take a list in the same order.
+    order: Vec<u32>,
+}
+
+/// For a leaf, `offset` is where its objects start and `count` how many there are. Other nodes
+/// have no objects, their first child right after them and their second child at `offset`.
+struct LinearNode {
     bounds: AABB,
+    offset: u32,
+    count: u16,
+    axis: u8,
+}
+
+/// A node of the tree, as `BVHNode::node` hands it out for building other layouts from.
+pub enum Node {
+    /// The range of `BVHNode::objects` a leaf holds.
+    Leaf(ops::Range<usize>),
+    /// Where an inner node's two children are.
+    Inner(usize, usize),
+}
+
+/// What the builder needs to know about each object, worked out once and shared by every thread.
+struct Primitives {
+    bounds: Vec<AABB>,
+    centroids: Vec<Point3>,
 }
 
 impl BVHNode {
-    pub fn from_hitlist(list: &HitList, time0: f64, time1: f64) -> BVHNode {
+    pub fn from_hitlist(list: &HitList, time0: Float, time1: Float) -> BVHNode {
         BVHNode::from_vec(&list.objects, 0, list.objects.len(), time0, time1)
     }
 
-    pub fn from_vec(src_objects: &Vec<HitArc>, start: usize, end: usize, time0: f64, time1: f64) -> BVHNode {
-        let mut objects = src_objects.clone();
-        let bounds = AABB::default();
-        let axis = randrange(0.0, 3.0).trunc() as usize;
+    pub fn from_vec(src_objects: &[HitArc], start: usize, end: usize, time0: Float, time1: Float) -> BVHNode {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        BVHNode::with_threads(&src_objects[start..end], time0, time1, threads)
+    }
 
-        let comparator = |a: &HitArc, b: &HitArc| hit_compare(Arc::clone(&a), Arc::clone(&b), axis);
+    fn with_threads(src_objects: &[HitArc], time0: Float, time1: Float, threads: usize) -> BVHNode {
+        BVHNode::from_boxes(src_objects, &boxes(src_objects, time0, time1), threads)
+    }
 
-        let object_span = end - start;
+    fn from_boxes(src_objects: &[HitArc], boxes: &[Option<AABB>], threads: usize) -> BVHNode {
+        let mut bounded = Vec::with_capacity(src_objects.len());
+        let mut primitives = Primitives{ bounds: Vec::with_capacity(src_objects.len()), centroids: Vec::new() };
+        let mut unbounded_order = Vec::new();
+        for (i, aabb) in boxes.iter().enumerate() {
+            match aabb {
+                Some(aabb) => {
+                    bounded.push(i as u32);
+                    primitives.bounds.push(*aabb);
+                }
+                None => unbounded_order.push(i as u32),
+            }
+        }
+        primitives.centroids = primitives.bounds.iter().map(|b| 0.5 * (b.min + b.max)).collect();
 
-        let mut node = match object_span {
-            1 => BVHNode{
-                left: Arc::clone(&objects[start]),
-                right: Arc::clone(&objects[start]),
-                bounds,
-            },
-            2 => match comparator(&objects[start], &objects[start+1]) {
-                Ordering::Greater => {
-                    BVHNode{
-                        left: Arc::clone(&objects[start]),
-                        right: Arc::clone(&objects[start+1]),
-                        bounds,
-                    }
-                },
-                _ => {
-                    BVHNode{
-                        left: Arc::clone(&objects[start+1]),
-                        right: Arc::clone(&objects[start]),
-                        bounds,
-                    }
-                },
-            },
-            _ => {
-                objects[start..end].sort_unstable_by(comparator);
-                let mid = start + object_span / 2;
-                BVHNode{
-                    left: Arc::new(BVHNode::from_vec(&objects, start, mid, time0, time1)),
-                    right: Arc::new(BVHNode::from_vec(&objects, mid, end, time0, time1)),
-                    bounds,
+        let mut indices: Vec<u32> = (0..bounded.len() as u32).collect();
+        let mut nodes = Vec::with_capacity(2 * bounded.len());
+        if !bounded.is_empty() {
+            BVHNode::build(&primitives, &mut indices, 0, 0, threads, &mut nodes);
+        }
+        let order = indices.iter().map(|&i| bounded[i as usize]).chain(unbounded_order).collect();
+        BVHNode::assemble(src_objects, nodes, order, bounded.len())
+    }
+
+    /// Puts the objects in the order the nodes expect, the first `in_tree` of `order` in the tree
+    /// and the rest to one side.
+    fn assemble(src_objects: &[HitArc], nodes: Vec<LinearNode>, order: Vec<u32>, in_tree: usize) -> BVHNode {
+        let pick = |i: &u32| Arc::clone(&src_objects[*i as usize]);
+        let objects = order[..in_tree].iter().map(pick).collect();
+        let unbounded = HitList{ objects: order[in_tree..].iter().map(pick).collect() };
+        let bounds = match nodes.first() {
+            Some(root) if unbounded.objects.is_empty() => Some(root.bounds),
+            _ => None,
+        };
+        let bvh = BVHNode{ nodes, objects, unbounded, bounds, order };
+        if let Some(built) = BUILT.lock().unwrap().as_mut() {
+            built.push(bvh.stats());
+        }
+        bvh
+    }
+
+    /**
+     * Like `from_vec`, but the tree is kept in `directory` between runs. It's filed under a hash
+     * of the objects' bounding boxes, which are all the builder looks at, so a tree read back is
+     * exactly the one that would have been built. A file that's unreadable, cut short or from a
+     * different version of the builder is stale, and the tree is built again and written over it.
+     * Failing to write the file only means building the tree again next time.
+     */
+    pub fn cached(src_objects: &[HitArc], time0: Float, time1: Float, directory: &Path) -> BVHNode {
+        let boxes = boxes(src_objects, time0, time1);
+        let key = geometry_hash(&boxes, time0, time1);
+        let path = directory.join(format!("{:016x}.bvh", key));
+        if let Some(bvh) = fs::read(&path).ok().and_then(|data| BVHNode::decode(&data, key, src_objects, &boxes)) {
+            return bvh;
+        }
+
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let bvh = BVHNode::from_boxes(src_objects, &boxes, threads);
+        // Written beside it and then moved into place, so that another run never reads half a file.
+        let partial = path.with_extension("partial");
+        let _ = fs::create_dir_all(directory)
+            .and_then(|_| fs::write(&partial, bvh.encode(key)))
+            .and_then(|_| fs::rename(&partial, &path));
+        bvh
+    }
+
+    fn encode(&self, key: u64) -> Vec<u8> {
+        let node_size = 6 * mem::size_of::<Float>() + 7;
+        let mut data = Vec::with_capacity(64 + 4 * self.order.len() + node_size * self.nodes.len());
+        data.extend_from_slice(CACHE_MAGIC);
+        data.extend_from_slice(&builder_hash().to_le_bytes());
+        data.extend_from_slice(&key.to_le_bytes());
+        for count in [self.order.len(), self.objects.len(), self.nodes.len()] {
+            data.extend_from_slice(&(count as u64).to_le_bytes());
+        }
+        for i in &self.order {
+            data.extend_from_slice(&i.to_le_bytes());
+        }
+        for node in &self.nodes {
+            for x in node.bounds.min.as_array().iter().chain(node.bounds.max.as_array().iter()) {
+                data.extend_from_slice(&x.to_le_bytes());
+            }
+            data.extend_from_slice(&node.offset.to_le_bytes());
+            data.extend_from_slice(&node.count.to_le_bytes());
+            data.push(node.axis);
+        }
+        data
+    }
+
+    /**
+     * Reads back what `encode` wrote, if it was written by this builder for these objects. The
+     * layout is checked as well, so that a damaged file can't send traversal out of bounds or
+     * round in circles.
+     */
+    fn decode(data: &[u8], key: u64, src_objects: &[HitArc], boxes: &[Option<AABB>]) -> Option<BVHNode> {
+        let mut reader = Reader{ data };
+        if reader.take(CACHE_MAGIC.len())? != CACHE_MAGIC || reader.u64()? != builder_hash() || reader.u64()? != key {
+            return None;
+        }
+        let (count, in_tree, node_count) = (reader.u64()? as usize, reader.u64()? as usize, reader.u64()? as usize);
+        if count != src_objects.len() || in_tree != boxes.iter().filter(|b| b.is_some()).count()
+            || node_count > 2 * in_tree || (node_count == 0) != (in_tree == 0) {
+            return None;
+        }
+
+        let mut order = Vec::with_capacity(count);
+        let mut seen = vec![false; count];
+        for position in 0..count {
+            let i = reader.u32()?;
+            let slot = seen.get_mut(i as usize)?;
+            if *slot || boxes[i as usize].is_some() != (position < in_tree) {
+                return None;
+            }
+            *slot = true;
+            order.push(i);
+        }
+
+        let mut nodes = Vec::with_capacity(node_count);
+        let mut depths = vec![0; node_count];
+        for index in 0..node_count {
+            let mut corner = || Some(Point3::new(reader.float()?, reader.float()?, reader.float()?));
+            let bounds = AABB::new(corner()?, corner()?);
+            let node = LinearNode{ bounds, offset: reader.u32()?, count: reader.u16()?, axis: reader.u8()? };
+            let (offset, depth) = (node.offset as usize, depths[index] + 1);
+            if node.count > 0 {
+                if node.count as usize > LEAF_SIZE || offset + node.count as usize > in_tree {
+                    return None;
+                }
+            } else if offset <= index + 1 || offset >= node_count || node.axis > 2 || depth > STACK_SIZE {
+                return None;
+            } else {
+                // Where a damaged file gives a node two parents, the deeper one counts.
+                depths[index + 1] = depths[index + 1].max(depth);
+                depths[offset] = depths[offset].max(depth);
+            }
+            nodes.push(node);
+        }
+        if !reader.data.is_empty() {
+            return None;
+        }
+
+        Some(BVHNode::assemble(src_objects, nodes, order, in_tree))
+    }
+
+    /**
+     * Updates the tree for objects that have moved or changed shape, without building it again.
+     * `src_objects` replace the ones the tree was built over, in the same order, and each takes
+     * its predecessor's place in the tree while the boxes are fitted around them from the leaves
+     * up. That's far cheaper than a rebuild, but the splits were chosen for where things used to
+     * be, so after big moves rays visit more of the tree than they'd need to in a fresh one.
+     *
+     * Panics if the number of objects has changed, or one that had a box no longer does.
+     */
+    pub fn refit(&mut self, src_objects: &[HitArc], time0: Float, time1: Float) {
+        assert_eq!(src_objects.len(), self.order.len(), "BVHNode::refit needs as many objects as it was built over");
+        let (bounded, unbounded) = self.order.split_at(self.objects.len());
+        for (object, &i) in self.objects.iter_mut().zip(bounded) {
+            *object = Arc::clone(&src_objects[i as usize]);
+        }
+        self.unbounded.objects = unbounded.iter().map(|&i| Arc::clone(&src_objects[i as usize])).collect();
+
+        // Children always come after their parents, so going backwards reaches both children of a
+        // node before the node itself.
+        for index in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[index];
+            let bounds = if node.count > 0 {
+                let start = node.offset as usize;
+                self.objects[start..start + node.count as usize].iter().map(|object| {
+                    object.bounding_box(time0, time1)
+                        .expect("BVHNode::refit can't take an object without a box into the tree")
+                }).reduce(surrounding_box).unwrap()
+            } else {
+                surrounding_box(self.nodes[index + 1].bounds, self.nodes[node.offset as usize].bounds)
+            };
+            self.nodes[index].bounds = bounds;
+        }
+        if self.unbounded.objects.is_empty() {
+            self.bounds = self.nodes.first().map(|root| root.bounds);
+        }
+    }
+
+    /**
+     * Appends the subtree over the indexed primitives to the nodes, depth first. The indices are
+     * reordered in place so that each side of a split is a contiguous run of them, `first` being
+     * where this run starts among all of them.
+     *
+     * Once a split is chosen, big enough halves are built at the same time, the far one on a
+     * thread of its own into a separate array that's then moved in behind the near one. `threads`
+     * is how many this subtree may use between them.
+     */
+    fn build(primitives: &Primitives, indices: &mut [u32], first: usize, depth: usize, threads: usize,
+        nodes: &mut Vec<LinearNode>) {
+
+        let bounds = indices.iter().skip(1)
+            .fold(primitives.bounds[indices[0] as usize], |b, &i| surrounding_box(b, primitives.bounds[i as usize]));
+        let index = nodes.len();
+        nodes.push(LinearNode{ bounds, offset: first as u32, count: indices.len() as u16, axis: 0 });
+        if indices.len() == 1 {
+            return;
+        }
+
+        let split = if depth < MAX_SAH_DEPTH { best_split(primitives, indices, bounds) } else { None };
+        let (axis, mid) = match split {
+            Some((axis, bin, cost)) if indices.len() > LEAF_SIZE || cost < indices.len() as Float => {
+                (axis, partition(primitives, indices, axis, bin))
+            }
+            _ if indices.len() <= LEAF_SIZE => return,
+            // Too many to leave in one leaf but with nothing to tell them apart, like a pile of
+            // objects all centered in the same place, so just halve them.
+            _ => (0, indices.len() / 2),
+        };
+
+        let parallel = threads > 1 && indices.len() >= PARALLEL_BUILD;
+        let (left, right) = indices.split_at_mut(mid);
+        let second = if parallel {
+            let mut far = Vec::with_capacity(2 * right.len());
+            thread::scope(|scope| {
+                scope.spawn(|| BVHNode::build(primitives, right, first + mid, depth + 1, threads / 2, &mut far));
+                BVHNode::build(primitives, left, first, depth + 1, threads - threads / 2, nodes);
+            });
+            // The far subtree's links to its own nodes were counted from the start of its array.
+            let second = nodes.len();
+            nodes.extend(far.into_iter().map(|mut node| {
+                if node.count == 0 {
+                    node.offset += second as u32;
                 }
-            },
+                node
+            }));
+            second
+        } else {
+            BVHNode::build(primitives, left, first, depth + 1, 1, nodes);
+            let second = nodes.len();
+            BVHNode::build(primitives, right, first + mid, depth + 1, 1, nodes);
+            second
         };
+        nodes[index] = LinearNode{ bounds, offset: second as u32, count: 0, axis: axis as u8 };
+    }
+
+    /// The box around the node at `index` and what it holds, the root being at 0. None past the
+    /// last node, which for a tree of only unbounded objects is every index.
+    pub fn node(&self, index: usize) -> Option<(AABB, Node)> {
+        let node = self.nodes.get(index)?;
+        let (offset, count) = (node.offset as usize, node.count as usize);
+        let contents = if count > 0 { Node::Leaf(offset..offset + count) } else { Node::Inner(index + 1, offset) };
+        Some((node.bounds, contents))
+    }
 
-        let mut box_left = AABB::default();
-        let mut box_right = AABB::default();
+    /// The objects in the tree, in the order its leaves refer to them.
+    pub fn objects(&self) -> &[HitArc] {
+        &self.objects
+    }
 
-        if (! node.left.bounding_box(time0, time1, &mut box_left)) ||
-        (! node.right.bounding_box(time0, time1, &mut box_right)) {
-            // Is this a panic? How do we decide to skip objects without the box?
-            panic!("Trying to add an object with no bounding box to a BVHNode");
+    /// The objects kept out of the tree for having no box.
+    pub fn unbounded(&self) -> &HitList {
+        &self.unbounded
+    }
+
+    /// Walks the tree to measure its shape.
+    pub fn stats(&self) -> BVHStats {
+        let mut stats = BVHStats{
+            objects: self.order.len(),
+            unbounded: self.unbounded.objects.len(),
+            nodes: self.nodes.len(),
+            ..BVHStats::default()
+        };
+        let root_area = self.nodes.first().map_or(1.0, |root| root.bounds.surface_area());
+        let mut depths = vec![0; self.nodes.len()];
+        let mut leaf_depths = 0;
+        for (index, node) in self.nodes.iter().enumerate() {
+            let depth = depths[index];
+            stats.depth = stats.depth.max(depth);
+            let area = node.bounds.surface_area() / root_area;
+            if node.count > 0 {
+                stats.leaf_sizes[node.count as usize - 1] += 1;
+                stats.sah_cost += area * node.count as Float;
+                leaf_depths += depth;
+            } else {
+                stats.sah_cost += area * TRAVERSAL_COST;
+                depths[index + 1] = depth + 1;
+                depths[node.offset as usize] = depth + 1;
+            }
         }
+        let leaves: usize = stats.leaf_sizes.iter().sum();
+        stats.mean_leaf_depth = leaf_depths as Float / leaves.max(1) as Float;
+        stats
+    }
+}
 
-        node.bounds = surrounding_box(box_left, box_right);
+impl Traverse for BVHNode {
+    type Leaf<'a> = &'a [HitArc];
 
-        node
+    fn unbounded(&self) -> &HitList {
+        &self.unbounded
+    }
+
+    /// Nearest side of each split first.
+    fn traverse<'a, F>(&'a self, ray: Ray, t_min: Float, mut t_max: Float, mut visit: F)
+        where F: FnMut(Self::Leaf<'a>, Float) -> Float {
+
+        if self.nodes.is_empty() {
+            return;
+        }
+        let origin = ray.origin.as_array();
+        let direction = ray.direction.as_array();
+        let inv_d = [1.0 / direction[0], 1.0 / direction[1], 1.0 / direction[2]];
+
+        let mut stack = [0u32; STACK_SIZE];
+        let mut top = 0;
+        let mut current = 0;
+        let mut counts = TraversalCounts::default();
+        loop {
+            let node = &self.nodes[current];
+            counts.nodes += 1;
+            if node.bounds.hit(origin, inv_d, t_min, t_max) {
+                if node.count > 0 {
+                    let start = node.offset as usize;
+                    counts.objects += node.count as u64;
+                    t_max = visit(&self.objects[start..start + node.count as usize], t_max);
+                    if t_max < t_min {
+                        break;
+                    }
+                } else if inv_d[node.axis as usize] < 0.0 {
+                    stack[top] = current as u32 + 1;
+                    top += 1;
+                    current = node.offset as usize;
+                    continue;
+                } else {
+                    stack[top] = node.offset;
+                    top += 1;
+                    current += 1;
+                    continue;
+                }
+            }
+            if top == 0 {
+                break;
+            }
+            top -= 1;
+            current = stack[top] as usize;
+        }
+        add_counts(counts);
+    }
+}
+
+/// How much work rays have made for the trees: nodes whose boxes were tested, and objects tested
+/// in the leaves those led to.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct TraversalCounts {
+    pub nodes: u64,
+    pub objects: u64,
+}
+
+impl ops::Add for TraversalCounts {
+    type Output = TraversalCounts;
+
+    fn add(self, rhs: TraversalCounts) -> TraversalCounts {
+        TraversalCounts{ nodes: self.nodes + rhs.nodes, objects: self.objects + rhs.objects }
+    }
+}
+
+thread_local! {
+    /// Kept per thread, and only added to once per ray per tree, so counting costs next to nothing
+    /// and render threads never contend for it.
+    static COUNTS: Cell<TraversalCounts> = Cell::new(TraversalCounts::default());
+}
+
+/// Adds to what this thread has counted, for other trees that count their work the same way.
+pub fn add_counts(counts: TraversalCounts) {
+    COUNTS.with(|total| total.set(total.get() + counts));
+}
+
+/// What every tree has counted on this thread since the last call, starting again from zero.
+pub fn take_counts() -> TraversalCounts {
+    COUNTS.with(|total| total.replace(TraversalCounts::default()))
+}
+
+/// The stats of each tree as it's built, once `record_stats` has been called.
+static BUILT: Mutex<Option<Vec<BVHStats>>> = Mutex::new(None);
+
+/// Starts keeping the stats of every tree built from here on, including those inside meshes.
+pub fn record_stats() {
+    BUILT.lock().unwrap().get_or_insert_with(Vec::new);
+}
+
+/// The stats of every tree built since `record_stats`, in the order they were built.
+pub fn built_stats() -> Vec<BVHStats> {
+    BUILT.lock().unwrap().as_mut().map_or_else(Vec::new, std::mem::take)
+}
+
+/// The shape of a built tree, for working out why a scene is slow.
+#[derive(Debug, Default)]
+pub struct BVHStats {
+    pub objects: usize,
+    pub unbounded: usize,
+    pub nodes: usize,
+    /// How many leaves hold one object, two, and so on.
+    pub leaf_sizes: [usize; LEAF_SIZE],
+    pub depth: usize,
+    pub mean_leaf_depth: Float,
+    /// What the surface area heuristic expects a ray that hits the root's box to cost, in tests
+    /// of one object.
+    pub sah_cost: Float,
+}
+
+impl fmt::Display for BVHStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let leaves: usize = self.leaf_sizes.iter().sum();
+        write!(f, "{} objects ({} unbounded), {} nodes, {} leaves (",
+            self.objects, self.unbounded, self.nodes, leaves)?;
+        for (i, count) in self.leaf_sizes.iter().enumerate() {
+            write!(f, "{}{} of {}", if i > 0 { ", " } else { "" }, count, i + 1)?;
+        }
+        write!(f, "), depth {} (leaves at {:.1} on average), SAH cost {:.2}",
+            self.depth, self.mean_leaf_depth, self.sah_cost)
+    }
+}
+
+/// The range of centroids along an axis, which the bins are spread evenly across.
+fn binning(primitives: &Primitives, indices: &[u32], axis: usize) -> (Float, Float) {
+    let along = |i: &u32| primitives.centroids[*i as usize].as_array()[axis];
+    let low = indices.iter().map(along).fold(Float::INFINITY, Float::min);
+    let high = indices.iter().map(along).fold(Float::NEG_INFINITY, Float::max);
+    (low, high)
+}
+
+fn bin_of(centroid: Point3, axis: usize, (low, high): (Float, Float)) -> usize {
+    let offset = (centroid.as_array()[axis] - low) / (high - low);
+    ((offset * BINS as Float) as usize).min(BINS - 1)
+}
+
+/**
+ * The cheapest way to split the primitives between bins on any axis, as the axis, the first bin
+ * on the right and the expected cost relative to testing one object. None when every centroid is
+ * in the same place.
+ */
+fn best_split(primitives: &Primitives, indices: &[u32], bounds: AABB) -> Option<(usize, usize, Float)> {
+    let mut best: Option<(usize, usize, Float)> = None;
+    for axis in 0..3 {
+        let range = binning(primitives, indices, axis);
+        if range.1 <= range.0 {
+            continue;
+        }
+
+        let mut counts = [0usize; BINS];
+        let mut boxes: [Option<AABB>; BINS] = [None; BINS];
+        for &i in indices {
+            let (centroid, aabb) = (primitives.centroids[i as usize], primitives.bounds[i as usize]);
+            let bin = bin_of(centroid, axis, range);
+            counts[bin] += 1;
+            boxes[bin] = Some(boxes[bin].map_or(aabb, |b| surrounding_box(b, aabb)));
+        }
+
+        // Sweep from the right to get the area and count of everything past each split, then from
+        // the left to price each split.
+        let mut right_area = [0.0; BINS];
+        let mut right_count = [0usize; BINS];
+        let mut sweep: Option<AABB> = None;
+        let mut count = 0;
+        for bin in (1..BINS).rev() {
+            sweep = merge(sweep, boxes[bin]);
+            count += counts[bin];
+            right_area[bin] = sweep.map_or(0.0, |b| b.surface_area());
+            right_count[bin] = count;
+        }
+
+        let mut sweep: Option<AABB> = None;
+        let mut count = 0;
+        for bin in 1..BINS {
+            sweep = merge(sweep, boxes[bin - 1]);
+            count += counts[bin - 1];
+            if count == 0 || right_count[bin] == 0 {
+                continue;
+            }
+            let left_area = sweep.map_or(0.0, |b| b.surface_area());
+            let cost = TRAVERSAL_COST
+                + (left_area * count as Float + right_area[bin] * right_count[bin] as Float) / bounds.surface_area();
+            if best.is_none_or(|(_, _, c)| cost < c) {
+                best = Some((axis, bin, cost));
+            }
+        }
+    }
+    best
+}
+
+fn merge(a: Option<AABB>, b: Option<AABB>) -> Option<AABB> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(surrounding_box(a, b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+/// Moves the indices of primitives left of the split bin to the front, returning how many there are.
+fn partition(primitives: &Primitives, indices: &mut [u32], axis: usize, split: usize) -> usize {
+    let range = binning(primitives, indices, axis);
+    let mut mid = 0;
+    for i in 0..indices.len() {
+        if bin_of(primitives.centroids[indices[i] as usize], axis, range) < split {
+            indices.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+fn boxes(src_objects: &[HitArc], time0: Float, time1: Float) -> Vec<Option<AABB>> {
+    src_objects.iter().map(|object| object.bounding_box(time0, time1)).collect()
+}
+
+/// FNV-1a, which is quick, simple and spreads keys well enough to name files by.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// Everything the tree built over the objects depends on.
+fn geometry_hash(boxes: &[Option<AABB>], time0: Float, time1: Float) -> u64 {
+    let mut hash = fnv1a(FNV_OFFSET, &time0.to_le_bytes());
+    hash = fnv1a(hash, &time1.to_le_bytes());
+    hash = fnv1a(hash, &(boxes.len() as u64).to_le_bytes());
+    for aabb in boxes {
+        match aabb {
+            Some(aabb) => {
+                hash = fnv1a(hash, &[1]);
+                for x in aabb.min.as_array().iter().chain(aabb.max.as_array().iter()) {
+                    hash = fnv1a(hash, &x.to_le_bytes());
+                }
+            }
+            None => hash = fnv1a(hash, &[0]),
+        }
+    }
+    hash
+}
+
+/// Changes whenever the cache layout or any of the builder's settings do, so that a tree built
+/// differently is never read back. That includes the float type, which sets how big the boxes are
+/// and so how many bytes of the traversal cost get hashed.
+fn builder_hash() -> u64 {
+    let settings = [CACHE_VERSION, LEAF_SIZE as u64, BINS as u64, MAX_SAH_DEPTH as u64];
+    let hash = settings.iter().fold(FNV_OFFSET, |hash, setting| fnv1a(hash, &setting.to_le_bytes()));
+    fnv1a(hash, &TRAVERSAL_COST.to_le_bytes())
+}
+
+/// Takes little-endian values off the front of a cached tree, or None once it runs out.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn float(&mut self) -> Option<Float> {
+        Some(Float::from_le_bytes(self.take(mem::size_of::<Float>())?.try_into().ok()?))
     }
 }
 
 impl Hit for BVHNode {
-    fn hit(&self, ray: crate::ray::Ray, t_min: f64, t_max: f64, hitrec: &mut crate::hit::HitRecord) -> bool {
-        if !self.bounds.hit(ray, t_min, t_max) {
-            return false;
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        accelerator::hit(self, ray, t_min, t_max)
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        accelerator::occluded(self, ray, t_min, t_max)
+    }
+
+    fn hit_all<'a>(&'a self, ray: Ray, t_min: Float, t_max: Float, hits: &mut Vec<HitRecord<'a>>) {
+        accelerator::hit_all(self, ray, t_min, t_max, hits);
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        self.bounds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::accelerator;
+    use crate::bvh::{boxes, BVHNode, geometry_hash, LinearNode, PARALLEL_BUILD, take_counts};
+    use crate::float::Float;
+    use crate::hit::{Hit, HitArc, HitList};
+    use crate::material::Lambertian;
+    use crate::quad::Plane;
+    use crate::ray::Ray;
+    use crate::rand::randrange;
+    use crate::sphere::Sphere;
+    use crate::vec3::{Color, Point3, Vec3};
+    use std::fs;
+    use std::sync::Arc;
+
+    #[test]
+    fn unbounded_objects() {
+        let material = || Lambertian::from_color(Color::default());
+        let up = Vec3::new(0.0, 1.0, 0.0);
+
+        let mut list = HitList::default();
+        list.add(Plane::new(Point3::new(0.0, 0.0, 0.0), up, material()));
+        for i in 0..5 {
+            list.add(Sphere::new(Point3::new(3.0*i as Float, 1.0, 0.0), 1.0, material()));
+        }
+        let bvh = BVHNode::from_hitlist(&list, 0.0, 1.0);
+        assert!(bvh.bounding_box(0.0, 1.0).is_none());
+
+        let down = Ray::new(Point3::new(6.0, 5.0, 0.0), -up, 0.0);
+        assert!((bvh.hit(down, 0.001, Float::INFINITY).unwrap().t - 3.0).abs() < 1e-9);
+        let down = Ray::new(Point3::new(7.5, 5.0, 0.0), -up, 0.0);
+        assert!((bvh.hit(down, 0.001, Float::INFINITY).unwrap().t - 5.0).abs() < 1e-9);
+
+        let mut planes = HitList::default();
+        planes.add(Plane::new(Point3::new(0.0, 0.0, 0.0), up, material()));
+        planes.add(Plane::new(Point3::new(0.0, 2.0, 0.0), up, material()));
+        let bvh = BVHNode::from_hitlist(&planes, 0.0, 1.0);
+        assert!((bvh.hit(down, 0.001, Float::INFINITY).unwrap().t - 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn same_hits_as_a_list() {
+        let list = accelerator::tests::objects(200);
+        let bvh = BVHNode::from_hitlist(&list, 0.0, 1.0);
+        accelerator::tests::assert_same_hits_for_random_rays(&list, &bvh, "BVHNode");
+    }
+
+    #[test]
+    fn refit() {
+        let material = || Lambertian::from_color(Color::default());
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let centers: Vec<Point3> = (0..100).map(|_| Vec3::randrange(-10.0, 10.0)).collect();
+        let place = |offset: Vec3| {
+            let mut objects: Vec<HitArc> = centers.iter().map(|c| Sphere::new(*c + offset, 0.5, material())).collect();
+            objects.insert(50, Plane::new(Point3::new(0.0, -20.0, 0.0) + offset, up, material()));
+            objects
+        };
+        let mut bvh = BVHNode::from_vec(&place(Vec3::default()), 0, 101, 0.0, 1.0);
+
+        // Everything moves far enough that none of the old boxes would do.
+        let moved = place(Vec3::new(30.0, 5.0, 0.0));
+        bvh.refit(&moved, 0.0, 1.0);
+        let list = HitList{ objects: moved };
+        for _ in 0..500 {
+            let ray = Ray::new(Vec3::randrange(15.0, 45.0), Vec3::randrange(-1.0, 1.0), 0.0);
+            let expected = list.hit(ray, 0.001, Float::INFINITY).map(|hitrec| hitrec.t);
+            assert_eq!(bvh.hit(ray, 0.001, Float::INFINITY).map(|hitrec| hitrec.t), expected);
         }
+    }
+
+    #[test]
+    fn cache() {
+        let directory = std::env::temp_dir().join(format!("shirley-bvh-{}", std::process::id()));
+        let material = || Lambertian::from_color(Color::default());
+        let mut objects: Vec<HitArc> = (0..300)
+            .map(|_| Sphere::new(Vec3::randrange(-10.0, 10.0), 0.5, material()))
+            .collect();
+        objects.push(Plane::new(Point3::default(), Vec3::new(0.0, 1.0, 0.0), material()));
+
+        let built = BVHNode::cached(&objects, 0.0, 1.0, &directory);
+        let files: Vec<_> = fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        let size = fs::metadata(&files[0]).unwrap().len();
 
-        let hit_left = self.left.hit(ray, t_min, t_max, hitrec);
-        let hit_right = self.right.hit(ray, t_min, if hit_left {hitrec.t} else {t_max}, hitrec);
+        let same = |a: &BVHNode, b: &BVHNode| {
+            let layout = |n: &LinearNode| (n.offset, n.count, n.bounds.min.as_array(), n.bounds.max.as_array());
+            a.order == b.order && a.nodes.iter().map(layout).eq(b.nodes.iter().map(layout))
+        };
+        let loaded = BVHNode::cached(&objects, 0.0, 1.0, &directory);
+        assert!(same(&built, &loaded));
+        assert_eq!(loaded.unbounded.objects.len(), 1);
+
+        // A cut-short file, or one for other geometry, is never read back.
+        let data = fs::read(&files[0]).unwrap();
+        let key = geometry_hash(&boxes(&objects, 0.0, 1.0), 0.0, 1.0);
+        assert!(BVHNode::decode(&data, key ^ 1, &objects, &boxes(&objects, 0.0, 1.0)).is_none());
+        fs::write(&files[0], &data[..data.len() / 2]).unwrap();
+        let rebuilt = BVHNode::cached(&objects, 0.0, 1.0, &directory);
+        assert!(same(&built, &rebuilt));
+        assert_eq!(fs::metadata(&files[0]).unwrap().len(), size);
 
-        hit_left || hit_right
+        fs::remove_dir_all(&directory).unwrap();
     }
 
-    fn bounding_box(&self, _time0: f64, _time1: f64, aabb: &mut AABB) -> bool {
-        *aabb = self.bounds;
-        true
+    #[test]
+    fn stats_and_counts() {
+        let mut list = HitList::default();
+        for i in 0..64 {
+            let material = Lambertian::from_color(Color::default());
+            list.add(Sphere::new(Point3::new(3.0 * i as Float, 0.0, 0.0), 1.0, material));
+        }
+        let bvh = BVHNode::from_hitlist(&list, 0.0, 1.0);
+        let stats = bvh.stats();
+        let held: usize = stats.leaf_sizes.iter().enumerate().map(|(i, count)| (i + 1) * count).sum();
+        let leaves: usize = stats.leaf_sizes.iter().sum();
+        assert_eq!((held, stats.nodes), (64, 2 * leaves - 1));
+        assert!(stats.depth >= 4 && stats.sah_cost > 1.0);
+
+        // Along the row through the corners of the boxes, a ray misses every sphere but has to
+        // test them all. One across the row only reaches the leaf it crosses.
+        take_counts();
+        let along = Ray::new(Point3::new(-5.0, 0.9, 0.9), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        assert!(bvh.hit(along, 0.001, Float::INFINITY).is_none());
+        let along = take_counts();
+        let across = Ray::new(Point3::new(30.0, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        assert!(bvh.hit(across, 0.001, Float::INFINITY).is_some());
+        let across = take_counts();
+        assert_eq!((along.objects, along.nodes), (64, stats.nodes as u64));
+        assert!(across.objects <= 4 && across.nodes <= 2 * stats.depth as u64 + 1);
+    }
+
+    #[test]
+    fn threads_build_the_same_tree() {
+        let objects: Vec<HitArc> = (0..3 * PARALLEL_BUILD)
+            .map(|_| {
+                let material = Lambertian::from_color(Color::default());
+                Sphere::new(Vec3::randrange(-50.0, 50.0), randrange(0.1, 1.0), material)
+            })
+            .collect();
+        let one = BVHNode::with_threads(&objects, 0.0, 1.0, 1);
+        let four = BVHNode::with_threads(&objects, 0.0, 1.0, 4);
+
+        assert_eq!(one.nodes.len(), four.nodes.len());
+        for (a, b) in one.nodes.iter().zip(four.nodes.iter()) {
+            assert_eq!((a.offset, a.count, a.axis), (b.offset, b.count, b.axis));
+            let corners = |n: &LinearNode| (n.bounds.min.as_array(), n.bounds.max.as_array());
+            assert_eq!(corners(a), corners(b));
+        }
+        assert!(one.objects.iter().zip(four.objects.iter()).all(|(a, b)| Arc::ptr_eq(a, b)));
     }
 }
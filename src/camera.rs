@@ -1,3 +1,4 @@
+use crate::float::Float;
 use crate::vec3::{cross, Point3, unit_vector, Vec3};
 use crate::ray::Ray;
 use crate::rand::randrange;
@@ -9,9 +10,9 @@ pub struct Camera {
     vertical: Vec3,
     u: Vec3,
     v: Vec3,
-    lens_radius: f64,
-    time0: f64,
-    time1: f64,
+    lens_radius: Float,
+    time0: Float,
+    time1: Float,
 }
 
 impl Camera {
@@ -19,12 +20,12 @@ impl Camera {
         lookfrom: Point3,
         lookat: Point3,
         vup: Vec3,
-        vfov: f64,
-        aspect_ratio: f64,
-        aperture: f64,
-        focus_dist: f64,
-        time0: f64,
-        time1: f64,
+        vfov: Float,
+        aspect_ratio: Float,
+        aperture: Float,
+        focus_dist: Float,
+        time0: Float,
+        time1: Float,
     ) -> Camera {
         let theta = vfov.to_radians();
         let h = (theta/2.0).tan();
@@ -45,7 +46,7 @@ impl Camera {
         Camera{ origin, lower_left_corner, horizontal, vertical, u, v, lens_radius, time0, time1 }
     }
 
-    pub fn get_ray(&self, s: f64, t: f64) -> Ray {
+    pub fn get_ray(&self, s: Float, t: Float) -> Ray {
         let rd = self.lens_radius * Vec3::random_in_unit_disk();
         let offset = self.u*rd.x + self.v*rd.y;
 
@@ -0,0 +1,203 @@
+use crate::aabb::{AABB, surrounding_box};
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitRecord};
+use crate::ray::Ray;
+use crate::vec3::Point3;
+use std::sync::Arc;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    /// Everything in the left object that isn't in the right one.
+    Difference,
+}
+
+impl CsgOp {
+    fn inside(&self, left: bool, right: bool) -> bool {
+        match self {
+            CsgOp::Union => left || right,
+            CsgOp::Intersection => left && right,
+            CsgOp::Difference => left && !right,
+        }
+    }
+}
+
+/**
+ * Combines two closed objects into one solid. Every crossing of both along the whole ray is
+ * walked in order, counting whether the ray is inside each, and the result's surface is wherever
+ * being inside the combination changes. The surface keeps the material and UVs of whichever
+ * object it came from, so the walls of a hole are the material of the thing that cut it.
+ *
+ * Insideness comes from counting crossings rather than from the normals, so the objects have to
+ * be closed but needn't agree on which way their normals face.
+ */
+pub struct Csg {
+    op: CsgOp,
+    left: HitArc,
+    right: HitArc,
+}
+
+impl Csg {
+    pub fn new(op: CsgOp, left: HitArc, right: HitArc) -> HitArc {
+        Arc::new(Csg{ op, left, right })
+    }
+
+    pub fn union(left: HitArc, right: HitArc) -> HitArc {
+        Csg::new(CsgOp::Union, left, right)
+    }
+
+    pub fn intersection(left: HitArc, right: HitArc) -> HitArc {
+        Csg::new(CsgOp::Intersection, left, right)
+    }
+
+    pub fn difference(left: HitArc, right: HitArc) -> HitArc {
+        Csg::new(CsgOp::Difference, left, right)
+    }
+}
+
+/// Room for the crossings of both objects in `hit` and `occluded`, which is plenty for a pair of
+/// simple shapes without growing.
+const CROSSINGS: usize = 8;
+
+impl Csg {
+    /**
+     * Appends where the ray goes into or out of the combination between t_min and t_max to `hits`,
+     * or only the first place if `first` is set. Both objects' crossings are gathered in `hits`
+     * itself while they're walked, and taken out again afterwards.
+     */
+    fn boundaries<'a>(&'a self, ray: Ray, t_min: Float, t_max: Float, first: bool, hits: &mut Vec<HitRecord<'a>>) {
+        // Both objects are needed along the whole ray, since what happens before t_min decides
+        // whether the ray starts out inside.
+        let start = hits.len();
+        self.left.hit_all(ray, Float::NEG_INFINITY, Float::INFINITY, hits);
+        let middle = hits.len();
+        self.right.hit_all(ray, Float::NEG_INFINITY, Float::INFINITY, hits);
+        let end = hits.len();
+
+        let (mut in_left, mut in_right) = (false, false);
+        let (mut i, mut j) = (start, middle);
+        while i < middle || j < end {
+            let from_left = j == end || (i < middle && hits[i].t <= hits[j].t);
+            let before = self.op.inside(in_left, in_right);
+            let crossing = if from_left {
+                in_left = !in_left;
+                i += 1;
+                hits[i - 1]
+            } else {
+                in_right = !in_right;
+                j += 1;
+                hits[j - 1]
+            };
+            let after = self.op.inside(in_left, in_right);
+
+            if crossing.t > t_max {
+                break;
+            }
+            if before != after && t_min <= crossing.t {
+                // Crossings always carry a normal that faces back along the ray, which is also
+                // right for the combined surface. Only which side of it the ray came from changes.
+                let mut boundary = crossing;
+                boundary.front_face = after;
+                hits.push(boundary);
+                if first {
+                    break;
+                }
+            }
+        }
+        hits.drain(start..end);
+    }
+}
+
+impl Hit for Csg {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let mut hits = Vec::with_capacity(CROSSINGS);
+        self.boundaries(ray, t_min, t_max, true, &mut hits);
+        hits.first().copied()
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        let mut hits = Vec::with_capacity(CROSSINGS);
+        self.boundaries(ray, t_min, t_max, true, &mut hits);
+        !hits.is_empty()
+    }
+
+    fn hit_all<'a>(&'a self, ray: Ray, t_min: Float, t_max: Float, hits: &mut Vec<HitRecord<'a>>) {
+        self.boundaries(ray, t_min, t_max, false, hits);
+    }
+
+    fn bounding_box(&self, time0: Float, time1: Float) -> Option<AABB> {
+        let left = self.left.bounding_box(time0, time1);
+        let right = self.right.bounding_box(time0, time1);
+
+        match self.op {
+            CsgOp::Union => Some(surrounding_box(left?, right?)),
+            CsgOp::Intersection => match (left, right) {
+                (Some(left), Some(right)) => Some(overlap(left, right)),
+                (left, right) => left.or(right),
+            },
+            CsgOp::Difference => left,
+        }
+    }
+}
+
+/// Where two boxes overlap. Boxes that don't overlap give an empty box at the edge of one of them.
+fn overlap(a: AABB, b: AABB) -> AABB {
+    let min = Point3::new(a.min.x.max(b.min.x), a.min.y.max(b.min.y), a.min.z.max(b.min.z));
+    let max = Point3::new(a.max.x.min(b.max.x), a.max.y.min(b.max.y), a.max.z.min(b.max.z));
+    AABB::new(min, Point3::new(max.x.max(min.x), max.y.max(min.y), max.z.max(min.z)))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::box3d::Box3D;
+    use crate::csg::Csg;
+    use crate::float::Float;
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+    use crate::vec3::{Color, Point3, Vec3};
+    use std::sync::Arc;
+
+    #[test]
+    fn operations() {
+        let gray = Lambertian::from_color(Color::new(0.5, 0.5, 0.5));
+        let red = Lambertian::from_color(Color::new(1.0, 0.0, 0.0));
+        let cube = || Box3D::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0), Arc::clone(&gray));
+        let ball = || Sphere::new(Point3::new(0.0, 0.0, 1.0), 0.5, Arc::clone(&red));
+        let down = Ray::new(Point3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+
+        // A bite out of the top of the cube. The ray comes in through the bite's floor, which is
+        // the inside of the sphere.
+        let bitten = Csg::difference(cube(), ball());
+        let hitrec = bitten.hit(down, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 4.5).abs() < 1e-9 && hitrec.front_face);
+        assert!((hitrec.normal - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-9);
+        assert!(Arc::ptr_eq(hitrec.material, &red));
+        assert!(bitten.occluded(down, 0.001, 4.6) && !bitten.occluded(down, 0.001, 4.4));
+
+        // Added after whatever the list already held.
+        let mut hits = vec![hitrec];
+        bitten.hit_all(down, 0.001, Float::INFINITY, &mut hits);
+        let ts: Vec<Float> = hits.iter().map(|h| h.t).collect();
+        assert_eq!(ts.len(), 3);
+        assert!((ts[2] - 6.0).abs() < 1e-9 && !hits[2].front_face);
+
+        // Off to the side of the bite the cube's own top is hit.
+        let beside = Ray::new(Point3::new(0.8, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hitrec = bitten.hit(beside, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 4.0).abs() < 1e-9);
+
+        // Only the cap of the sphere that's inside the cube.
+        let lens = Csg::intersection(cube(), ball());
+        let hitrec = lens.hit(down, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 4.0).abs() < 1e-9 && Arc::ptr_eq(hitrec.material, &gray));
+
+        // Starting inside the union, the first surface is the way out.
+        let union = Csg::union(cube(), ball());
+        let inside = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let hitrec = union.hit(inside, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 1.5).abs() < 1e-9 && !hitrec.front_face);
+    }
+}
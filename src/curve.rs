@@ -0,0 +1,322 @@
+use crate::aabb::AABB;
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitRecord};
+use crate::material::Material;
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::vec3::{dot, Point3, unit_vector, Vec3};
+use std::sync::Arc;
+
+/// Subdividing stops here however curvy the curve, which is already 1024 pieces.
+const MAX_DEPTH: usize = 10;
+
+#[derive(Debug, Copy, Clone)]
+pub enum CurveKind {
+    /**
+     * A strip that always turns to face the ray, shaded as though it were round. Cheap fur and
+     * hair that's too thin for the difference to show.
+     */
+    Cylinder,
+    /**
+     * A flat strip facing the given normals at its two ends, blended in between, like a blade of
+     * grass. Seen edge on it gets thinner until it vanishes.
+     */
+    Ribbon(Vec3, Vec3),
+}
+
+/**
+ * A thin cubic Bézier curve whose width changes linearly from one end to the other. u runs along
+ * the curve and v across it, and the record's tangent is the direction the curve runs, for
+ * materials like Hair that shade along it.
+ *
+ * Rays are tested the way pbrt does it: the control points are moved into a frame looking down
+ * the ray, and the curve is split in half until the pieces are close enough to straight to be
+ * tested as line segments, throwing away any piece whose box the ray misses along the way.
+ */
+pub struct Curve {
+    points: [Point3; 4],
+    widths: (Float, Float),
+    kind: CurveKind,
+    material: Material,
+}
+
+impl Curve {
+    pub fn new(points: [Point3; 4], width0: Float, width1: Float, kind: CurveKind, material: Material) -> HitArc {
+        Arc::new(Curve{ points, widths: (width0, width1), kind, material })
+    }
+
+    fn width(&self, u: Float) -> Float {
+        lerp(self.widths.0, self.widths.1, u)
+    }
+
+    /// The closest hit along the piece of the curve between u0 and u1, as (z, u, v), where z is
+    /// the distance down the ray. A ribbon's normals at its two ends are given in the ray's frame.
+    fn recurse(&self, cp: [Vec3; 4], (u0, u1): (Float, Float), depth: usize, (z_min, z_max): (Float, Float),
+        ribbon: Option<(Vec3, Vec3)>) -> Option<(Float, Float, Float)> {
+
+        // The curve lies within the hull of its control points, so if the ray misses their box
+        // widened by half the width, it misses this piece.
+        let half = 0.5 * self.width(u0).max(self.width(u1));
+        let (low, high) = bounds(&cp);
+        if low.x - half > 0.0 || high.x + half < 0.0 || low.y - half > 0.0 || high.y + half < 0.0
+            || high.z + half < z_min || low.z - half > z_max {
+            return None;
+        }
+
+        if depth > 0 {
+            let (a, b) = split(cp);
+            let middle = 0.5 * (u0 + u1);
+            let near = self.recurse(a, (u0, middle), depth - 1, (z_min, z_max), ribbon);
+            let z_max = near.map_or(z_max, |(z, _, _)| z);
+            return self.recurse(b, (middle, u1), depth - 1, (z_min, z_max), ribbon).or(near);
+        }
+
+        // Close enough to straight. Rays past either end of the segment are left for the next
+        // piece, so that pieces don't overlap.
+        let edge = (cp[1].y - cp[0].y) * -cp[0].y + cp[0].x * (cp[0].x - cp[1].x);
+        if edge < 0.0 {
+            return None;
+        }
+        let edge = (cp[2].y - cp[3].y) * -cp[3].y + cp[3].x * (cp[3].x - cp[2].x);
+        if edge < 0.0 {
+            return None;
+        }
+
+        // The nearest point of the segment to the ray, which runs through the origin here.
+        let (sx, sy) = (cp[3].x - cp[0].x, cp[3].y - cp[0].y);
+        let length2 = sx*sx + sy*sy;
+        if length2 == 0.0 {
+            return None;
+        }
+        let w = ((-cp[0].x*sx - cp[0].y*sy) / length2).clamp(0.0, 1.0);
+        let u = lerp(u0, u1, w);
+
+        // A ribbon looks as wide as its normal there faces down the ray.
+        let mut width = self.width(u);
+        if let Some((n0, n1)) = ribbon {
+            width *= slerp(n0, n1, u).z.abs();
+        }
+        let (center, along) = evaluate(cp, w);
+        let distance2 = center.x*center.x + center.y*center.y;
+        if distance2 > 0.25*width*width || center.z < z_min || center.z > z_max {
+            return None;
+        }
+
+        let across = distance2.sqrt() / width;
+        let side = along.x * -center.y + center.x * along.y;
+        let v = if side > 0.0 { 0.5 + across } else { 0.5 - across };
+        Some((center.z, u, v))
+    }
+}
+
+impl Hit for Curve {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        // Looking down the ray, so the ray is the z axis and distances along it are z over speed.
+        let speed = ray.direction.length();
+        let frame = Onb::from_w(ray.direction);
+        let cp = self.points.map(|p| frame.project(p - ray.origin));
+
+        // Split until the pieces are flat to within a twentieth of the width.
+        let mut bend: Float = 0.0;
+        for i in 0..2 {
+            let d = cp[i] - 2.0*cp[i + 1] + cp[i + 2];
+            bend = bend.max(d.x.abs()).max(d.y.abs()).max(d.z.abs());
+        }
+        let epsilon = 0.05 * self.widths.0.max(self.widths.1);
+        let depth = if bend > 0.0 && epsilon > 0.0 {
+            let levels = (crate::float::consts::SQRT_2 * 6.0 * bend / (8.0 * epsilon)).log2() / 2.0;
+            levels.round().clamp(0.0, MAX_DEPTH as Float) as usize
+        } else {
+            0
+        };
+
+        let ribbon = match self.kind {
+            CurveKind::Ribbon(n0, n1) => Some((frame.project(unit_vector(n0)), frame.project(unit_vector(n1)))),
+            CurveKind::Cylinder => None,
+        };
+
+        let (z, u, v) = self.recurse(cp, (0.0, 1.0), depth, (t_min*speed, t_max*speed), ribbon)?;
+
+        let t = z / speed;
+        let (center, along) = evaluate(self.points, u);
+        let tangent = unit_vector(along);
+
+        let normal = match self.kind {
+            CurveKind::Cylinder => {
+                // Bend the normal round from facing the ray in the middle to edge on at the sides,
+                // as it would on a round fiber.
+                let toward = -ray.direction / speed;
+                let facing = unit_vector(toward - dot(toward, tangent)*tangent);
+                let offset = ray.at(t) - center;
+                let offset = offset - dot(offset, tangent)*tangent - dot(offset, facing)*facing;
+                let sin = (2.0*(v - 0.5)).clamp(-1.0, 1.0);
+                if offset.near_zero() {
+                    facing
+                } else {
+                    (1.0 - sin*sin).sqrt()*facing + sin.abs()*unit_vector(offset)
+                }
+            }
+            CurveKind::Ribbon(n0, n1) => {
+                let normal = slerp(unit_vector(n0), unit_vector(n1), u);
+                unit_vector(normal - dot(normal, tangent)*tangent)
+            }
+        };
+
+        let mut hitrec = HitRecord::new(ray, t, normal, u, v, &self.material);
+        hitrec.tangent = tangent;
+        // The hit is on a flat ribbon standing in for the fiber, so a ray leaving it could meet
+        // the ribbon facing its own way almost at once. Like pbrt, start it two widths clear.
+        hitrec.error = 2.0 * self.width(u);
+        Some(hitrec)
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        // The curve's own extremes rather than its control points', which can be a long way off.
+        let mut low = self.points[0];
+        let mut high = self.points[0];
+        let mut include = |p: Point3| {
+            low = Point3::new(low.x.min(p.x), low.y.min(p.y), low.z.min(p.z));
+            high = Point3::new(high.x.max(p.x), high.y.max(p.y), high.z.max(p.z));
+        };
+        include(self.points[3]);
+        for axis in 0..3 {
+            let c = self.points.map(|p| p.as_array()[axis]);
+            for u in derivative_roots(c) {
+                include(evaluate(self.points, u).0);
+            }
+        }
+
+        let half = 0.5 * self.widths.0.max(self.widths.1);
+        Some(AABB::new(low + -half, high + half))
+    }
+}
+
+/// The point on the curve at u and the derivative there, by de Casteljau's algorithm.
+fn evaluate(cp: [Vec3; 4], u: Float) -> (Point3, Vec3) {
+    let a = [lerp_vec(cp[0], cp[1], u), lerp_vec(cp[1], cp[2], u), lerp_vec(cp[2], cp[3], u)];
+    let b = [lerp_vec(a[0], a[1], u), lerp_vec(a[1], a[2], u)];
+    let along = if (b[1] - b[0]).near_zero() {
+        // The derivative vanishes at an end whose control points coincide.
+        cp[3] - cp[0]
+    } else {
+        3.0 * (b[1] - b[0])
+    };
+    (lerp_vec(b[0], b[1], u), along)
+}
+
+/// The two halves of the curve, each as a cubic of its own.
+fn split(cp: [Vec3; 4]) -> ([Vec3; 4], [Vec3; 4]) {
+    let a = [0.5*(cp[0] + cp[1]), 0.5*(cp[1] + cp[2]), 0.5*(cp[2] + cp[3])];
+    let b = [0.5*(a[0] + a[1]), 0.5*(a[1] + a[2])];
+    let middle = 0.5*(b[0] + b[1]);
+    ([cp[0], a[0], b[0], middle], [middle, b[1], a[2], cp[3]])
+}
+
+/// Where one coordinate of the curve turns around, which are the roots of its quadratic derivative
+/// between 0 and 1.
+fn derivative_roots(c: [Float; 4]) -> Vec<Float> {
+    let a = -c[0] + 3.0*c[1] - 3.0*c[2] + c[3];
+    let b = 2.0*(c[0] - 2.0*c[1] + c[2]);
+    let k = c[1] - c[0];
+    let roots = if a.abs() < 1e-12 {
+        if b.abs() < 1e-12 { vec![] } else { vec![-k / b] }
+    } else {
+        let discriminant = b*b - 4.0*a*k;
+        if discriminant < 0.0 {
+            vec![]
+        } else {
+            let sqrtd = discriminant.sqrt();
+            vec![(-b - sqrtd) / (2.0*a), (-b + sqrtd) / (2.0*a)]
+        }
+    };
+    roots.into_iter().filter(|u| *u > 0.0 && *u < 1.0).collect()
+}
+
+fn bounds(cp: &[Vec3; 4]) -> (Vec3, Vec3) {
+    cp.iter().fold((cp[0], cp[0]), |(low, high), p| (
+        Vec3::new(low.x.min(p.x), low.y.min(p.y), low.z.min(p.z)),
+        Vec3::new(high.x.max(p.x), high.y.max(p.y), high.z.max(p.z)),
+    ))
+}
+
+fn lerp(a: Float, b: Float, t: Float) -> Float {
+    a + (b - a)*t
+}
+
+fn lerp_vec(a: Vec3, b: Vec3, t: Float) -> Vec3 {
+    a + t*(b - a)
+}
+
+/// Turns evenly from one unit vector to another. Ones nearly the same, or opposite, which has no
+/// one way round, are blended straight instead.
+fn slerp(a: Vec3, b: Vec3, t: Float) -> Vec3 {
+    let cos = dot(a, b).clamp(-1.0, 1.0);
+    if cos.abs() > 0.9995 {
+        return unit_vector(lerp_vec(a, b, t));
+    }
+    let theta = cos.acos();
+    ((1.0 - t)*theta).sin() / theta.sin() * a + (t*theta).sin() / theta.sin() * b
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::curve::{Curve, CurveKind};
+    use crate::float::Float;
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+    use crate::vec3::{Color, Point3, Vec3};
+
+    #[test]
+    fn strands() {
+        let material = || Lambertian::from_color(Color::default());
+        let at = |x, y, z| Point3::new(x, y, z);
+        // An arch in the xy plane from (-1,0) to (1,0), 0.2 wide at the start and 0.1 at the end.
+        let arch = [at(-1.0, 0.0, 0.0), at(-1.0, 2.0, 0.0), at(1.0, 2.0, 0.0), at(1.0, 0.0, 0.0)];
+        let curve = Curve::new(arch, 0.2, 0.1, CurveKind::Cylinder, material());
+
+        // The top of the arch is at y = 1.5, which the box should hug rather than reaching y = 2.
+        let aabb = curve.bounding_box(0.0, 1.0).unwrap();
+        assert!((aabb.max.y - (1.5 + 0.1)).abs() < 1e-9 && (aabb.min.x + 1.1).abs() < 1e-9);
+
+        // Straight at the top of the arch, which is the middle of the curve.
+        let ray = Ray::new(at(0.0, 1.5, 5.0), Vec3::new(0.0, 0.0, -2.0), 0.0);
+        let hitrec = curve.hit(ray, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 2.5).abs() < 1e-6);
+        assert!((hitrec.u - 0.5).abs() < 1e-3 && (hitrec.v - 0.5).abs() < 1e-3);
+        assert!((hitrec.tangent - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-3);
+        assert!((hitrec.normal - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-3);
+
+        // Just inside and just outside the width at the top, which is 0.15 there.
+        let ray = Ray::new(at(0.0, 1.57, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hitrec = curve.hit(ray, 0.001, Float::INFINITY).unwrap();
+        assert!(hitrec.normal.y > 0.5);
+        let ray = Ray::new(at(0.0, 1.58, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(curve.hit(ray, 0.001, Float::INFINITY).is_none());
+
+        // A ribbon lying in the xy plane is invisible edge on.
+        let flat = Curve::new(arch, 0.2, 0.2,
+            CurveKind::Ribbon(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, 1.0)), material());
+        let face_on = Ray::new(at(0.0, 1.5, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(flat.hit(face_on, 0.001, Float::INFINITY).is_some());
+        let edge_on = Ray::new(at(0.0, 5.0, 0.05), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        assert!(flat.hit(edge_on, 0.001, Float::INFINITY).is_none());
+    }
+
+    #[test]
+    fn twisted_ribbon() {
+        // Straight up the y axis, 0.4 wide, facing the rays at the bottom and turned edge on to
+        // them at the top.
+        let points = [0.0, 1.0, 2.0, 3.0].map(|y| Point3::new(0.0, y, 0.0));
+        let kind = CurveKind::Ribbon(Vec3::new(0.0, 0.0, 1.0), Vec3::new(1.0, 0.0, 0.0));
+        let ribbon = Curve::new(points, 0.4, 0.4, kind, Lambertian::from_color(Color::default()));
+        let ray = |x: Float, y: Float| Ray::new(Point3::new(x, y, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+
+        // Nearly its whole width shows near the bottom.
+        let hitrec = ribbon.hit(ray(0.18, 0.15), 0.001, Float::INFINITY).unwrap();
+        assert!(hitrec.normal.z > 0.99);
+        // Near the top only a sliver does.
+        assert!(ribbon.hit(ray(0.0, 2.9), 0.001, Float::INFINITY).is_some());
+        assert!(ribbon.hit(ray(0.03, 2.9), 0.001, Float::INFINITY).is_none());
+    }
+}
@@ -0,0 +1,228 @@
+use crate::aabb::{AABB, surrounding_box};
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitRecord};
+use crate::material::Material;
+use crate::onb::Onb;
+use crate::quad::disk_extent;
+use crate::ray::Ray;
+use crate::vec3::{Point3, unit_vector, Vec3};
+use crate::float::consts::PI;
+use std::sync::Arc;
+
+/**
+ * A circular cylinder running from the center of its base to the center of its top. Without caps
+ * it's an open tube that can be seen into. Around the side u is the angle and v the height; on
+ * the caps u is the angle and v the distance from the axis over the radius.
+ */
+pub struct Cylinder {
+    base: Point3,
+    height: Float,
+    radius: Float,
+    capped: bool,
+    frame: Onb,
+    material: Material,
+}
+
+impl Cylinder {
+    pub fn new(base: Point3, top: Point3, radius: Float, capped: bool, material: Material) -> HitArc {
+        let axis = top - base;
+        Arc::new(Cylinder{ base, height: axis.length(), radius, capped, frame: Onb::from_w(axis), material })
+    }
+}
+
+impl Hit for Cylinder {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let (o, d) = (self.frame.project(ray.origin - self.base), self.frame.project(ray.direction));
+        let mut nearest = Nearest::new(t_max);
+
+        // x^2 + y^2 = r^2 along the side.
+        let a = d.x*d.x + d.y*d.y;
+        let half_b = o.x*d.x + o.y*d.y;
+        let c = o.x*o.x + o.y*o.y - self.radius*self.radius;
+        for t in quadratic_roots(a, half_b, c).iter().flatten() {
+            let p = o + *t*d;
+            if *t >= t_min && (0.0..=self.height).contains(&p.z) {
+                nearest.offer(*t, Vec3::new(p.x, p.y, 0.0) / self.radius, angle(p), p.z / self.height);
+            }
+        }
+
+        if self.capped {
+            for &(z, normal) in &[(0.0, -1.0), (self.height, 1.0)] {
+                cap(o, d, z, normal, self.radius, t_min, &mut nearest);
+            }
+        }
+
+        nearest.record(ray, &self.frame, &self.material)
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        let extent = disk_extent(self.frame.w, self.radius);
+        let top = self.base + self.height*self.frame.w;
+        Some(surrounding_box(
+            AABB::new(self.base - extent, self.base + extent),
+            AABB::new(top - extent, top + extent),
+        ))
+    }
+}
+
+/**
+ * A right circular cone standing on its base with its tip at `apex`. The UVs follow Cylinder, with
+ * v running from the base up to the tip.
+ */
+pub struct Cone {
+    base: Point3,
+    height: Float,
+    radius: Float,
+    capped: bool,
+    frame: Onb,
+    material: Material,
+}
+
+impl Cone {
+    pub fn new(base: Point3, apex: Point3, radius: Float, capped: bool, material: Material) -> HitArc {
+        let axis = apex - base;
+        Arc::new(Cone{ base, height: axis.length(), radius, capped, frame: Onb::from_w(axis), material })
+    }
+}
+
+impl Hit for Cone {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let (o, d) = (self.frame.project(ray.origin - self.base), self.frame.project(ray.direction));
+        let mut nearest = Nearest::new(t_max);
+
+        // x^2 + y^2 = k^2 (h - z)^2, where k is how fast the radius shrinks with height. This is a
+        // double cone, so the roots are kept to the half between the base and the tip.
+        let k2 = (self.radius / self.height).powi(2);
+        let s = self.height - o.z;
+        let a = d.x*d.x + d.y*d.y - k2*d.z*d.z;
+        let half_b = o.x*d.x + o.y*d.y + k2*s*d.z;
+        let c = o.x*o.x + o.y*o.y - k2*s*s;
+        for t in quadratic_roots(a, half_b, c).iter().flatten() {
+            let p = o + *t*d;
+            if *t >= t_min && (0.0..=self.height).contains(&p.z) {
+                let normal = unit_vector(Vec3::new(p.x, p.y, k2*(self.height - p.z)));
+                nearest.offer(*t, normal, angle(p), p.z / self.height);
+            }
+        }
+
+        if self.capped {
+            cap(o, d, 0.0, -1.0, self.radius, t_min, &mut nearest);
+        }
+
+        nearest.record(ray, &self.frame, &self.material)
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        let extent = disk_extent(self.frame.w, self.radius);
+        let apex = self.base + self.height*self.frame.w;
+        Some(surrounding_box(AABB::new(self.base - extent, self.base + extent), AABB::new(apex, apex)))
+    }
+}
+
+/// The closest surface point found so far, kept in the shape's own frame until the end.
+struct Nearest {
+    t: Float,
+    found: bool,
+    normal: Vec3,
+    u: Float,
+    v: Float,
+}
+
+impl Nearest {
+    fn new(t_max: Float) -> Nearest {
+        Nearest{ t: t_max, found: false, normal: Vec3::default(), u: 0.0, v: 0.0 }
+    }
+
+    fn offer(&mut self, t: Float, normal: Vec3, u: Float, v: Float) {
+        if t <= self.t {
+            *self = Nearest{ t, found: true, normal, u, v };
+        }
+    }
+
+    fn record<'a>(&self, ray: Ray, frame: &Onb, material: &'a Material) -> Option<HitRecord<'a>> {
+        if !self.found {
+            return None;
+        }
+        Some(HitRecord::new(ray, self.t, frame.local(self.normal), self.u, self.v, material))
+    }
+}
+
+/// A flat end at height z with its outward normal pointing up or down the axis.
+fn cap(o: Point3, d: Vec3, z: Float, normal: Float, radius: Float, t_min: Float, nearest: &mut Nearest) {
+    if d.z == 0.0 {
+        return;
+    }
+    let t = (z - o.z) / d.z;
+    let p = o + t*d;
+    let r = (p.x*p.x + p.y*p.y).sqrt();
+    if t >= t_min && r <= radius {
+        nearest.offer(t, Vec3::new(0.0, 0.0, normal), angle(p), r / radius);
+    }
+}
+
+/// Around the axis, as a fraction of a full turn.
+fn angle(p: Point3) -> Float {
+    (p.y.atan2(p.x) + PI) / (2.0*PI)
+}
+
+/// Both real roots of a t^2 + 2 half_b t + c, nearest first. A zero `a` leaves the linear case.
+fn quadratic_roots(a: Float, half_b: Float, c: Float) -> [Option<Float>; 2] {
+    if a.abs() < 1e-12 {
+        return if half_b == 0.0 { [None, None] } else { [Some(-c / (2.0*half_b)), None] };
+    }
+
+    let discriminant = half_b*half_b - a*c;
+    if discriminant < 0.0 {
+        return [None, None];
+    }
+
+    let sqrtd = discriminant.sqrt();
+    let (t0, t1) = ((-half_b - sqrtd) / a, (-half_b + sqrtd) / a);
+    if t0 <= t1 { [Some(t0), Some(t1)] } else { [Some(t1), Some(t0)] }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::cylinder::{Cone, Cylinder};
+    use crate::float::Float;
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+    use crate::vec3::{Color, Point3, Vec3};
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1e-9
+    }
+
+    #[test]
+    fn caps() {
+        let material = || Lambertian::from_color(Color::default());
+        let (base, top) = (Point3::new(0.0, 0.0, 0.0), Point3::new(0.0, 2.0, 0.0));
+        let capped = Cylinder::new(base, top, 1.0, true, material());
+        let open = Cylinder::new(base, top, 1.0, false, material());
+
+        let down = Ray::new(Point3::new(0.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let hitrec = capped.hit(down, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 3.0).abs() < 1e-9 && close(hitrec.normal, Vec3::new(0.0, 1.0, 0.0)));
+        assert!(open.hit(down, 0.001, Float::INFINITY).is_none());
+
+        let side = Ray::new(Point3::new(5.0, 1.0, 0.0), Vec3::new(-1.0, 0.0, 0.0), 0.0);
+        let hitrec = open.hit(side, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 4.0).abs() < 1e-9 && (hitrec.v - 0.5).abs() < 1e-9);
+        assert!(close(hitrec.normal, Vec3::new(1.0, 0.0, 0.0)));
+
+        // Inside the open tube, the far wall is hit from the back.
+        let hitrec = open.hit(side, 4.5, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 6.0).abs() < 1e-9 && !hitrec.front_face);
+
+        let cone = Cone::new(base, Point3::new(0.0, 1.0, 0.0), 1.0, true, material());
+        let side = Ray::new(Point3::new(5.0, 0.5, 0.0), Vec3::new(-1.0, 0.0, 0.0), 0.0);
+        let hitrec = cone.hit(side, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 4.5).abs() < 1e-9);
+        assert!(close(hitrec.normal, Vec3::new(1.0, 1.0, 0.0) / Float::sqrt(2.0)));
+
+        let up = Ray::new(Point3::new(0.2, -5.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.0);
+        let hitrec = cone.hit(up, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 5.0).abs() < 1e-9 && close(hitrec.normal, Vec3::new(0.0, -1.0, 0.0)));
+    }
+}
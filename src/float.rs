@@ -0,0 +1,15 @@
+/**
+ * The float type all the geometry and color math is done in. It's f64 unless the crate is built
+ * with the `f32` feature, which halves the size of vectors, boxes and meshes and so the memory
+ * bandwidth a render needs, at the cost of precision. Rays leaving a surface are started just off
+ * it with `ray::offset_origin`, which keeps that precision loss from turning into shadow acne.
+ */
+#[cfg(not(feature = "f32"))]
+pub type Float = f64;
+#[cfg(feature = "f32")]
+pub type Float = f32;
+
+#[cfg(not(feature = "f32"))]
+pub use std::f64::consts;
+#[cfg(feature = "f32")]
+pub use std::f32::consts;
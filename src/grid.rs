@@ -0,0 +1,234 @@
+use crate::aabb::{AABB, surrounding_box};
+use crate::accelerator::{self, Indexed, Traverse};
+use crate::bvh::{self, TraversalCounts};
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitList, HitRecord};
+use crate::ray::Ray;
+
+/// How many cells the longest axis is cut into, per cube root of the number of objects. Three
+/// makes about one object to a cell when they're spread evenly through a cube.
+const DENSITY: Float = 3.0;
+
+/// No axis is cut into more cells than this, however many objects there are.
+const MAX_RESOLUTION: usize = 128;
+
+/**
+ * A uniform grid: the box around the objects cut into equal cells, each listing the objects whose
+ * boxes overlap it. A ray steps through the cells along its path in order with a 3D-DDA (Amanatides
+ * and Woo), testing the objects listed in each, and stops at the first cell that starts beyond the
+ * nearest hit found so far. It's quick to build and needs no stack to walk, and for lots of small
+ * objects spread evenly, like the spheres of `random_scene`, it can beat a tree. Objects of very
+ * different sizes, or bunched together in a few places, leave most cells empty and a few crowded.
+ *
+ * An object overlapping several cells is listed in each of them, so a ray may test it more than
+ * once. Objects without boxes are kept to one side, as in `BVHNode`.
+ */
+pub struct Grid {
+    /// None when there are no objects with boxes to put in cells.
+    bounds: Option<AABB>,
+    resolution: [usize; 3],
+    cell_size: [Float; 3],
+    /// Where each cell's objects start in `indices`, with one more on the end where the last
+    /// cell's objects end.
+    starts: Vec<u32>,
+    indices: Vec<u32>,
+    objects: Vec<HitArc>,
+    unbounded: HitList,
+}
+
+impl Grid {
+    pub fn from_hitlist(list: &HitList, time0: Float, time1: Float) -> Grid {
+        let (objects, boxes, unbounded) = accelerator::split(list, time0, time1);
+        let bounds = boxes.iter().copied().reduce(surrounding_box);
+        let (resolution, cell_size) = match bounds {
+            Some(bounds) => {
+                let extent = (bounds.max - bounds.min).as_array();
+                let longest = extent.iter().copied().fold(0.0, Float::max);
+                let per_unit = DENSITY * (boxes.len() as Float).cbrt() / longest;
+                let resolution = extent.map(|e| ((e * per_unit).round() as usize).clamp(1, MAX_RESOLUTION));
+                (resolution, [0, 1, 2].map(|axis| extent[axis] / resolution[axis] as Float))
+            }
+            None => ([0; 3], [0.0; 3]),
+        };
+        let (starts, indices) = (Vec::new(), Vec::new());
+        let mut grid = Grid{ bounds, resolution, cell_size, starts, indices, objects, unbounded };
+
+        // Count what goes in each cell, then fill them in, each cell's objects following the last's.
+        let cells = resolution.iter().product::<usize>();
+        let mut starts = vec![0u32; cells + 1];
+        for aabb in &boxes {
+            grid.for_cells(*aabb, |cell| starts[cell + 1] += 1);
+        }
+        for cell in 0..cells {
+            starts[cell + 1] += starts[cell];
+        }
+        let mut filled = starts.clone();
+        let mut indices = vec![0u32; starts[cells] as usize];
+        for (i, aabb) in boxes.iter().enumerate() {
+            grid.for_cells(*aabb, |cell| {
+                indices[filled[cell] as usize] = i as u32;
+                filled[cell] += 1;
+            });
+        }
+        grid.starts = starts;
+        grid.indices = indices;
+        grid
+    }
+
+    /// The cell holding a coordinate along an axis, with points on or past the edges in the cells
+    /// at the edges.
+    fn cell_along(&self, axis: usize, x: Float) -> usize {
+        let min = self.bounds.unwrap().min.as_array()[axis];
+        let cell = ((x - min) / self.cell_size[axis]).max(0.0) as usize;
+        cell.min(self.resolution[axis] - 1)
+    }
+
+    fn index(&self, cell: [usize; 3]) -> usize {
+        (cell[2] * self.resolution[1] + cell[1]) * self.resolution[0] + cell[0]
+    }
+
+    /// Calls `f` with the index of every cell the box overlaps.
+    fn for_cells<F: FnMut(usize)>(&self, aabb: AABB, mut f: F) {
+        let (min, max) = (aabb.min.as_array(), aabb.max.as_array());
+        let low = [0, 1, 2].map(|axis| self.cell_along(axis, min[axis]));
+        let high = [0, 1, 2].map(|axis| self.cell_along(axis, max[axis]));
+        for z in low[2]..=high[2] {
+            for y in low[1]..=high[1] {
+                for x in low[0]..=high[0] {
+                    f(self.index([x, y, z]));
+                }
+            }
+        }
+    }
+}
+
+impl Traverse for Grid {
+    type Leaf<'a> = Indexed<'a>;
+
+    fn unbounded(&self) -> &HitList {
+        &self.unbounded
+    }
+
+    /// Every non-empty cell the ray passes through, in order along the ray.
+    fn traverse<'a, F>(&'a self, ray: Ray, t_min: Float, mut t_max: Float, mut visit: F)
+        where F: FnMut(Self::Leaf<'a>, Float) -> Float {
+
+        let bounds = match self.bounds {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        let t_enter = match bounds.clip(ray, t_min, t_max) {
+            Some((t_enter, _)) => t_enter,
+            None => return,
+        };
+        let origin = ray.origin.as_array();
+        let direction = ray.direction.as_array();
+        let entry = ray.at(t_enter).as_array();
+        let min = bounds.min.as_array();
+
+        // For each axis, the cell the ray is in, which way it steps, where along the ray it
+        // crosses into the next cell, and how far it goes between crossings.
+        let mut cell = [0; 3];
+        let mut step = [0isize; 3];
+        let mut next = [Float::INFINITY; 3];
+        let mut delta = [Float::INFINITY; 3];
+        for axis in 0..3 {
+            cell[axis] = self.cell_along(axis, entry[axis]);
+            let size = self.cell_size[axis];
+            let wall = |cell: usize| (min[axis] + cell as Float * size - origin[axis]) / direction[axis];
+            if direction[axis] > 0.0 {
+                step[axis] = 1;
+                next[axis] = wall(cell[axis] + 1);
+                delta[axis] = size / direction[axis];
+            } else if direction[axis] < 0.0 {
+                step[axis] = -1;
+                next[axis] = wall(cell[axis]);
+                delta[axis] = -size / direction[axis];
+            }
+        }
+
+        let mut counts = TraversalCounts::default();
+        loop {
+            let index = self.index(cell);
+            counts.nodes += 1;
+            let (start, end) = (self.starts[index] as usize, self.starts[index + 1] as usize);
+            if start < end {
+                counts.objects += (end - start) as u64;
+                t_max = visit(Indexed{ indices: &self.indices[start..end], objects: &self.objects }, t_max);
+            }
+
+            let axis = if next[0] < next[1] {
+                if next[0] < next[2] { 0 } else { 2 }
+            } else if next[1] < next[2] { 1 } else { 2 };
+            if next[axis] >= t_max {
+                break;
+            }
+            let stepped = cell[axis] as isize + step[axis];
+            if stepped < 0 || stepped >= self.resolution[axis] as isize {
+                break;
+            }
+            cell[axis] = stepped as usize;
+            next[axis] += delta[axis];
+        }
+        bvh::add_counts(counts);
+    }
+}
+
+impl Hit for Grid {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        accelerator::hit(self, ray, t_min, t_max)
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        accelerator::occluded(self, ray, t_min, t_max)
+    }
+
+    /// Objects in several cells along the ray are only asked for their crossings once.
+    fn hit_all<'a>(&'a self, ray: Ray, t_min: Float, t_max: Float, hits: &mut Vec<HitRecord<'a>>) {
+        accelerator::hit_all(self, ray, t_min, t_max, hits);
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        if self.unbounded.objects.is_empty() { self.bounds } else { None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::float::Float;
+    use crate::grid::Grid;
+    use crate::hit::{Hit, HitList};
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+    use crate::vec3::{Color, Point3, Vec3};
+
+    #[test]
+    fn cells() {
+        // A flat layer of small spheres, with a big sphere around all of them that every cell lists.
+        let material = || Lambertian::from_color(Color::default());
+        let mut list = HitList::default();
+        for i in 0..8 {
+            for j in 0..8 {
+                list.add(Sphere::new(Point3::new(i as Float + 0.5, 0.0, j as Float + 0.5), 0.25, material()));
+            }
+        }
+        list.add(Sphere::new(Point3::new(4.0, 0.0, 4.0), 6.0, material()));
+        let grid = Grid::from_hitlist(&list, 0.0, 1.0);
+        let cells = grid.resolution.iter().product::<usize>();
+        assert!(cells > 64);
+        let listed = |cell: usize| grid.indices[grid.starts[cell] as usize..grid.starts[cell + 1] as usize].to_vec();
+        assert!((0..cells).all(|cell| listed(cell).contains(&64)));
+
+        // From inside the big sphere, down onto the layer and slanting across it.
+        for i in 0..8 {
+            let x = i as Float + 0.5;
+            for direction in [Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.3, -0.1, 0.2), Vec3::new(-1.0, 0.0, 0.0)] {
+                let ray = Ray::new(Point3::new(x, 2.0, x), direction, 0.0);
+                let expected = list.hit(ray, 0.0, Float::INFINITY).map(|hitrec| hitrec.t);
+                assert_eq!(grid.hit(ray, 0.0, Float::INFINITY).map(|hitrec| hitrec.t), expected);
+            }
+        }
+    }
+}
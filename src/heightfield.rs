@@ -0,0 +1,242 @@
+use crate::aabb::AABB;
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitRecord};
+use crate::material::Material;
+use crate::perlin::PerlinNoise;
+use crate::ray::Ray;
+use crate::triangle::{intersect_triangle, shading_normal};
+use crate::vec3::{cross, Point3, unit_vector, Vec3};
+use image::io::Reader as ImageReader;
+use image::ImageResult;
+use std::sync::Arc;
+
+/**
+ * Terrain given as heights on a regular grid over the xz plane. Each grid cell is split into two
+ * triangles, but rays walk the grid cell by cell with a 2D DDA rather than testing triangles one
+ * at a time, and cells the ray passes entirely above or below are skipped without testing either.
+ * Normals are interpolated from the slope at the grid points, and the texture is stretched once
+ * across the whole grid.
+ */
+pub struct Heightfield {
+    heights: Vec<Float>,
+    normals: Vec<Vec3>,
+    /// The lowest and highest corner of each cell.
+    ranges: Vec<(Float, Float)>,
+    columns: usize,
+    rows: usize,
+    corner: Point3,
+    cell: (Float, Float),
+    bounds: AABB,
+    material: Material,
+}
+
+impl Heightfield {
+    /**
+     * `heights` holds `columns` samples along x for each of `rows` along z. The grid starts at
+     * `corner` and covers `size.x` by `size.z`, with every height scaled by `size.y` and added to
+     * `corner.y`. There must be at least two samples each way.
+     */
+    pub fn new(heights: Vec<Float>, columns: usize, rows: usize, corner: Point3, size: Vec3, material: Material) -> HitArc {
+        assert!(columns >= 2 && rows >= 2, "a heightfield needs at least a 2x2 grid");
+        assert_eq!(heights.len(), columns * rows, "a heightfield needs one height per grid point");
+
+        let heights: Vec<Float> = heights.iter().map(|h| corner.y + size.y * h).collect();
+        let cell = (size.x / (columns - 1) as Float, size.z / (rows - 1) as Float);
+        let at = |i: usize, j: usize| heights[j*columns + i];
+
+        // Central differences where there are neighbours on both sides, one sided at the edges.
+        let mut normals = Vec::with_capacity(heights.len());
+        for j in 0..rows {
+            for i in 0..columns {
+                let (i0, i1) = (i.saturating_sub(1), (i + 1).min(columns - 1));
+                let (j0, j1) = (j.saturating_sub(1), (j + 1).min(rows - 1));
+                let dx = (at(i1, j) - at(i0, j)) / ((i1 - i0) as Float * cell.0);
+                let dz = (at(i, j1) - at(i, j0)) / ((j1 - j0) as Float * cell.1);
+                normals.push(unit_vector(Vec3::new(-dx, 1.0, -dz)));
+            }
+        }
+
+        let mut ranges = Vec::with_capacity((columns - 1) * (rows - 1));
+        for j in 0..rows - 1 {
+            for i in 0..columns - 1 {
+                let corners = [at(i, j), at(i + 1, j), at(i, j + 1), at(i + 1, j + 1)];
+                let low = corners.iter().cloned().fold(Float::INFINITY, Float::min);
+                let high = corners.iter().cloned().fold(Float::NEG_INFINITY, Float::max);
+                ranges.push((low, high));
+            }
+        }
+
+        let low = ranges.iter().map(|r| r.0).fold(Float::INFINITY, Float::min);
+        let high = ranges.iter().map(|r| r.1).fold(Float::NEG_INFINITY, Float::max);
+        let bounds = AABB::new(
+            Point3::new(corner.x, low - 0.0001, corner.z),
+            Point3::new(corner.x + size.x, high + 0.0001, corner.z + size.z),
+        );
+
+        Arc::new(Heightfield{ heights, normals, ranges, columns, rows, corner, cell, bounds, material })
+    }
+
+    /// One grid point per pixel, with black at the bottom and white `size.y` above it.
+    pub fn from_image(filename: &str, corner: Point3, size: Vec3, material: Material) -> ImageResult<HitArc> {
+        let image = ImageReader::open(filename)?.decode()?.into_luma8();
+        let (columns, rows) = (image.width() as usize, image.height() as usize);
+        let heights = image.pixels().map(|p| p[0] as Float / 255.0).collect();
+        Ok(Heightfield::new(heights, columns, rows, corner, size, material))
+    }
+
+    /**
+     * Rolling hills from Perlin turbulence sampled on a `resolution` square grid. `frequency` is
+     * how many noise features fit across the grid.
+     */
+    pub fn from_noise(
+        noise: &PerlinNoise,
+        resolution: usize,
+        frequency: Float,
+        depth: usize,
+        corner: Point3,
+        size: Vec3,
+        material: Material,
+    ) -> HitArc {
+        let step = frequency / (resolution - 1) as Float;
+        let mut heights = Vec::with_capacity(resolution * resolution);
+        for j in 0..resolution {
+            for i in 0..resolution {
+                heights.push(noise.turb(Point3::new(i as Float * step, 0.5, j as Float * step), depth));
+            }
+        }
+        Heightfield::new(heights, resolution, resolution, corner, size, material)
+    }
+
+    fn point(&self, i: usize, j: usize) -> Point3 {
+        Point3::new(
+            self.corner.x + i as Float * self.cell.0,
+            self.heights[j*self.columns + i],
+            self.corner.z + j as Float * self.cell.1,
+        )
+    }
+
+    /// Tests the two triangles of cell (i, j), giving the nearer hit.
+    fn hit_cell(&self, i: usize, j: usize, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let corners = [(i, j), (i + 1, j), (i + 1, j + 1), (i, j + 1)];
+        let mut nearest = t_max;
+        let mut found = None;
+        for triangle in &[[0, 2, 1], [0, 3, 2]] {
+            let [a, b, c] = triangle.map(|k| corners[k]);
+            let (p0, p1, p2) = (self.point(a.0, a.1), self.point(b.0, b.1), self.point(c.0, c.1));
+            if let Some((t, bary)) = intersect_triangle(ray, p0, p1, p2, t_min, nearest) {
+                nearest = t;
+                found = Some(([a, b, c], [p0, p1, p2], t, bary));
+            }
+        }
+
+        let ([a, b, c], [p0, p1, p2], t, bary) = found?;
+
+        let point = bary[0]*p0 + bary[1]*p1 + bary[2]*p2;
+        let u = (point.x - self.bounds.min.x) / (self.bounds.max.x - self.bounds.min.x);
+        let v = (point.z - self.bounds.min.z) / (self.bounds.max.z - self.bounds.min.z);
+        let mut hitrec = HitRecord::new(ray, t, unit_vector(cross(p1 - p0, p2 - p0)), u, v, &self.material);
+        hitrec.point = point;
+        let normal = |(i, j): (usize, usize)| self.normals[j*self.columns + i];
+        let interpolated = bary[0]*normal(a) + bary[1]*normal(b) + bary[2]*normal(c);
+        hitrec.normal = shading_normal(hitrec.normal, interpolated);
+        Some(hitrec)
+    }
+}
+
+impl Hit for Heightfield {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let (enter, exit) = self.bounds.clip(ray, t_min, t_max)?;
+
+        // Find the cell the ray enters by, then step across cell boundaries in x and z, whichever
+        // comes first along the ray.
+        let start = ray.at(enter);
+        let cell_of = |x: Float, size: Float, count: usize| (((x / size).floor()).max(0.0) as usize).min(count - 2);
+        let mut i = cell_of(start.x - self.corner.x, self.cell.0, self.columns);
+        let mut j = cell_of(start.z - self.corner.z, self.cell.1, self.rows);
+
+        let axis = |origin: Float, direction: Float, corner: Float, size: Float, index: usize| {
+            if direction > 0.0 {
+                (1isize, (corner + (index + 1) as Float * size - origin) / direction, size / direction)
+            } else if direction < 0.0 {
+                (-1isize, (corner + index as Float * size - origin) / direction, -size / direction)
+            } else {
+                (0isize, Float::INFINITY, Float::INFINITY)
+            }
+        };
+        let (step_i, mut next_i, delta_i) = axis(ray.origin.x, ray.direction.x, self.corner.x, self.cell.0, i);
+        let (step_j, mut next_j, delta_j) = axis(ray.origin.z, ray.direction.z, self.corner.z, self.cell.1, j);
+
+        let mut t_cell = enter;
+        loop {
+            let t_leave = next_i.min(next_j).min(exit);
+
+            // Skip cells the ray passes wholly above or below.
+            let (low, high) = self.ranges[j*(self.columns - 1) + i];
+            let (y0, y1) = (ray.at(t_cell).y, ray.at(t_leave).y);
+            if y0.min(y1) <= high && y0.max(y1) >= low {
+                if let Some(hitrec) = self.hit_cell(i, j, ray, t_min, t_max) {
+                    return Some(hitrec);
+                }
+            }
+
+            if t_leave >= exit {
+                return None;
+            }
+            t_cell = t_leave;
+            if next_i < next_j {
+                i = match i.checked_add_signed(step_i) {
+                    Some(i) if i < self.columns - 1 => i,
+                    _ => return None,
+                };
+                next_i += delta_i;
+            } else {
+                j = match j.checked_add_signed(step_j) {
+                    Some(j) if j < self.rows - 1 => j,
+                    _ => return None,
+                };
+                next_j += delta_j;
+            }
+        }
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        Some(self.bounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::float::Float;
+    use crate::heightfield::Heightfield;
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+    use crate::vec3::{Color, Point3, Vec3};
+
+    #[test]
+    fn ramp() {
+        // A ramp rising one unit along x over a 4x3 grid of unit cells.
+        let columns = 5;
+        let heights: Vec<Float> = (0..3*columns).map(|k| (k % columns) as Float / 4.0).collect();
+        let ramp = Heightfield::new(heights, columns, 3, Point3::new(0.0, 0.0, 0.0), Vec3::new(4.0, 1.0, 2.0),
+            Lambertian::from_color(Color::default()));
+
+        let down = Ray::new(Point3::new(3.0, 5.0, 1.5), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let hitrec = ramp.hit(down, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 4.25).abs() < 1e-9);
+        assert!((hitrec.u - 0.75).abs() < 1e-9 && (hitrec.v - 0.75).abs() < 1e-9);
+        let slope = Vec3::new(-0.25, 1.0, 0.0);
+        assert!((hitrec.normal - slope / slope.length()).length() < 1e-9);
+
+        // Skimming in low from the side crosses several cells before it meets the rising ground.
+        let across = Ray::new(Point3::new(-1.0, 0.5, 0.7), Vec3::new(1.0, 0.0, 0.1), 0.0);
+        let hitrec = ramp.hit(across, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.point.x - 2.0).abs() < 1e-9);
+
+        // Passing over the top, and starting underneath looking away.
+        let over = Ray::new(Point3::new(-1.0, 1.5, 0.7), Vec3::new(1.0, 0.0, 0.1), 0.0);
+        assert!(ramp.hit(over, 0.001, Float::INFINITY).is_none());
+        let away = Ray::new(Point3::new(2.0, -1.0, 1.0), Vec3::new(0.3, -1.0, 0.2), 0.0);
+        assert!(ramp.hit(away, 0.001, Float::INFINITY).is_none());
+    }
+}
@@ -1,50 +1,144 @@
-use crate::aabb::{AABB, aabb_compare, surrounding_box};
-use crate::ray::Ray;
-use crate::vec3::{dot, Point3, Vec3};
+use crate::aabb::{AABB, surrounding_box};
+use crate::float::Float;
+use crate::ray::{offset_origin, Ray};
+use crate::vec3::{Color, dot, Point3, Vec3};
 use crate::material::Material;
-use std::cmp::Ordering;
 use std::sync::Arc;
 
-#[derive(Default)]
-pub struct HitRecord {
+/**
+ * Where a ray meets a surface. The material is borrowed from the object that was hit rather than
+ * shared, so finding a hit never touches a reference count, and a record is small enough to copy.
+ */
+#[derive(Copy, Clone)]
+pub struct HitRecord<'a> {
     pub point: Point3,
     pub normal: Vec3,
-    pub t: f64,
+    pub t: Float,
     pub front_face: bool,
-    pub material: Option<Material>,
-    pub u: f64,
-    pub v: f64,
+    pub material: &'a Material,
+    pub u: Float,
+    pub v: Float,
+    pub color: Option<Color>,
+    /// Which way a thin fiber runs at the hit, for materials that shade along it. Zero for
+    /// surfaces that don't have one.
+    pub tangent: Vec3,
+    /// How far the point may be from the surface, for shapes found only approximately, like by
+    /// marching or flattening a curve. Rays leaving the hit start at least this far off it.
+    pub error: Float,
 }
 
-impl HitRecord {
+impl<'a> HitRecord<'a> {
+    /// A hit at `t` along the ray, with the normal turned to face the ray and no vertex color or
+    /// tangent. The point is taken as exact.
+    pub fn new(ray: Ray, t: Float, outward_normal: Vec3, u: Float, v: Float, material: &'a Material) -> HitRecord<'a> {
+        let mut hitrec = HitRecord{
+            point: ray.at(t),
+            normal: outward_normal,
+            t,
+            front_face: true,
+            material,
+            u,
+            v,
+            color: None,
+            tangent: Vec3::default(),
+            error: 0.0,
+        };
+        hitrec.set_face_normal(ray, outward_normal);
+        hitrec
+    }
 
     pub fn set_face_normal(&mut self, ray: Ray, outward_normal: Vec3) {
         self.front_face = dot(ray.direction, outward_normal) < 0.0;
         self.normal = if self.front_face { outward_normal } else { -outward_normal };
     }
 
-    pub fn copy(&mut self, other: &HitRecord) {
-        self.point = other.point;
-        self.normal = other.normal;
-        self.t = other.t;
-        self.front_face = other.front_face;
-        self.u = other.u;
-        self.v = other.v;
-        match other.material {
-            Some(ref material) => {
-                self.material = Some(Arc::clone(material));
-            }
-            None => {
-                self.material = None;
+    /// A ray leaving the surface in `direction`, started far enough off it on that side to be
+    /// traced from t = 0 without finding this hit again.
+    pub fn spawn_ray(&self, direction: Vec3, time: Float) -> Ray {
+        let normal = if dot(direction, self.normal) < 0.0 { -self.normal } else { self.normal };
+        Ray::new(offset_origin(self.point + self.error*normal, normal), direction, time)
+    }
+}
+
+pub trait Hit {
+    /// The nearest hit between t_min and t_max, if there is one.
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>>;
+
+    /// A box around the object over the shutter interval, or None for things like infinite
+    /// planes that don't fit in one.
+    fn bounding_box(&self, time0: Float, time1: Float) -> Option<AABB>;
+
+    /**
+     * Whether anything is hit between t_min and t_max, for shadow and visibility rays that only
+     * need to know that much. Any hit will do, so a search can stop at the first it finds instead
+     * of looking on for a nearer one, and no record is filled in. The default calls `hit`, which
+     * is all shapes whose cost is in finding the crossing need.
+     */
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        self.hit(ray, t_min, t_max).is_some()
+    }
+
+    /**
+     * Appends every surface crossing between t_min and t_max, nearest first. This is what CSG
+     * needs to tell when a ray is inside a solid. The default steps along the ray with `hit`,
+     * which works for anything whose `hit` finds the nearest crossing after t_min, but shapes
+     * that can solve for all of them at once should.
+     */
+    fn hit_all<'a>(&'a self, ray: Ray, t_min: Float, t_max: Float, hits: &mut Vec<HitRecord<'a>>) {
+        let mut t = t_min;
+        for _ in 0..MAX_CROSSINGS {
+            let hitrec = match self.hit(ray, t, t_max) {
+                Some(hitrec) => hitrec,
+                None => break,
+            };
+            t = past(hitrec.t);
+            hits.push(hitrec);
+        }
+    }
+
+    /**
+     * The first two surface crossings between t_min and t_max, which for a closed shape are the
+     * way in and the way out. This is all a medium filling the shape needs, without gathering a
+     * list. The default calls `hit` twice, stepping past the first crossing as `hit_all` does.
+     */
+    fn span(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<(Float, Float)> {
+        let first = self.hit(ray, t_min, t_max)?.t;
+        let second = self.hit(ray, past(first), t_max)?.t;
+        Some((first, second))
+    }
+
+    /**
+     * The nearest hit for each of a packet of rays, each with its own t_max. Lanes whose t_max
+     * is below t_min are left out and come back empty. The default traces the rays one by one;
+     * trees that can walk them together, sharing each node they visit, should.
+     */
+    fn hit_packet(&self, rays: &[Ray; PACKET], t_min: Float, t_max: [Float; PACKET])
+        -> [Option<HitRecord<'_>>; PACKET] {
+
+        let mut hits = [None; PACKET];
+        for (lane, hit) in hits.iter_mut().enumerate() {
+            if t_max[lane] >= t_min {
+                *hit = self.hit(rays[lane], t_min, t_max[lane]);
             }
         }
+        hits
     }
 }
 
-pub trait Hit {
-    fn hit(&self, ray: Ray, t_min: f64, t_max: f64, hitrec: &mut HitRecord) -> bool;
+/// How many rays `Hit::hit_packet` traces together.
+pub const PACKET: usize = 4;
+
+/// A ceiling on how many crossings the default `hit_all` looks for along a single ray.
+const MAX_CROSSINGS: usize = 64;
+
+/// Far enough past a crossing at t that looking on from there doesn't find it again.
+fn past(t: Float) -> Float {
+    t + 1e-7 * t.abs().max(1.0)
+}
 
-    fn bounding_box(&self, time0: f64, time1: f64, aabb: &mut AABB) -> bool;
+/// Sorts crossings gathered from several objects into order along the ray.
+pub fn sort_hits(hits: &mut [HitRecord]) {
+    hits.sort_by(|a, b| a.t.total_cmp(&b.t));
 }
 
 pub type HitArc = Arc<dyn Hit + Sync + Send>;
@@ -61,177 +155,175 @@ impl HitList {
 }
 
 impl Hit for HitList {
-    fn hit(&self, ray: Ray, t_min: f64, t_max: f64, hitrec: &mut HitRecord) -> bool {
-        let mut temp_rec = HitRecord::default();
-        let mut hit_anything = false;
-        let mut closest_so_far = t_max;
-
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let mut closest: Option<HitRecord> = None;
         for object in &self.objects {
-            if object.hit(ray, t_min, closest_so_far, &mut temp_rec) {
-                hit_anything = true;
-                closest_so_far = temp_rec.t;
-                hitrec.copy(&temp_rec);
+            if let Some(hitrec) = object.hit(ray, t_min, closest.map_or(t_max, |c| c.t)) {
+                closest = Some(hitrec);
             }
         }
-
-        return hit_anything;
+        closest
     }
 
-    fn bounding_box(&self, time0: f64, time1: f64, aabb: &mut AABB) -> bool {
-        let mut temp_box = AABB::default();
-        let mut first_box = true;
+    fn hit_packet(&self, rays: &[Ray; PACKET], t_min: Float, mut t_max: [Float; PACKET])
+        -> [Option<HitRecord<'_>>; PACKET] {
 
+        let mut closest = [None; PACKET];
         for object in &self.objects {
-            if object.bounding_box(time0, time1, &mut temp_box) {
-                *aabb = if first_box { temp_box } else { surrounding_box(temp_box, *aabb) };
-                first_box = false;
-            } else {
-                return false;
+            for (lane, hit) in object.hit_packet(rays, t_min, t_max).iter().enumerate() {
+                if let Some(hitrec) = hit {
+                    t_max[lane] = hitrec.t;
+                    closest[lane] = Some(*hitrec);
+                }
             }
         }
-
-        true
+        closest
     }
-}
-
-pub struct Translate {
-    thing: HitArc,
-    offset: Vec3,
-}
 
-impl Translate {
-    pub fn new(thing: HitArc, offset: Vec3) -> HitArc {
-        Arc::new(Translate{ thing, offset })
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        self.objects.iter().any(|object| object.occluded(ray, t_min, t_max))
     }
-}
 
-impl Hit for Translate {
-    fn hit(&self, ray: Ray, t_min: f64, t_max: f64, hitrec: &mut HitRecord) -> bool {
-        let moved = Ray::new(ray.origin - self.offset, ray.direction, ray.time);
-        if self.thing.hit(moved, t_min, t_max, hitrec) {
-            hitrec.point += self.offset;
-            hitrec.set_face_normal(moved, hitrec.normal);
-            true
-        } else {
-            false
+    fn hit_all<'a>(&'a self, ray: Ray, t_min: Float, t_max: Float, hits: &mut Vec<HitRecord<'a>>) {
+        let start = hits.len();
+        for object in &self.objects {
+            object.hit_all(ray, t_min, t_max, hits);
         }
+        sort_hits(&mut hits[start..]);
     }
 
-    fn bounding_box(&self, time0: f64, time1: f64, aabb: &mut AABB) -> bool {
-        if self.thing.bounding_box(time0, time1, aabb) {
-            aabb.min += self.offset;
-            aabb.max += self.offset;
-            true
-        } else {
-            false
-        }
+    fn bounding_box(&self, time0: Float, time1: Float) -> Option<AABB> {
+        let mut boxes = self.objects.iter().map(|object| object.bounding_box(time0, time1));
+        let first = boxes.next()??;
+        boxes.try_fold(first, |aabb, next| Some(surrounding_box(aabb, next?)))
     }
 }
 
-pub struct RotateY {
-    thing: HitArc,
-    sin_theta: f64,
-    cos_theta: f64,
-    aabb: Option<AABB>
-}
-
-impl RotateY {
-    pub fn new(thing: HitArc, theta: f64) -> HitArc {
-        let cos_theta = theta.cos();
-        let sin_theta = theta.sin();
-        let mut bbox = AABB::default();
-        let aabb;
-        if thing.bounding_box(0.0, 1.0, &mut bbox) {
-            let mut min = [f64::INFINITY, f64::INFINITY, f64::INFINITY];
-            let mut max = [f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY];
-
-            for i in 0..2 {
-                let i = i as f64;
-                for j in 0..2 {
-                    let j = j as f64;
-                    for k in 0..2 {
-                        let k = k as f64;
-                        let x = i*bbox.max.x + (1.0-i)*bbox.min.x;
-                        let y = j*bbox.max.y + (1.0-j)*bbox.min.y;
-                        let z = k*bbox.max.z + (1.0-k)*bbox.min.z;
-
-                        let newx =  cos_theta*x + sin_theta*z;
-                        let newz = -sin_theta*x + cos_theta*z;
-
-                        let tester = [newx, y, newz];
-
-                        for c in 0..3 {
-                            min[c] = min[c].min(tester[c]);
-                            max[c] = max[c].max(tester[c]);
-                        }
-                    }
-                }
+#[cfg(test)]
+mod tests {
+
+    use crate::aarect::{XYRect, XZRect, YZRect};
+    use crate::animatedtransform::{AnimatedTransform, Keyframe};
+    use crate::box3d::Box3D;
+    use crate::bvh::BVHNode;
+    use crate::float::Float;
+    use crate::hit::{Hit, HitArc, HitList};
+    use crate::instance::Instance;
+    use crate::material::Lambertian;
+    use crate::matrix::Matrix4;
+    use crate::movingsphere::MovingSphere;
+    use crate::quad::{Disk, Plane, Quad};
+    use crate::quaternion::Quaternion;
+    use crate::rand::{randrange, rfloat};
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+    use crate::transform::Transform;
+    use crate::triangle::Triangle;
+    use crate::vec3::{Color, Point3, Vec3};
+    use std::sync::Arc;
+
+    #[test]
+    fn occluded_agrees_with_hit() {
+        let material = || Lambertian::from_color(Color::default());
+        let p = Point3::new;
+        let one = Vec3::new(1.0, 1.0, 1.0);
+        let ball = Sphere::new(p(0.0, 0.0, 0.0), 1.0, material());
+        let shapes: Vec<HitArc> = vec![
+            Sphere::new(p(1.0, 0.0, 0.0), 1.5, material()),
+            MovingSphere::new(p(0.0, 0.0, 0.0), p(2.0, 0.0, 0.0), 0.0, 1.0, 1.0, material()),
+            Box3D::new(p(-1.0, -1.0, -1.0), p(1.0, 2.0, 0.5), material()),
+            Quad::new(p(-1.0, -1.0, 0.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 3.0, 1.0), material()),
+            Disk::new(p(0.0, 0.5, 0.0), Vec3::new(1.0, 1.0, 0.0), 1.5, material()),
+            Plane::new(p(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.2), material()),
+            Triangle::new(p(-2.0, 0.0, 0.0), p(2.0, 0.0, 0.0), p(0.0, 2.0, 1.0), material()),
+            XYRect::new(-1.0, 1.0, -1.0, 2.0, 0.5, material()),
+            XZRect::new(-1.0, 1.0, -1.0, 2.0, 0.5, material()),
+            YZRect::new(-1.0, 1.0, -1.0, 2.0, 0.5, material()),
+            Transform::new(Arc::clone(&ball), Matrix4::scale(Vec3::new(2.0, 0.5, 1.0))),
+            Instance::new(Arc::clone(&ball), Matrix4::translate(Vec3::new(0.0, 1.0, 0.0)), None),
+            AnimatedTransform::new(ball, vec![
+                Keyframe::new(0.0, Vec3::default(), Quaternion::identity(), one),
+                Keyframe::new(1.0, Vec3::new(0.0, 2.0, 0.0), Quaternion::identity(), 2.0*one),
+            ]),
+        ];
+
+        let mut list = HitList::default();
+        for shape in &shapes {
+            list.add(Transform::new(Arc::clone(shape), Matrix4::translate(Vec3::randrange(-4.0, 4.0))));
+        }
+        let bvh = BVHNode::from_hitlist(&list, 0.0, 1.0);
+
+        for _ in 0..2000 {
+            let ray = Ray::new(Vec3::randrange(-6.0, 6.0), Vec3::randrange(-1.0, 1.0), rfloat());
+            let t_max = randrange(0.5, 8.0);
+            for shape in shapes.iter().chain([&list.objects[0]]) {
+                let hit = shape.hit(ray, 0.001, t_max).is_some();
+                assert_eq!(shape.occluded(ray, 0.001, t_max), hit);
             }
-            aabb = Some(AABB::new(
-                Point3::from_array(min),
-                Point3::from_array(max),
-            ));
-        } else {
-            aabb = None
+            let hit = list.hit(ray, 0.001, t_max).is_some();
+            assert_eq!(list.occluded(ray, 0.001, t_max), hit);
+            assert_eq!(bvh.occluded(ray, 0.001, t_max), hit);
         }
-        Arc::new(RotateY{ thing, sin_theta, cos_theta, aabb })
     }
-}
-
-impl Hit for RotateY {
-    fn hit(&self, ray: Ray, t_min: f64, t_max: f64, hitrec: &mut HitRecord) -> bool {
-        let mut origin = ray.origin;
-        let mut direction = ray.direction;
-
-        origin.x = self.cos_theta*ray.origin.x - self.sin_theta*ray.origin.z;
-        origin.z = self.sin_theta*ray.origin.x + self.cos_theta*ray.origin.z;
-
-        direction.x = self.cos_theta*ray.direction.x - self.sin_theta*ray.direction.z;
-        direction.z = self.sin_theta*ray.direction.x + self.cos_theta*ray.direction.z;
 
-        let rotated = Ray::new(origin, direction, ray.time);
-
-        if self.thing.hit(rotated, t_min, t_max, hitrec) {
-            let mut point = hitrec.point;
-            let mut normal = hitrec.normal;
-
-            point.x =  self.cos_theta*hitrec.point.x + self.sin_theta*hitrec.point.z;
-            point.z = -self.sin_theta*hitrec.point.x + self.cos_theta*hitrec.point.z;
-
-            normal.x = self.cos_theta*hitrec.normal.x + self.sin_theta*hitrec.normal.z;
-            normal.z = self.sin_theta*hitrec.normal.x + self.cos_theta*hitrec.normal.z;
-
-            hitrec.point = point;
-            hitrec.set_face_normal(rotated, normal);
-
-            true
-        } else {
-            false
+    #[test]
+    fn records_borrow_their_materials() {
+        let material = Lambertian::from_color(Color::default());
+        let p = Point3::new;
+        let ball = Sphere::new(p(0.0, 0.0, 0.0), 1.0, Arc::clone(&material));
+        let shapes: Vec<HitArc> = vec![
+            Sphere::new(p(1.0, 0.0, 0.0), 1.5, Arc::clone(&material)),
+            Box3D::new(p(-1.0, -1.0, -1.0), p(1.0, 2.0, 0.5), Arc::clone(&material)),
+            Quad::new(p(-1.0, -1.0, 0.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 3.0, 1.0), Arc::clone(&material)),
+            Triangle::new(p(-2.0, 0.0, 0.0), p(2.0, 0.0, 0.0), p(0.0, 2.0, 1.0), Arc::clone(&material)),
+            Transform::new(Arc::clone(&ball), Matrix4::scale(Vec3::new(2.0, 0.5, 1.0))),
+            Instance::new(ball, Matrix4::translate(Vec3::new(0.0, 1.0, 0.0)), Some(Arc::clone(&material))),
+        ];
+        let mut list = HitList::default();
+        for shape in &shapes {
+            list.add(Transform::new(Arc::clone(shape), Matrix4::translate(Vec3::randrange(-2.0, 2.0))));
         }
-    }
-
-    fn bounding_box(&self, _time0: f64, _time1: f64, aabb: &mut AABB) -> bool {
-        match self.aabb {
-            Some(bbox) => {
-                *aabb = bbox;
-                true
-            },
-            None => {
-                false
-            },
+        let bvh = BVHNode::from_hitlist(&list, 0.0, 1.0);
+        let owners = Arc::strong_count(&material);
+
+        // Holding on to every record found leaves the count where the objects put it.
+        let mut records = Vec::new();
+        for _ in 0..2000 {
+            let ray = Ray::new(Vec3::randrange(-4.0, 4.0), Vec3::randrange(-1.0, 1.0), rfloat());
+            records.extend(bvh.hit(ray, 0.001, Float::INFINITY));
+            bvh.hit_all(ray, Float::NEG_INFINITY, Float::INFINITY, &mut records);
         }
+        assert!(records.len() > 1000);
+        assert_eq!(Arc::strong_count(&material), owners);
     }
-}
-
-pub fn hit_compare(a: HitArc, b: HitArc, axis: usize) -> Ordering {
-    let mut box_a = AABB::default();
-    let mut box_b = AABB::default();
 
-    // Generating a runtime error seems unfortunate here when we might be able to avoid this using
-    // compile time checks
-    if (! a.bounding_box(0.0, 0.0, &mut box_a)) || (!b.bounding_box(0.0, 0.0, &mut box_b)) {
-        panic!("Attempting to compare an object without a bounding box");
+    #[test]
+    fn span_agrees_with_hit_all() {
+        let material = || Lambertian::from_color(Color::default());
+        let p = Point3::new;
+        let one = Vec3::new(1.0, 1.0, 1.0);
+        let ball = Sphere::new(p(0.0, 0.0, 0.0), 1.0, material());
+        let shapes: Vec<HitArc> = vec![
+            Sphere::new(p(1.0, 0.0, 0.0), 1.5, material()),
+            MovingSphere::new(p(0.0, 0.0, 0.0), p(2.0, 0.0, 0.0), 0.0, 1.0, 1.0, material()),
+            Box3D::new(p(-1.0, -1.0, -1.0), p(1.0, 2.0, 0.5), material()),
+            Transform::new(Arc::clone(&ball), Matrix4::scale(Vec3::new(2.0, 0.5, 1.0))),
+            Instance::new(Arc::clone(&ball), Matrix4::translate(Vec3::new(0.0, 1.0, 0.0)), None),
+            AnimatedTransform::new(ball, vec![
+                Keyframe::new(0.0, Vec3::default(), Quaternion::identity(), one),
+                Keyframe::new(1.0, Vec3::new(0.0, 2.0, 0.0), Quaternion::identity(), 2.0*one),
+            ]),
+        ];
+
+        for _ in 0..2000 {
+            let ray = Ray::new(Vec3::randrange(-4.0, 4.0), Vec3::randrange(-1.0, 1.0), rfloat());
+            let (t_min, t_max) = if rfloat() < 0.5 { (Float::NEG_INFINITY, Float::INFINITY) } else { (0.001, 3.0) };
+            for shape in &shapes {
+                let mut hits = Vec::new();
+                shape.hit_all(ray, t_min, t_max, &mut hits);
+                let expected = if hits.len() >= 2 { Some((hits[0].t, hits[1].t)) } else { None };
+                assert_eq!(shape.span(ray, t_min, t_max), expected);
+            }
+        }
     }
-
-    aabb_compare(box_a, box_b, axis)
 }
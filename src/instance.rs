@@ -0,0 +1,128 @@
+use crate::aabb::AABB;
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitRecord};
+use crate::material::Material;
+use crate::matrix::Matrix4;
+use crate::ray::Ray;
+use crate::vec3::unit_vector;
+use std::sync::Arc;
+
+/**
+ * One placement of a shared prototype. The prototype is only referenced, so a mesh with its own
+ * BVH can be placed thousands of times while its triangles are stored once. Put the instances in a
+ * BVHNode of their own to get a top-level structure over them, so that a ray only visits the
+ * prototypes whose placed bounds it passes through.
+ */
+pub struct Instance {
+    prototype: HitArc,
+    matrix: Matrix4,
+    inverse: Matrix4,
+    material: Option<Material>,
+    bounds: Option<AABB>,
+}
+
+impl Instance {
+    /// Panics if the matrix can't be inverted. A material, if given, replaces whatever the
+    /// prototype would have reported.
+    pub fn new(prototype: HitArc, matrix: Matrix4, material: Option<Material>) -> HitArc {
+        let inverse = matrix.inverse().expect("Instance needs an invertible matrix");
+
+        // Prototypes are static geometry, so the placed box only has to be worked out once rather
+        // than every time a BVH asks for it.
+        let bounds = prototype.bounding_box(0.0, 1.0).map(|local| matrix.transform_box(local));
+
+        Arc::new(Instance{ prototype, matrix, inverse, material, bounds })
+    }
+
+    fn to_local(&self, ray: Ray) -> Ray {
+        Ray::new(
+            self.inverse.transform_point(ray.origin),
+            self.inverse.transform_vector(ray.direction),
+            ray.time,
+        )
+    }
+
+    fn to_world<'a>(&'a self, hitrec: &mut HitRecord<'a>) {
+        hitrec.point = self.matrix.transform_point(hitrec.point);
+        if hitrec.error > 0.0 {
+            hitrec.error *= self.matrix.transform_vector(hitrec.normal).length();
+        }
+        hitrec.normal = unit_vector(self.inverse.transform_normal(hitrec.normal));
+        if !hitrec.tangent.near_zero() {
+            hitrec.tangent = unit_vector(self.matrix.transform_vector(hitrec.tangent));
+        }
+        if let Some(ref material) = self.material {
+            hitrec.material = material;
+        }
+    }
+}
+
+impl Hit for Instance {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let mut hitrec = self.prototype.hit(self.to_local(ray), t_min, t_max)?;
+        self.to_world(&mut hitrec);
+        Some(hitrec)
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        self.prototype.occluded(self.to_local(ray), t_min, t_max)
+    }
+
+    fn hit_all<'a>(&'a self, ray: Ray, t_min: Float, t_max: Float, hits: &mut Vec<HitRecord<'a>>) {
+        let start = hits.len();
+        self.prototype.hit_all(self.to_local(ray), t_min, t_max, hits);
+        for hitrec in &mut hits[start..] {
+            self.to_world(hitrec);
+        }
+    }
+
+    fn span(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<(Float, Float)> {
+        self.prototype.span(self.to_local(ray), t_min, t_max)
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        self.bounds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::float::Float;
+    use crate::instance::Instance;
+    use crate::material::Lambertian;
+    use crate::matrix::Matrix4;
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+    use crate::vec3::{Color, Point3, Vec3};
+    use std::sync::Arc;
+
+    #[test]
+    fn shared_prototype() {
+        let red = Lambertian::from_color(Color::new(1.0, 0.0, 0.0));
+        let blue = Lambertian::from_color(Color::new(0.0, 0.0, 1.0));
+        let prototype = Sphere::new(Point3::default(), 1.0, Arc::clone(&red));
+
+        let plain = Instance::new(Arc::clone(&prototype),
+            Matrix4::translate(Vec3::new(5.0, 0.0, 0.0)), None);
+        let recolored = Instance::new(Arc::clone(&prototype),
+            Matrix4::translate(Vec3::new(-5.0, 0.0, 0.0)) * Matrix4::scale(Vec3::new(2.0, 2.0, 2.0)),
+            Some(Arc::clone(&blue)));
+        assert_eq!(Arc::strong_count(&prototype), 3);
+
+        let ray = Ray::new(Point3::new(5.0, 0.0, 10.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hitrec = plain.hit(ray, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 9.0).abs() < 1e-9);
+        assert!(Arc::ptr_eq(hitrec.material, &red));
+        assert!(recolored.hit(ray, 0.001, Float::INFINITY).is_none());
+
+        let ray = Ray::new(Point3::new(-5.0, 0.0, 10.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hitrec = recolored.hit(ray, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 8.0).abs() < 1e-9);
+        assert!((hitrec.normal - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-9);
+        assert!(Arc::ptr_eq(hitrec.material, &blue));
+
+        let aabb = recolored.bounding_box(0.0, 1.0).unwrap();
+        assert!((aabb.min - Point3::new(-7.0, -2.0, -2.0)).length() < 1e-9);
+    }
+}
@@ -0,0 +1,303 @@
+use crate::aabb::{AABB, surrounding_box};
+use crate::accelerator::{self, Indexed, Traverse};
+use crate::bvh::{self, TraversalCounts};
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitList, HitRecord};
+use crate::ray::Ray;
+
+/// The cost of stepping through one node, relative to testing one object. Far cheaper than a
+/// `BVHNode` node, since it's a single plane rather than a box, and no virtual call.
+const TRAVERSAL_COST: Float = 0.125;
+
+/// Taken off the cost of a split that leaves one side empty, since rays crossing empty space are
+/// nearly free, which makes the tree cut empty space away early.
+const EMPTY_BONUS: Float = 0.5;
+
+/// Leaves this small aren't worth splitting any further.
+const LEAF_SIZE: usize = 1;
+
+/// How many splits in a row may cost more than the leaf they split before the tree gives up on
+/// that branch. A few bad splits can still lead to good ones further down.
+const BAD_REFINES: usize = 3;
+
+/// Deep enough for the deepest tree `max_depth` allows for 2^32 objects.
+const STACK_SIZE: usize = 64;
+
+/**
+ * A kd-tree: space split in two by a plane across one axis, each half split again, and so on, down
+ * to leaves listing the objects that overlap them. Splits are chosen with the surface area
+ * heuristic as in `BVHNode`, but among the planes through the faces of the objects' boxes, and an
+ * object straddling a split goes down both sides. That makes the tree bigger than a BVH, but its
+ * cells never overlap, so a ray goes through them strictly in order and can stop at the first leaf
+ * with a hit. Building follows pbrt's `KdTreeAccel`, sorting the box faces along each axis at each
+ * node, which is O(n log^2 n).
+ *
+ * Objects without boxes are kept to one side, as in `BVHNode`.
+ */
+pub struct KdTree {
+    /// The root comes first, and every inner node's child below its split right after it.
+    nodes: Vec<KdNode>,
+    /// Each leaf's objects, one leaf after another.
+    indices: Vec<u32>,
+    objects: Vec<HitArc>,
+    unbounded: HitList,
+    /// None when there are no objects with boxes to put in the tree.
+    bounds: Option<AABB>,
+}
+
+enum KdNode {
+    Leaf { start: u32, count: u32 },
+    Inner { axis: u8, split: Float, above: u32 },
+}
+
+/// Where an object's box begins or ends along an axis.
+#[derive(Copy, Clone)]
+struct Edge {
+    at: Float,
+    starts: bool,
+    object: u32,
+}
+
+impl KdTree {
+    pub fn from_hitlist(list: &HitList, time0: Float, time1: Float) -> KdTree {
+        let (objects, boxes, unbounded) = accelerator::split(list, time0, time1);
+        let bounds = boxes.iter().copied().reduce(surrounding_box);
+        let mut tree = KdTree{ nodes: Vec::new(), indices: Vec::new(), objects, unbounded, bounds };
+        if let Some(bounds) = bounds {
+            let all = (0..boxes.len() as u32).collect();
+            tree.build(&boxes, all, bounds, max_depth(boxes.len()), 0);
+        }
+        tree
+    }
+
+    /**
+     * Appends the subtree over the given objects within `bounds`, depth first. `depth` is how many
+     * more levels may go below this one, and `bad` how many splits above it in a row cost more
+     * than not splitting.
+     */
+    fn build(&mut self, boxes: &[AABB], inside: Vec<u32>, bounds: AABB, depth: usize, mut bad: usize) {
+        let leaf_cost = inside.len() as Float;
+        let split = if inside.len() > LEAF_SIZE && depth > 0 { best_split(boxes, &inside, bounds) } else { None };
+        let (axis, at, cost) = match split {
+            Some(split) => split,
+            None => return self.leaf(&inside),
+        };
+        if cost > leaf_cost {
+            bad += 1;
+        }
+        if (cost > 4.0 * leaf_cost && inside.len() < 16) || bad == BAD_REFINES {
+            return self.leaf(&inside);
+        }
+
+        let along = |i: &u32| (boxes[*i as usize].min.as_array()[axis], boxes[*i as usize].max.as_array()[axis]);
+        let below: Vec<u32> = inside.iter().copied().filter(|i| along(i).0 < at).collect();
+        // A box with no thickness lying right on the plane has to go somewhere.
+        let above: Vec<u32> = inside.iter().copied().filter(|i| along(i).1 > at || along(i).0 == at).collect();
+        let (mut below_bounds, mut above_bounds) = (bounds, bounds);
+        match axis {
+            0 => { below_bounds.max.x = at; above_bounds.min.x = at; }
+            1 => { below_bounds.max.y = at; above_bounds.min.y = at; }
+            _ => { below_bounds.max.z = at; above_bounds.min.z = at; }
+        }
+        drop(inside);
+
+        let index = self.nodes.len();
+        self.nodes.push(KdNode::Leaf{ start: 0, count: 0 });
+        self.build(boxes, below, below_bounds, depth - 1, bad);
+        let above_index = self.nodes.len() as u32;
+        self.build(boxes, above, above_bounds, depth - 1, bad);
+        self.nodes[index] = KdNode::Inner{ axis: axis as u8, split: at, above: above_index };
+    }
+
+    fn leaf(&mut self, inside: &[u32]) {
+        self.nodes.push(KdNode::Leaf{ start: self.indices.len() as u32, count: inside.len() as u32 });
+        self.indices.extend_from_slice(inside);
+    }
+}
+
+impl Traverse for KdTree {
+    type Leaf<'a> = Indexed<'a>;
+
+    fn unbounded(&self) -> &HitList {
+        &self.unbounded
+    }
+
+    /// Every leaf the ray passes through, in order along the ray.
+    fn traverse<'a, F>(&'a self, ray: Ray, t_min: Float, mut t_max: Float, mut visit: F)
+        where F: FnMut(Self::Leaf<'a>, Float) -> Float {
+
+        let (mut t0, mut t1) = match self.bounds.and_then(|bounds| bounds.clip(ray, t_min, t_max)) {
+            Some(range) => range,
+            None => return,
+        };
+        let origin = ray.origin.as_array();
+        let direction = ray.direction.as_array();
+        let inv_d = [1.0 / direction[0], 1.0 / direction[1], 1.0 / direction[2]];
+
+        // Far sides still to visit, with the part of the ray inside each.
+        let mut stack = [(0u32, 0.0, 0.0); STACK_SIZE];
+        let mut top = 0;
+        let mut current = 0;
+        let mut counts = TraversalCounts::default();
+        loop {
+            // Everything from here on is beyond the nearest hit.
+            if t_max < t0 {
+                break;
+            }
+            match self.nodes[current] {
+                KdNode::Inner{ axis, split, above } => {
+                    counts.nodes += 1;
+                    let axis = axis as usize;
+                    let below = current + 1;
+                    // The side the ray goes through first, which needn't be the side its origin is on
+                    // when t_min is negative. One parallel to the plane stays on its origin's side.
+                    let upward = direction[axis] > 0.0 || (direction[axis] == 0.0 && origin[axis] < split);
+                    let (near, far) = if upward { (below, above as usize) } else { (above as usize, below) };
+                    // Where the ray crosses the plane decides whether it reaches one side or both, and
+                    // one lying in the plane reaches both all along.
+                    let t_split = (split - origin[axis]) * inv_d[axis];
+                    if direction[axis] == 0.0 && origin[axis] == split {
+                        stack[top] = (far as u32, t0, t1);
+                        top += 1;
+                        current = near;
+                    } else if direction[axis] == 0.0 || t_split > t1 {
+                        current = near;
+                    } else if t_split < t0 {
+                        current = far;
+                    } else {
+                        stack[top] = (far as u32, t_split, t1);
+                        top += 1;
+                        current = near;
+                        t1 = t_split;
+                    }
+                    continue;
+                }
+                KdNode::Leaf{ start, count } => {
+                    if count > 0 {
+                        counts.objects += count as u64;
+                        let indices = &self.indices[start as usize..(start + count) as usize];
+                        t_max = visit(Indexed{ indices, objects: &self.objects }, t_max);
+                    }
+                }
+            }
+            if top == 0 {
+                break;
+            }
+            top -= 1;
+            let (next, next_t0, next_t1) = stack[top];
+            current = next as usize;
+            t0 = next_t0;
+            t1 = next_t1;
+        }
+        bvh::add_counts(counts);
+    }
+}
+
+/// pbrt's limit on how deep the tree may go.
+fn max_depth(objects: usize) -> usize {
+    (8.0 + 1.3 * (objects as Float).log2()).round() as usize
+}
+
+/**
+ * The cheapest plane to split the objects at, as its axis, where it is along that axis and the
+ * expected cost relative to testing one object, sweeping the faces of their boxes from low to
+ * high along each axis in turn. None when no face lies strictly inside the bounds.
+ */
+fn best_split(boxes: &[AABB], inside: &[u32], bounds: AABB) -> Option<(usize, Float, Float)> {
+    let (min, max) = (bounds.min.as_array(), bounds.max.as_array());
+    let extent = (bounds.max - bounds.min).as_array();
+    let area = bounds.surface_area();
+    let mut best: Option<(usize, Float, Float)> = None;
+    let mut edges = Vec::with_capacity(2 * inside.len());
+    for axis in 0..3 {
+        edges.clear();
+        for &object in inside {
+            let aabb = boxes[object as usize];
+            edges.push(Edge{ at: aabb.min.as_array()[axis], starts: true, object });
+            edges.push(Edge{ at: aabb.max.as_array()[axis], starts: false, object });
+        }
+        // Ties are broken so that the tree doesn't depend on the order the objects came in.
+        edges.sort_by(|a, b| a.at.total_cmp(&b.at).then(b.starts.cmp(&a.starts)).then(a.object.cmp(&b.object)));
+
+        let (other0, other1) = ((axis + 1) % 3, (axis + 2) % 3);
+        let (mut below, mut above) = (0, inside.len());
+        for edge in &edges {
+            if !edge.starts {
+                above -= 1;
+            }
+            if edge.at > min[axis] && edge.at < max[axis] {
+                let cross = extent[other0] * extent[other1];
+                let around = extent[other0] + extent[other1];
+                let below_area = 2.0 * (cross + (edge.at - min[axis]) * around);
+                let above_area = 2.0 * (cross + (max[axis] - edge.at) * around);
+                let bonus = if below == 0 || above == 0 { EMPTY_BONUS } else { 0.0 };
+                let cost = TRAVERSAL_COST
+                    + (1.0 - bonus) * (below_area * below as Float + above_area * above as Float) / area;
+                if best.is_none_or(|(_, _, c)| cost < c) {
+                    best = Some((axis, edge.at, cost));
+                }
+            }
+            if edge.starts {
+                below += 1;
+            }
+        }
+    }
+    best
+}
+
+impl Hit for KdTree {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        accelerator::hit(self, ray, t_min, t_max)
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        accelerator::occluded(self, ray, t_min, t_max)
+    }
+
+    /// Objects in several leaves along the ray are only asked for their crossings once.
+    fn hit_all<'a>(&'a self, ray: Ray, t_min: Float, t_max: Float, hits: &mut Vec<HitRecord<'a>>) {
+        accelerator::hit_all(self, ray, t_min, t_max, hits);
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        if self.unbounded.objects.is_empty() { self.bounds } else { None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::float::Float;
+    use crate::hit::{Hit, HitList};
+    use crate::kdtree::{KdNode, KdTree};
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+    use crate::vec3::{Color, Point3, Vec3};
+    use std::sync::Arc;
+
+    #[test]
+    fn splits_between_objects() {
+        // Spheres in a row with gaps between them, which the tree should cut through and nothing else.
+        let material = Lambertian::from_color(Color::default());
+        let mut list = HitList::default();
+        for i in 0..16 {
+            list.add(Sphere::new(Point3::new(3.0 * i as Float, 0.0, 0.0), 1.0, Arc::clone(&material)));
+        }
+        let tree = KdTree::from_hitlist(&list, 0.0, 1.0);
+        for node in &tree.nodes {
+            if let KdNode::Leaf{ count, .. } = node {
+                assert!(*count <= 1);
+            }
+        }
+        let mut indices = tree.indices.clone();
+        indices.sort_unstable();
+        assert_eq!(indices, (0..16).collect::<Vec<u32>>());
+
+        // Along the row from either end, the first sphere is the nearest.
+        let ray = Ray::new(Point3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        assert_eq!(tree.hit(ray, 0.0, Float::INFINITY).map(|hitrec| hitrec.t), Some(4.0));
+        let ray = Ray::new(Point3::new(50.0, 0.0, 0.0), Vec3::new(-1.0, 0.0, 0.0), 0.0);
+        assert_eq!(tree.hit(ray, 0.0, Float::INFINITY).map(|hitrec| hitrec.t), Some(4.0));
+    }
+}
@@ -1,43 +1,85 @@
 mod aabb;
 mod aarect;
+mod accelerator;
+mod animatedtransform;
 mod box3d;
 mod bvh;
 mod camera;
 mod constantmedium;
+mod csg;
+mod curve;
+mod cylinder;
+mod float;
+mod grid;
+mod heightfield;
 mod hit;
+mod instance;
+mod kdtree;
 mod material;
+mod matrix;
+mod mesh;
 mod movingsphere;
+mod obj;
+mod onb;
 mod perlin;
+mod ply;
+mod qbvh;
+mod quad;
+mod quaternion;
 mod rand;
 mod ray;
+mod sdf;
+mod simd;
 mod sphere;
+mod stl;
 mod texture;
+mod torus;
+mod transform;
+mod triangle;
 mod vec3;
 
+use aabb::AABB;
 use aarect::{XYRect, XZRect, YZRect};
+use accelerator::Accelerator;
+use animatedtransform::{AnimatedTransform, Keyframe};
 use box3d::Box3D;
 use bvh::BVHNode;
 use camera::Camera;
 use constantmedium::ConstantMedium;
-use hit::{Hit, HitList, HitRecord, Translate, RotateY};
+use csg::Csg;
+use curve::{Curve, CurveKind};
+use cylinder::{Cone, Cylinder};
+use float::Float;
+use heightfield::Heightfield;
+use hit::{Hit, HitArc, HitList, HitRecord, PACKET};
 use image::{ImageBuffer, RgbImage, Rgb};
+use instance::Instance;
 use ray::Ray;
-use crate::rand::rf64;
+use crate::rand::rfloat;
+use sdf::SdfObject;
 use sphere::Sphere;
 use movingsphere::MovingSphere;
-use material::{Dielectric, DiffuseLight, Lambertian, Metal};
+use perlin::PerlinNoise;
+use material::{Conductor, Dielectric, DiffuseLight, Hair, Lambertian, Material, Metal};
+use matrix::Matrix4;
+use mesh::{Face, TriangleMesh};
+use quad::{Disk, Plane, Quad};
+use quaternion::Quaternion;
 use crate::rand::randrange;
 use std::thread;
 use std::sync::Arc;
-use texture::{Checker, Perlin, Image, SolidColor};
-use vec3::{Color, Point3, Vec3};
+use texture::{Checker, Perlin, Image, SolidColor, VertexColor};
+use torus::Torus;
+use transform::Transform;
+use triangle::Triangle;
+use vec3::{Color, Point3, unit_vector, Vec3};
 
 /**
  * Averages the corresponding indices in the given list of vectors. The result is a vector as long
  * as the shortest inner vector in the data. This is destructive. The input vector is collapsed
  * down to a single inner vector that's the sum of its contents.
  */
-fn average(mut data: Vec<Vec<f64>>) -> Vec<f64> {
+fn average(mut data: Vec<Vec<Float>>) -> Vec<Float> {
     let denomenator = data.len();
     while data.len() > 1 {
         // I'm confident this unwrap won't panic (famous last words?)
@@ -45,7 +87,7 @@ fn average(mut data: Vec<Vec<f64>>) -> Vec<f64> {
         let vec2 = data.pop().unwrap();
         let it = vec1.iter().zip(vec2.iter());
 
-        let mut sum: Vec<f64> = Vec::with_capacity(vec1.len().min(vec2.len()));
+        let mut sum: Vec<Float> = Vec::with_capacity(vec1.len().min(vec2.len()));
         for (a, b) in it {
             sum.push(a + b);
         }
@@ -54,12 +96,12 @@ fn average(mut data: Vec<Vec<f64>>) -> Vec<f64> {
     }
 
     let summed = data.pop().unwrap();
-    let averaged = summed.iter().map(|x| x/denomenator as f64).collect::<Vec<f64>>();
+    let averaged = summed.iter().map(|x| x/denomenator as Float).collect::<Vec<Float>>();
 
     averaged
 }
 
-fn imsave(name: &str, width: usize, height: usize, data: Vec<f64>) {
+fn imsave(name: &str, width: usize, height: usize, data: Vec<Float>) {
 
     let mut img: RgbImage = ImageBuffer::new(width as u32, height as u32);
 
@@ -79,27 +121,84 @@ fn imsave(name: &str, width: usize, height: usize, data: Vec<f64>) {
 
 }
 
+/// Blue through green and yellow to red and white as `x` goes from 0 to 1.
+fn false_color(x: Float) -> Color {
+    let stops = [
+        Color::new(0.0, 0.0, 0.0), Color::new(0.0, 0.0, 1.0), Color::new(0.0, 1.0, 1.0), Color::new(0.0, 1.0, 0.0),
+        Color::new(1.0, 1.0, 0.0), Color::new(1.0, 0.0, 0.0), Color::new(1.0, 1.0, 1.0),
+    ];
+    let scaled = x.clamp(0.0, 1.0) * (stops.len() - 1) as Float;
+    let i = (scaled as usize).min(stops.len() - 2);
+    let t = scaled - i as Float;
+    (1.0 - t) * stops[i] + t * stops[i + 1]
+}
+
+/**
+ * Instead of rendering, traces one ray through the middle of each pixel and writes how many BVH
+ * nodes it visited and how many objects it tested, scaled to the busiest pixel, as
+ * heatmap_nodes.png and heatmap_objects.png. Then prints the shape of every tree in the scene,
+ * and for every group of objects the scene accelerated, how long each kind of accelerator takes to
+ * build over it and to trace the rays that reached it.
+ */
+fn heatmaps(world: &HitList, camera: &Camera, image_width: usize, image_height: usize) {
+    let mut nodes = Vec::with_capacity(image_width * image_height);
+    let mut objects = Vec::with_capacity(image_width * image_height);
+    bvh::take_counts();
+    for j in (0..image_height).rev() {
+        for i in 0..image_width {
+            let u = (i as Float + 0.5) / (image_width-1) as Float;
+            let v = (j as Float + 0.5) / (image_height-1) as Float;
+            world.hit(camera.get_ray(u, v), 0.0, Float::INFINITY);
+            let counts = bvh::take_counts();
+            nodes.push(counts.nodes as Float);
+            objects.push(counts.objects as Float);
+        }
+    }
+
+    for (name, counts) in [("nodes", nodes), ("objects", objects)] {
+        let most = counts.iter().cloned().fold(1.0, Float::max);
+        let mean = counts.iter().sum::<Float>() / counts.len() as Float;
+        println!("{} per ray: {:.1} on average, {} at most", name, mean, most);
+        let data = counts.iter().flat_map(|count| false_color(count / most).as_array()).collect();
+        imsave(&format!("heatmap_{}.png", name), image_width, image_height, data);
+    }
+
+    for (i, stats) in bvh::built_stats().iter().enumerate() {
+        println!("tree {}: {}", i + 1, stats);
+    }
+
+    for (i, group) in accelerator::recorded_groups().iter().enumerate() {
+        println!("group {}: {} objects, {} rays", i + 1, group.objects(), group.rays());
+        for (accelerator, building, tracing) in group.compare() {
+            println!("    {:?}: built in {:.1?}, traced in {:.1?}", accelerator, building, tracing);
+        }
+    }
+}
+
+/// Scattered rays are started off the surface by `HitRecord::spawn_ray`, so every ray is traced
+/// from t = 0.
 fn ray_color(ray: Ray, world: &HitList, depth: usize, background: Color) -> Color {
     if depth <= 0 {
         return Color::new(0., 0., 0.);
     }
 
-    let mut hitrec = HitRecord::default();
-    if world.hit(ray, 0.001, f64::INFINITY, &mut hitrec) {
+    hit_color(ray, world.hit(ray, 0.0, Float::INFINITY), world, depth, background)
+}
+
+/// The rest of `ray_color`, once the ray's hit has been found, for rays whose hits are found
+/// several at a time.
+fn hit_color(ray: Ray, hit: Option<HitRecord>, world: &HitList, depth: usize, background: Color) -> Color {
+    if let Some(hitrec) = hit {
         let mut ray_scattered = Ray::default();
         let mut attenuation = Color::new(0., 0., 0.);
 
-        if let Some(ref material) = hitrec.material {
-            let material = Arc::clone(&material);
-            let emitted = material.emitted(hitrec.u, hitrec.v, hitrec.point);
+        let material = hitrec.material;
+        let emitted = material.emitted(hitrec.u, hitrec.v, hitrec.point);
 
-            if material.scatter(ray, &mut hitrec, &mut attenuation, &mut ray_scattered) {
-                return emitted + attenuation * ray_color(ray_scattered, world, depth-1, background);
-            } else {
-                return emitted;
-            }
+        if material.scatter(ray, &hitrec, &mut attenuation, &mut ray_scattered) {
+            return emitted + attenuation * ray_color(ray_scattered, world, depth-1, background);
         } else {
-            panic!("If something in the world hits, then it must update the material on the hit record.");
+            return emitted;
         }
 
     } else {
@@ -107,40 +206,63 @@ fn ray_color(ray: Ray, world: &HitList, depth: usize, background: Color) -> Colo
     }
 }
 
+/// What scenes gather their objects into, unless they pick something else for a group of them.
+const ACCELERATOR: Accelerator = Accelerator::Qbvh;
+
+/// The list gathered into the default accelerator.
+fn accelerate(list: &HitList) -> HitArc {
+    ACCELERATOR.build(list, 0.0, 1.0)
+}
+
+/// An endless floor just under y = 0. The checker texture is solid, and its y term would be zero
+/// everywhere on y = 0 itself.
+fn ground(material: Material) -> HitArc {
+    Plane::new(Point3::new(0.0, -0.001, 0.0), Vec3::new(0.0, 1.0, 0.0), material)
+}
+
+/// Gold by its complex index of refraction at the middle of red, green and blue.
+fn gold(roughness: Float) -> Material {
+    Conductor::from_ior(Color::new(0.143, 0.374, 1.442), Color::new(3.983, 2.385, 1.603), roughness)
+}
+
+/// Copper, like `gold`.
+fn copper(roughness: Float) -> Material {
+    Conductor::from_ior(Color::new(0.200, 0.924, 1.102), Color::new(3.912, 2.452, 2.142), roughness)
+}
+
 fn random_scene() -> HitList {
     let mut world = HitList::default();
 
     let ground_material = Lambertian::new(Checker::new(
         Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9)
     ));
-    world.add(Sphere::new(
-        Point3::new(0.0, -1000.0, 0.0), 1000.0, ground_material));
+    world.add(ground(ground_material));
 
     for a in -11..11 {
         for b in -11..11 {
-            let choose_mat = rf64();
+            let choose_mat = rfloat();
             let center = Point3::new(
-                a as f64 + 0.9*rf64(),
+                a as Float + 0.9*rfloat(),
                 0.2,
-                b as f64 + 0.9*rf64());
+                b as Float + 0.9*rfloat());
 
             if (center - Point3::new(4.0, 0.2, 0.0)).length() > 0.9 {
 
                 match choose_mat {
                     x if x < 0.8 => {
                         let material = Lambertian::from_color(Color::new(
-                            rf64()*rf64(),
-                            rf64()*rf64(),
-                            rf64()*rf64(),
+                            rfloat()*rfloat(),
+                            rfloat()*rfloat(),
+                            rfloat()*rfloat(),
                         ));
                         let center2 = center + Vec3::new(0.0, randrange(0.0, 0.5), 0.0);
                         world.add(MovingSphere::new(center, center2, 0.0, 1.0, 0.2, material));
                     }
                     x if x < 0.95 => {
                         let material = Metal::new(Color::new(
-                                rf64()*rf64(),
-                                rf64()*rf64(),
-                                rf64()*rf64(),
+                                rfloat()*rfloat(),
+                                rfloat()*rfloat(),
+                                rfloat()*rfloat(),
                             ),
                             randrange(0.0, 0.5)
                         );
@@ -167,9 +289,9 @@ fn random_scene() -> HitList {
     world.add(Sphere::new(
         Point3::new(4.0, 1.0, 0.0), 1.0, material3));
 
-    let bvh = BVHNode::from_hitlist(&world, 0.0, 1.0);
+    let bvh = accelerate(&world);
     let mut world = HitList::default();
-    world.add(Arc::new(bvh));
+    world.add(bvh);
     world
 }
 
@@ -236,13 +358,13 @@ fn cornell_box() -> HitList {
     objects.add(XYRect::new(0.0, 555.0, 0.0, 555.0, 555.0, Arc::clone(&white)));
 
     let box1 = Box3D::new(Point3::new(0.0, 0.0, 0.0), Point3::new(165.0, 330.0, 165.0), Arc::clone(&white));
-    let box1 = RotateY::new(box1, 15f64.to_radians());
-    let box1 = Translate::new(box1, Vec3::new(265.0, 0.0, 295.0));
+    let box1 = Transform::new(box1,
+        Matrix4::translate(Vec3::new(265.0, 0.0, 295.0)) * Matrix4::rotate_y(Float::to_radians(15.0)));
     objects.add(box1);
 
     let box2 = Box3D::new(Point3::new(0.0, 0.0, 0.0), Point3::new(165.0, 165.0, 165.0), Arc::clone(&white));
-    let box2 = RotateY::new(box2, -18f64.to_radians());
-    let box2 = Translate::new(box2, Vec3::new(130.0, 0.0, 65.0));
+    let box2 = Transform::new(box2,
+        Matrix4::translate(Vec3::new(130.0, 0.0, 65.0)) * Matrix4::rotate_y(-Float::to_radians(18.0)));
     objects.add(box2);
 
     objects
@@ -266,12 +388,12 @@ fn cornell_smoke() -> HitList {
     objects.add(XYRect::new(0.0, 555.0, 0.0, 555.0, 555.0, Arc::clone(&white)));
 
     let box1 = Box3D::new(Point3::new(0.0, 0.0, 0.0), Point3::new(165.0, 330.0, 165.0), Arc::clone(&white));
-    let box1 = RotateY::new(box1, 15f64.to_radians());
-    let box1 = Translate::new(box1, Vec3::new(265.0, 0.0, 295.0));
+    let box1 = Transform::new(box1,
+        Matrix4::translate(Vec3::new(265.0, 0.0, 295.0)) * Matrix4::rotate_y(Float::to_radians(15.0)));
 
     let box2 = Box3D::new(Point3::new(0.0, 0.0, 0.0), Point3::new(165.0, 165.0, 165.0), Arc::clone(&white));
-    let box2 = RotateY::new(box2, -18f64.to_radians());
-    let box2 = Translate::new(box2, Vec3::new(130.0, 0.0, 65.0));
+    let box2 = Transform::new(box2,
+        Matrix4::translate(Vec3::new(130.0, 0.0, 65.0)) * Matrix4::rotate_y(-Float::to_radians(18.0)));
 
     objects.add(ConstantMedium::new(box1, 0.01, Color::new(0.0, 0.0, 0.0)));
     objects.add(ConstantMedium::new(box2, 0.01, Color::new(1.0, 1.0, 1.0)));
@@ -302,10 +424,10 @@ fn final_scene() -> HitList {
     const BOXES_PER_SIDE: usize = 20;
     for i in 0..BOXES_PER_SIDE {
         for j in 0..BOXES_PER_SIDE {
-            let w = 100.0f64;
-            let x0: f64 = -1000.0 + (i as f64)*w;
-            let z0: f64 = -1000.0 + (j as f64)*w;
-            let y0 = 0.0f64;
+            let w: Float = 100.0;
+            let x0: Float = -1000.0 + (i as Float)*w;
+            let z0: Float = -1000.0 + (j as Float)*w;
+            let y0: Float = 0.0;
             let x1 = x0 + w;
             let y1 = randrange(1.0, 101.0);
             let z1 = z0 + w;
@@ -320,7 +442,7 @@ fn final_scene() -> HitList {
 
     let mut objects: HitList = Default::default();
 
-    objects.add(Arc::new(BVHNode::from_hitlist(&boxes1, 0.0, 1.0)));
+    objects.add(Accelerator::KdTree.build(&boxes1, 0.0, 1.0));
 
     let light = DiffuseLight::new(SolidColor::from_rgb(7.0, 7.0, 7.0));
     objects.add(XZRect::new(123.0, 423.0, 147.0, 412.0, 554.0, light));
@@ -352,47 +474,538 @@ fn final_scene() -> HitList {
 
     let mut boxes2: HitList = Default::default();
     let white = Lambertian::from_color(Color::new(0.73, 0.73, 0.73));
+    let ball = Sphere::new(Point3::new(0.0, 0.0, 0.0), 10.0, white);
     let ns = 1000;
     for _ in 0..ns {
-        boxes2.add(Sphere::new(Point3::randrange(0.0, 165.0), 10.0, Arc::clone(&white)));
+        boxes2.add(Instance::new(Arc::clone(&ball), Matrix4::translate(Point3::randrange(0.0, 165.0)), None));
     }
 
-    objects.add(
-        Translate::new(
-            RotateY::new(
-                Arc::new(BVHNode::from_hitlist(&boxes2, 0.0, 1.0)),
-                15.0f64.to_radians()),
-            Vec3::new(-100.0, 270.0, 395.0)
+    objects.add(Transform::new(
+        Accelerator::Grid.build(&boxes2, 0.0, 1.0),
+        Matrix4::translate(Vec3::new(-100.0, 270.0, 395.0)) * Matrix4::rotate_y(Float::to_radians(15.0)),
     ));
 
     objects
 }
 
+fn triangles() -> HitList {
+    let mut objects = HitList::default();
+
+    let checker = Checker::new(Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9));
+    objects.add(ground(Lambertian::new(checker)));
+
+    // A flat quad made of two triangles with the earth map stretched across it.
+    let earth = Lambertian::new(Image::new("textures/earthmap.jpg"));
+    let corners = [
+        Point3::new(-3.0, 0.0, -2.0),
+        Point3::new( 1.0, 0.0, -2.0),
+        Point3::new( 1.0, 2.0, -2.0),
+        Point3::new(-3.0, 2.0, -2.0),
+    ];
+    objects.add(Triangle::with_attributes(
+        [corners[0], corners[1], corners[2]], None,
+        [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0)],
+        Arc::clone(&earth),
+    ));
+    objects.add(Triangle::with_attributes(
+        [corners[0], corners[2], corners[3]], None,
+        [(0.0, 0.0), (1.0, 1.0), (0.0, 1.0)],
+        earth,
+    ));
+
+    // An octahedron with radial vertex normals, so that it shades like a faceted sphere.
+    let center = Point3::new(2.0, 1.0, 1.0);
+    let axes = [
+        Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 1.0),
+        Vec3::new(-1.0, 0.0, 0.0), Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 0.0, -1.0),
+    ];
+    let gold = gold(0.1);
+    for &(a, b, c) in &[(0, 1, 2), (2, 1, 3), (3, 1, 5), (5, 1, 0), (2, 4, 0), (3, 4, 2), (5, 4, 3), (0, 4, 5)] {
+        let (a, b, c) = (axes[a], axes[b], axes[c]);
+        objects.add(Triangle::with_attributes(
+            [center + a, center + b, center + c],
+            Some([a, b, c]),
+            [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0)],
+            Arc::clone(&gold),
+        ));
+    }
+
+    objects.add(Triangle::new(
+        Point3::new(-1.0, 0.0, 2.0),
+        Point3::new(0.5, 0.0, 2.5),
+        Point3::new(-0.25, 1.5, 2.25),
+        Lambertian::from_color(Color::new(0.7, 0.1, 0.1)),
+    ));
+
+    objects
+}
+
+fn obj_model() -> HitList {
+    let default_material = Lambertian::from_color(Color::new(0.5, 0.5, 0.5));
+    let model = obj::load("models/showcase.obj", default_material)
+        .unwrap_or_else(|error| panic!("Couldn't load the model: {}", error));
+
+    let mut objects = HitList::default();
+    objects.add(accelerate(&model));
+
+    objects
+}
+
+fn scanned_models() -> HitList {
+    let mut objects = HitList::default();
+
+    let checker = Checker::new(Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9));
+    objects.add(ground(Lambertian::new(checker)));
+
+    let colored = Lambertian::new(VertexColor::new(Color::new(0.5, 0.5, 0.5)));
+    let sphere = ply::load("models/icosphere.ply", colored)
+        .unwrap_or_else(|error| panic!("Couldn't load the model: {}", error));
+    objects.add(Transform::new(sphere, Matrix4::translate(Vec3::new(-1.5, 1.0, 0.0))));
+
+    let torus = stl::load("models/torus.stl", Conductor::new(Color::new(0.7, 0.7, 0.75), 0.05))
+        .unwrap_or_else(|error| panic!("Couldn't load the model: {}", error));
+    objects.add(Transform::new(torus,
+        Matrix4::translate(Vec3::new(1.5, 0.35, 0.0)) * Matrix4::rotate_y(Float::to_radians(30.0))));
+
+    objects
+}
+
+fn transforms() -> HitList {
+    let mut objects = HitList::default();
+
+    let checker = Checker::new(Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9));
+    objects.add(ground(Lambertian::new(checker)));
+
+    // A sphere squashed into an ellipsoid and tipped over.
+    let ellipsoid = Sphere::new(Point3::new(0.0, 0.0, 0.0), 1.0, Conductor::new(Color::new(0.8, 0.8, 0.8), 0.0));
+    objects.add(Transform::new(ellipsoid,
+        Matrix4::translate(Vec3::new(-3.0, 0.8, 0.0))
+        * Matrix4::rotate_z(Float::to_radians(30.0))
+        * Matrix4::scale(Vec3::new(1.0, 0.5, 0.8))));
+
+    // A cube balanced on a corner with Euler angles.
+    let cube = Box3D::new(Point3::new(-0.6, -0.6, -0.6), Point3::new(0.6, 0.6, 0.6),
+        Lambertian::from_color(Color::new(0.7, 0.2, 0.2)));
+    objects.add(Transform::new(cube,
+        Matrix4::translate(Vec3::new(-0.8, 1.04, 0.0))
+        * Matrix4::euler(Float::to_radians(45.0), 0.0, Float::to_radians(35.26))));
+
+    // The earth with its axial tilt, about an arbitrary axis.
+    let earth = Sphere::new(Point3::new(0.0, 0.0, 0.0), 0.9, Lambertian::new(Image::new("textures/earthmap.jpg")));
+    objects.add(Transform::new(earth,
+        Matrix4::translate(Vec3::new(1.4, 0.9, 0.0))
+        * Matrix4::rotate(Vec3::new(1.0, 0.0, 1.0), Float::to_radians(23.4))));
+
+    // A slab turned by composing two quaternions.
+    let slab = Box3D::new(Point3::new(-0.5, -0.1, -0.8), Point3::new(0.5, 0.1, 0.8),
+        Lambertian::from_color(Color::new(0.2, 0.3, 0.7)));
+    let turn = Quaternion::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), Float::to_radians(40.0))
+        * Quaternion::from_axis_angle(Vec3::new(1.0, 0.0, 0.0), Float::to_radians(60.0));
+    objects.add(Transform::new(slab,
+        Matrix4::translate(Vec3::new(3.4, 0.8, 0.0)) * Matrix4::from_quaternion(turn)));
+
+    // A panel that turns to face the camera.
+    let panel = XYRect::new(-1.5, 1.5, -0.75, 0.75, 0.0, Lambertian::new(Image::new("textures/earthmap.jpg")));
+    objects.add(Transform::new(panel, Matrix4::look_at(
+        Point3::new(0.0, 2.8, -3.0),
+        Point3::new(0.0, 3.0, 12.0),
+        Vec3::new(0.0, 1.0, 0.0))));
+
+    objects
+}
+
+fn instances() -> HitList {
+    let mut objects = HitList::default();
+
+    let checker = Checker::new(Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9));
+    objects.add(ground(Lambertian::new(checker)));
+
+    // Every copy shares these two meshes and their BVHs; only the placements are new.
+    let colored = Lambertian::new(VertexColor::new(Color::new(0.5, 0.5, 0.5)));
+    let sphere = ply::load("models/icosphere.ply", colored)
+        .unwrap_or_else(|error| panic!("Couldn't load the model: {}", error));
+    let torus = stl::load("models/torus.stl", Lambertian::from_color(Color::new(0.5, 0.5, 0.5)))
+        .unwrap_or_else(|error| panic!("Couldn't load the model: {}", error));
+
+    let mut placed = HitList::default();
+    for i in -5..5 {
+        for j in -5..5 {
+            let position = Vec3::new(i as Float + 0.8*rfloat(), 0.0, j as Float + 0.8*rfloat());
+            let size = randrange(0.15, 0.35);
+            let spin = Matrix4::rotate_y(randrange(0.0, 2.0*crate::float::consts::PI));
+
+            if rfloat() < 0.5 {
+                // The spheres keep their vertex colors.
+                placed.add(Instance::new(Arc::clone(&sphere),
+                    Matrix4::translate(position + Vec3::new(0.0, size, 0.0))
+                    * spin * Matrix4::scale(Vec3::new(size, size, size)),
+                    None));
+            } else {
+                // The tori each get a material of their own.
+                let material = if rfloat() < 0.3 {
+                    Conductor::new(Color::randrange(0.5, 1.0), 0.1)
+                } else {
+                    Lambertian::from_color(Color::random() * Color::random())
+                };
+                let size = 1.5 * size;
+                placed.add(Instance::new(Arc::clone(&torus),
+                    Matrix4::translate(position + Vec3::new(0.0, 0.35*size, 0.0))
+                    * spin * Matrix4::scale(Vec3::new(size, size, size)),
+                    Some(material)));
+            }
+        }
+    }
+
+    objects.add(accelerate(&placed));
+
+    objects
+}
+
+fn shapes() -> HitList {
+    let mut objects = HitList::default();
+
+    let checker = Checker::new(Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9));
+    objects.add(ground(Lambertian::new(checker)));
+
+    let earth = Lambertian::new(Image::new("textures/earthmap.jpg"));
+    let marble = Lambertian::new(Perlin::new(4.0));
+
+    // A leaning cylinder and an open tube that shows its inside.
+    objects.add(Cylinder::new(Point3::new(-4.0, 0.0, 0.0), Point3::new(-3.4, 2.0, 0.3), 0.6, true, Arc::clone(&earth)));
+    objects.add(Cylinder::new(Point3::new(-2.0, 0.0, 1.5), Point3::new(-2.0, 1.0, 1.5), 0.6, false,
+        Lambertian::from_color(Color::new(0.8, 0.3, 0.2))));
+
+    objects.add(Cone::new(Point3::new(-1.2, 0.0, -1.0), Point3::new(-1.2, 2.2, -1.0), 0.8, true, Arc::clone(&marble)));
+
+    objects.add(Torus::new(Point3::new(0.8, 0.9, 0.0), Vec3::new(0.0, 1.0, -1.5), 0.7, 0.25,
+        gold(0.1)));
+    objects.add(Torus::new(Point3::new(0.6, 0.2, 1.8), Vec3::new(0.0, 1.0, 0.0), 0.5, 0.2, earth));
+
+    objects.add(Disk::new(Point3::new(2.8, 1.0, 0.0), Vec3::new(-1.0, 0.2, 1.0), 0.9, marble));
+
+    // A tilted panel light and a parallelogram wall behind everything.
+    let light = DiffuseLight::new(SolidColor::from_rgb(4.0, 4.0, 4.0));
+    objects.add(Quad::new(Point3::new(-1.0, 4.0, 2.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 0.8, 0.6), light));
+    objects.add(Quad::new(Point3::new(1.5, 0.0, -3.0), Vec3::new(3.0, 0.0, 1.5), Vec3::new(0.5, 2.5, 0.0),
+        Lambertian::from_color(Color::new(0.2, 0.4, 0.7))));
+
+    objects
+}
+
+fn csg() -> HitList {
+    let mut objects = HitList::default();
+
+    let checker = Checker::new(Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9));
+    objects.add(ground(Lambertian::new(checker)));
+
+    // A die: the overlap of a cube and a sphere, with a pip scooped out of each face we can see.
+    let ivory = Lambertian::from_color(Color::new(0.9, 0.85, 0.75));
+    let pips = Lambertian::from_color(Color::new(0.1, 0.1, 0.1));
+    let mut die = Csg::intersection(
+        Box3D::new(Point3::new(-2.7, 0.0, -0.7), Point3::new(-1.3, 1.4, 0.7), Arc::clone(&ivory)),
+        Sphere::new(Point3::new(-2.0, 0.7, 0.0), 0.95, ivory),
+    );
+    for center in &[Point3::new(-2.0, 1.4, 0.0), Point3::new(-2.0, 0.7, 0.7), Point3::new(-1.3, 0.7, 0.0)] {
+        die = Csg::difference(die, Sphere::new(*center, 0.2, Arc::clone(&pips)));
+    }
+    objects.add(die);
+
+    // A glass lens where two spheres overlap.
+    let lens = Csg::intersection(
+        Sphere::new(Point3::new(0.0, 1.0, -1.3), 1.5, Dielectric::new(1.5)),
+        Sphere::new(Point3::new(0.0, 1.0, 1.3), 1.5, Dielectric::new(1.5)),
+    );
+    objects.add(lens);
+
+    // A block with a tunnel bored through it, and a ball and rod fused together.
+    let copper = copper(0.2);
+    let block = Csg::difference(
+        Box3D::new(Point3::new(1.2, 0.0, -0.8), Point3::new(2.8, 1.2, 0.8), Lambertian::from_color(Color::new(0.2, 0.4, 0.7))),
+        Cylinder::new(Point3::new(2.0, 0.6, -1.0), Point3::new(2.0, 0.6, 1.0), 0.4, true, Arc::clone(&copper)),
+    );
+    objects.add(block);
+    objects.add(Csg::union(
+        Sphere::new(Point3::new(0.5, 0.4, 2.0), 0.4, Arc::clone(&copper)),
+        Cylinder::new(Point3::new(0.5, 0.4, 2.0), Point3::new(1.6, 0.4, 2.6), 0.15, true, copper),
+    ));
+
+    objects
+}
+
+fn distance_fields() -> HitList {
+    let mut objects = HitList::default();
+
+    let checker = Checker::new(Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9));
+    objects.add(ground(Lambertian::new(checker)));
+
+    let at = Point3::new;
+    let bounds = |min: Point3, max: Point3| AABB::new(min, max);
+
+    // A rounded box melted into a sphere, with a hole cut through both.
+    let blob = sdf::smooth_subtract(
+        sdf::smooth_union(
+            sdf::cuboid(at(-3.0, 0.7, 0.0), Vec3::new(0.6, 0.6, 0.6)),
+            sdf::sphere(at(-3.0, 1.4, 0.0), 0.6),
+            0.3),
+        sdf::capsule(at(-3.0, 0.7, -1.0), at(-3.0, 0.7, 1.0), 0.3),
+        0.1);
+    objects.add(SdfObject::new(blob, bounds(at(-3.7, 0.0, -0.7), at(-2.3, 2.1, 0.7)),
+        Lambertian::from_color(Color::new(0.8, 0.3, 0.2))));
+
+    // A twisted bar on a torus.
+    let bar = sdf::twist(sdf::cuboid(Point3::default(), Vec3::new(0.4, 1.0, 0.15)), 1.2);
+    let bar = Arc::new(move |p: Point3| bar(p - Vec3::new(-1.2, 1.25, 0.0)));
+    let base = sdf::torus(at(-1.2, 0.15, 0.0), 0.5, 0.15);
+    objects.add(SdfObject::new(sdf::smooth_union(bar, base, 0.15),
+        bounds(at(-1.9, 0.0, -0.7), at(-0.5, 2.3, 0.7)),
+        Conductor::new(Color::new(0.8, 0.8, 0.85), 0.05)));
+
+    // The Mandelbulb.
+    objects.add(SdfObject::new(sdf::mandelbulb(at(0.8, 1.1, 0.0), 1.0, 8.0, 10),
+        bounds(at(-0.4, -0.1, -1.2), at(2.0, 2.3, 1.2)),
+        Lambertian::from_color(Color::new(0.3, 0.5, 0.8))));
+
+    // A field of little capsules repeated across a slab.
+    let pill = sdf::capsule(at(-0.15, 0.0, 0.0), at(0.15, 0.0, 0.0), 0.12);
+    let pills = sdf::repeat(pill, Vec3::new(0.6, 10.0, 0.6));
+    let pills = Arc::new(move |p: Point3| pills(p - Vec3::new(0.0, 0.12, 0.0)));
+    objects.add(SdfObject::new(pills, bounds(at(2.6, 0.0, -1.5), at(4.4, 0.24, 1.5)),
+        Lambertian::from_color(Color::new(0.9, 0.8, 0.3))));
+
+    objects
+}
+
+fn terrain() -> HitList {
+    let mut objects = HitList::default();
+
+    // Hills from noise, with water filling the valleys.
+    let grass = Lambertian::from_color(Color::new(0.35, 0.5, 0.25));
+    objects.add(Heightfield::from_noise(&PerlinNoise::new(), 256, 6.0, 7,
+        Point3::new(-20.0, -0.5, -30.0), Vec3::new(40.0, 5.0, 40.0), grass));
+    objects.add(Plane::new(Point3::new(0.0, 0.3, 0.0), Vec3::new(0.0, 1.0, 0.0),
+        Conductor::new(Color::new(0.3, 0.45, 0.6), 0.02)));
+
+    // The earth map read as a height map, so the continents stand up out of the oceans.
+    let earth = Lambertian::new(Image::new("textures/earthmap.jpg"));
+    let relief = Heightfield::from_image("textures/earthmap.jpg",
+        Point3::new(-2.0, 0.0, -1.0), Vec3::new(4.0, 0.25, 2.0), earth)
+        .unwrap_or_else(|error| panic!("Couldn't load the height map: {}", error));
+    objects.add(Transform::new(relief, Matrix4::translate(Vec3::new(0.0, 2.6, 2.0)) * Matrix4::rotate_x(0.5)));
+
+    objects
+}
+
+fn hair() -> HitList {
+    let mut objects = HitList::default();
+
+    // A lawn of grass blades, each a ribbon turned a random way and leaning a little.
+    let mut grass = HitList::default();
+    let blade = Lambertian::from_color(Color::new(0.3, 0.55, 0.2));
+    for _ in 0..1500 {
+        let root = Point3::new(randrange(-4.0, 4.0), 0.0, randrange(-3.0, 2.0));
+        let height = randrange(0.3, 0.6);
+        let lean = Vec3::new(randrange(-0.2, 0.2), 0.0, randrange(-0.2, 0.2));
+        let points = [root, root + Vec3::new(0.0, height/3.0, 0.0),
+            root + Vec3::new(0.0, 2.0*height/3.0, 0.0) + 0.5*lean, root + Vec3::new(0.0, height, 0.0) + lean];
+        let angle = randrange(0.0, crate::float::consts::PI);
+        let facing = Vec3::new(angle.cos(), 0.0, angle.sin());
+        grass.add(Curve::new(points, 0.04, 0.005, CurveKind::Ribbon(facing, facing), Arc::clone(&blade)));
+    }
+    objects.add(accelerate(&grass));
+    objects.add(ground(Lambertian::from_color(Color::new(0.25, 0.2, 0.1))));
+
+    // A ball of fur, with every hair standing out from the surface and drooping at the tip.
+    let mut fur = HitList::default();
+    let center = Point3::new(0.0, 1.0, 0.0);
+    let brown = Hair::new(Color::new(0.6, 0.35, 0.15), 0.15, 3.0);
+    for _ in 0..3000 {
+        let out = Vec3::random_unit_vector();
+        let root = center + 0.8*out;
+        let droop = Vec3::new(0.0, -0.08, 0.0);
+        let points = [root, root + 0.1*out, root + 0.2*out + droop, root + 0.25*out + 2.0*droop];
+        fur.add(Curve::new(points, 0.01, 0.002, CurveKind::Cylinder, Arc::clone(&brown)));
+    }
+    objects.add(accelerate(&fur));
+    objects.add(Sphere::new(center, 0.82, Lambertian::from_color(Color::new(0.2, 0.1, 0.05))));
+
+    objects
+}
+
+fn motion() -> HitList {
+    let mut objects = HitList::default();
+    objects.add(ground(Lambertian::new(Checker::new(Color::new(0.2, 0.3, 0.1), Color::new(0.9, 0.9, 0.9)))));
+
+    // A box tumbling across the floor, a quarter turn while it rolls one side's width.
+    let one = Vec3::new(1.0, 1.0, 1.0);
+    let red = Lambertian::from_color(Color::new(0.7, 0.15, 0.1));
+    let cube = Box3D::new(Point3::new(-0.5, -0.5, -0.5), Point3::new(0.5, 0.5, 0.5), red);
+    let roll = Quaternion::from_axis_angle(Vec3::new(0.0, 0.0, -1.0), crate::float::consts::FRAC_PI_2);
+    objects.add(AnimatedTransform::new(cube, vec![
+        Keyframe::new(0.0, Vec3::new(-2.5, 0.5, 0.0), Quaternion::identity(), one),
+        Keyframe::new(0.5, Vec3::new(-2.0, 0.5*Float::sqrt(2.0), 0.0), roll.slerp(Quaternion::identity(), 0.5), one),
+        Keyframe::new(1.0, Vec3::new(-1.5, 0.5, 0.0), roll, one),
+    ]));
+
+    // A ball bouncing, squashed where it meets the floor.
+    let bounce = |time: Float, height: Float, squash: Float| Keyframe::new(time, Vec3::new(0.5, height, 0.5),
+        Quaternion::identity(), Vec3::new(1.0 / squash.sqrt(), squash, 1.0 / squash.sqrt()));
+    let ball = Sphere::new(Point3::default(), 0.5, Lambertian::from_color(Color::new(0.1, 0.3, 0.7)));
+    objects.add(AnimatedTransform::new(ball, vec![
+        bounce(0.0, 2.0, 1.1), bounce(0.4, 0.85, 1.2), bounce(0.5, 0.35, 0.7), bounce(0.6, 0.85, 1.2),
+        bounce(1.0, 2.0, 1.1),
+    ]));
+
+    // A torus spinning in place, and the earth sliding by with its texture intact.
+    let spin = |time: Float, angle: Float| Keyframe::new(time, Vec3::new(2.5, 1.0, -0.5),
+        Quaternion::from_axis_angle(Vec3::new(1.0, 0.0, 0.0), angle), one);
+    let ring = Torus::new(Point3::default(), Vec3::new(0.0, 1.0, 0.0), 0.7, 0.25,
+        Conductor::new(Color::new(0.8, 0.7, 0.3), 0.1));
+    objects.add(AnimatedTransform::new(ring, vec![spin(0.0, 0.0), spin(0.5, 0.6), spin(1.0, 1.2)]));
+    objects.add(MovingSphere::new(Point3::new(-0.5, 0.6, 2.0), Point3::new(0.3, 0.6, 2.0), 0.0, 1.0, 0.6,
+        Lambertian::new(Image::new("textures/earthmap.jpg"))));
+
+    objects
+}
+
+/**
+ * Flags waving on their poles, at `frame` of an animation at 24 frames a second. The cloth is
+ * built flat once and deformed to where the wave has got to, which refits its tree, and the same
+ * cloth is hung on every pole by an instance under a small top-level tree of its own.
+ */
+fn flags(frame: usize) -> HitList {
+    let mut objects = HitList::default();
+    objects.add(ground(Lambertian::from_color(Color::new(0.35, 0.45, 0.25))));
+
+    let (columns, rows) = (48, 24);
+    let (width, height) = (2.0, 1.2);
+    let mut positions = Vec::new();
+    let mut uvs = Vec::new();
+    let mut faces = Vec::new();
+    for j in 0..=rows {
+        for i in 0..=columns {
+            let (u, v) = (i as Float / columns as Float, j as Float / rows as Float);
+            positions.push(Point3::new(width * u, height * v, 0.0));
+            uvs.push((u, v));
+            if i < columns && j < rows {
+                let corner = j * (columns + 1) + i;
+                let above = corner + columns + 1;
+                for vertices in [[corner, corner + 1, above + 1], [corner, above + 1, above]] {
+                    faces.push(Face{ vertices, normals: Some(vertices), uvs: Some(vertices), material: 0 });
+                }
+            }
+        }
+    }
+    let flat = vec![Vec3::new(0.0, 0.0, 1.0); positions.len()];
+    let white = Lambertian::from_color(Color::new(0.9, 0.9, 0.9));
+    let mut cloth = TriangleMesh::deformable(positions.clone(), flat, uvs, Vec::new(), faces, vec![white]);
+
+    // A travelling wave that's still at the pole and grows toward the free end.
+    let time = frame as Float / 24.0;
+    let (amplitude, k, omega) = (0.35, 4.0, 8.0);
+    let wave = |x: Float| (amplitude * x * (k*x - omega*time).sin(),
+        amplitude * ((k*x - omega*time).sin() + k * x * (k*x - omega*time).cos()));
+    let waved = positions.iter().map(|p| Point3::new(p.x, p.y, wave(p.x).0)).collect();
+    let normals = positions.iter().map(|p| unit_vector(Vec3::new(-wave(p.x).1, 0.0, 1.0))).collect();
+    cloth.deform(waved, normals);
+    let cloth: HitArc = Arc::new(cloth);
+
+    let colors = [Color::new(0.7, 0.1, 0.1), Color::new(0.1, 0.25, 0.6), Color::new(0.85, 0.65, 0.1)];
+    let mut hung: Vec<HitArc> = Vec::new();
+    for (n, color) in colors.iter().enumerate() {
+        let x = 3.0 * n as Float - 3.0;
+        let pole = Conductor::new(Color::new(0.6, 0.6, 0.6), 0.2);
+        objects.add(Cylinder::new(Point3::new(x, 0.0, 0.0), Point3::new(x, 3.2, 0.0), 0.04, true, pole));
+        let placement = Matrix4::translate(Vec3::new(x, 1.9, 0.0)) * Matrix4::rotate_y(0.3 * n as Float - 0.3);
+        hung.push(Instance::new(Arc::clone(&cloth), placement, Some(Lambertian::from_color(*color))));
+    }
+    objects.add(Arc::new(BVHNode::from_vec(&hung, 0, hung.len(), 0.0, 1.0)));
+
+    objects
+}
+
+/// How each camera ray is turned into a color.
+#[derive(Copy, Clone)]
+enum Shading {
+    /// Full path tracing, bouncing up to `max_depth` times before giving up.
+    Path { max_depth: usize, background: Color },
+    /// White where nothing is within this distance of the first hit in a random direction over
+    /// its surface, black where something is, so each pixel averages to how open it is.
+    AmbientOcclusion(Float),
+}
+
+impl Shading {
+    /// The color of a camera ray, given its first hit.
+    fn color(&self, ray: Ray, hit: Option<HitRecord>, world: &HitList) -> Color {
+        match *self {
+            Shading::Path{ max_depth, background } => hit_color(ray, hit, world, max_depth, background),
+            Shading::AmbientOcclusion(distance) => {
+                let hitrec = match hit {
+                    Some(hitrec) => hitrec,
+                    None => return Color::new(1.0, 1.0, 1.0),
+                };
+                let mut direction = hitrec.normal + Vec3::random_unit_vector();
+                if direction.near_zero() {
+                    direction = hitrec.normal;
+                }
+                let probe = hitrec.spawn_ray(direction, ray.time);
+                if world.occluded(probe, 0.0, distance / direction.length()) {
+                    Color::new(0.0, 0.0, 0.0)
+                } else {
+                    Color::new(1.0, 1.0, 1.0)
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Pixels are rendered in 2x2 blocks, with a ray through each of the four making a packet. With
+ * `packets` set, the camera rays of a packet are traced together with `Hit::hit_packet`, and only
+ * the bounces after the first hit go one at a time. Along the right and top edges of an image
+ * with an odd size, blocks hang off the edge, and their rays there are traced but thrown away.
+ */
 fn render(
     world: &HitList,
     camera: &Camera,
     image_width: usize,
     image_height: usize,
     samples_per_pixel: usize,
-    max_depth: usize,
-    background: Color,
-) -> Vec<f64> {
-    let mut data: Vec<f64> = Vec::with_capacity(image_width*image_height*3);
-
-    for j in (0..image_height).rev() {
-        for i in 0..image_width {
-            let mut pixel_color = Color::new(0., 0., 0.);
+    shading: Shading,
+    packets: bool,
+) -> Vec<Float> {
+    let mut data: Vec<Float> = vec![0.0; image_width*image_height*3];
+
+    for j0 in (0..image_height).step_by(2) {
+        for i0 in (0..image_width).step_by(2) {
+            let pixels = [(i0, j0), (i0 + 1, j0), (i0, j0 + 1), (i0 + 1, j0 + 1)];
+            let mut pixel_colors = [Color::new(0., 0., 0.); PACKET];
             for _ in 0..samples_per_pixel {
-                let u = (i as f64 + rf64()) / (image_width-1) as f64;
-                let v = (j as f64 + rf64()) / (image_height-1) as f64;
+                let rays = pixels.map(|(i, j)| {
+                    let u = (i as Float + rfloat()) / (image_width-1) as Float;
+                    let v = (j as Float + rfloat()) / (image_height-1) as Float;
+                    camera.get_ray(u, v)
+                });
+                let hits = if packets {
+                    world.hit_packet(&rays, 0.0, [Float::INFINITY; PACKET])
+                } else {
+                    rays.map(|r| world.hit(r, 0.0, Float::INFINITY))
+                };
+                for lane in 0..PACKET {
+                    pixel_colors[lane] += shading.color(rays[lane], hits[lane], world);
+                }
+            }
 
-                let r = camera.get_ray(u, v);
-                pixel_color += ray_color(r, &world, max_depth, background);
+            let scale = 1. / samples_per_pixel as Float;
+            for (&(i, j), pixel_color) in pixels.iter().zip(pixel_colors.iter()) {
+                if i >= image_width || j >= image_height {
+                    continue;
+                }
+                // Rows are written from the top down.
+                let index = 3 * ((image_height - 1 - j) * image_width + i);
+                data[index] = (pixel_color.x * scale).sqrt();
+                data[index + 1] = (pixel_color.y * scale).sqrt();
+                data[index + 2] = (pixel_color.z * scale).sqrt();
             }
-            let scale = 1. / samples_per_pixel as f64;
-            data.push((pixel_color.x * scale).sqrt());
-            data.push((pixel_color.y * scale).sqrt());
-            data.push((pixel_color.z * scale).sqrt());
         }
     }
 
@@ -404,17 +1017,28 @@ fn main() {
     // Image
     let aspect_ratio = 1.0;
     let image_width = 600;
-    let image_height = (image_width as f64 / aspect_ratio) as usize;
+    let image_height = (image_width as Float / aspect_ratio) as usize;
     let samples_per_pixel = 10;
     let max_depth = 50;
+    // Writes heatmaps of where rays work hardest, describes the trees and times the accelerators,
+    // instead of rendering.
+    let heatmap = false;
+    // Renders ambient occlusion out to this distance instead of lighting, if set.
+    let occlusion_distance: Option<Float> = None;
+    // Traces camera rays in packets of four, which four-wide trees walk together.
+    let packets = true;
 
     // World
     let lookfrom: Point3;
     let lookat: Point3;
-    let vfov: f64;
-    let aperture: f64;
+    let vfov: Float;
+    let aperture: Float;
     let background: Color;
 
+    if heatmap {
+        bvh::record_stats();
+        accelerator::record_groups();
+    }
     let world: HitList = match 8 {
         1 => {
             lookfrom = Point3::new(13.0 ,2.0 ,3.0);
@@ -480,6 +1104,102 @@ fn main() {
             background = Color::new(0.0, 0.0, 0.0);
             final_scene()
         }
+        9 => {
+            lookfrom = Point3::new(0.0, 3.0, 12.0);
+            lookat = Point3::new(0.0, 1.0, 0.0);
+            vfov = 30.0;
+            aperture = 0.0;
+            background = Color::new(0.7, 0.8, 1.0);
+            triangles()
+        }
+        10 => {
+            lookfrom = Point3::new(0.0, 3.0, 10.0);
+            lookat = Point3::new(0.0, 1.2, 0.0);
+            vfov = 35.0;
+            aperture = 0.0;
+            background = Color::new(0.05, 0.05, 0.08);
+            obj_model()
+        }
+        11 => {
+            lookfrom = Point3::new(0.0, 4.0, 10.0);
+            lookat = Point3::new(0.0, 0.7, 0.0);
+            vfov = 30.0;
+            aperture = 0.0;
+            background = Color::new(0.7, 0.8, 1.0);
+            scanned_models()
+        }
+        12 => {
+            lookfrom = Point3::new(0.0, 3.0, 12.0);
+            lookat = Point3::new(0.0, 1.2, 0.0);
+            vfov = 35.0;
+            aperture = 0.0;
+            background = Color::new(0.7, 0.8, 1.0);
+            transforms()
+        }
+        13 => {
+            lookfrom = Point3::new(0.0, 4.0, 9.0);
+            lookat = Point3::new(0.0, 0.0, 0.0);
+            vfov = 40.0;
+            aperture = 0.0;
+            background = Color::new(0.7, 0.8, 1.0);
+            instances()
+        }
+        14 => {
+            lookfrom = Point3::new(0.0, 3.0, 12.0);
+            lookat = Point3::new(0.0, 1.0, 0.0);
+            vfov = 40.0;
+            aperture = 0.0;
+            background = Color::new(0.4, 0.45, 0.5);
+            shapes()
+        }
+        15 => {
+            lookfrom = Point3::new(0.0, 3.0, 10.0);
+            lookat = Point3::new(0.0, 0.7, 0.0);
+            vfov = 35.0;
+            aperture = 0.0;
+            background = Color::new(0.7, 0.8, 1.0);
+            csg()
+        }
+        16 => {
+            lookfrom = Point3::new(0.0, 3.0, 10.0);
+            lookat = Point3::new(0.2, 0.9, 0.0);
+            vfov = 50.0;
+            aperture = 0.0;
+            background = Color::new(0.7, 0.8, 1.0);
+            distance_fields()
+        }
+        17 => {
+            lookfrom = Point3::new(0.0, 4.5, 8.0);
+            lookat = Point3::new(0.0, 1.5, -6.0);
+            vfov = 50.0;
+            aperture = 0.0;
+            background = Color::new(0.7, 0.8, 1.0);
+            terrain()
+        }
+        18 => {
+            lookfrom = Point3::new(0.0, 2.0, 6.0);
+            lookat = Point3::new(0.0, 0.8, 0.0);
+            vfov = 35.0;
+            aperture = 0.0;
+            background = Color::new(0.7, 0.8, 1.0);
+            hair()
+        }
+        19 => {
+            lookfrom = Point3::new(0.0, 3.0, 10.0);
+            lookat = Point3::new(0.0, 0.9, 0.0);
+            vfov = 35.0;
+            aperture = 0.0;
+            background = Color::new(0.7, 0.8, 1.0);
+            motion()
+        }
+        20 => {
+            lookfrom = Point3::new(6.0, 3.0, 9.0);
+            lookat = Point3::new(1.0, 1.6, 0.0);
+            vfov = 45.0;
+            aperture = 0.0;
+            background = Color::new(0.7, 0.8, 1.0);
+            flags(12)
+        }
         _ => {
             lookfrom = Point3::new(15.0, 4.0, 3.0);
             lookat = Point3::new(0.0, 2.0, 0.0);
@@ -501,16 +1221,26 @@ fn main() {
         0.0, 1.0 // shutter time
     );
 
+    if heatmap {
+        heatmaps(&world, &camera, image_width, image_height);
+        return;
+    }
+
     // Render
 
+    let shading = match occlusion_distance {
+        Some(distance) => Shading::AmbientOcclusion(distance),
+        None => Shading::Path{ max_depth, background },
+    };
+
     let worldref = Arc::new(world);
     let camref = Arc::new(camera);
 
     let thread_count = 8;
-    let mut results: Vec<Vec<f64>> = Vec::new();
-    let mut threads: Vec<thread::JoinHandle<Vec<f64>>> = Vec::new();
+    let mut results: Vec<Vec<Float>> = Vec::new();
+    let mut threads: Vec<thread::JoinHandle<Vec<Float>>> = Vec::new();
 
-    let samples_per_thread = (samples_per_pixel as f64 / thread_count as f64).ceil() as usize;
+    let samples_per_thread = (samples_per_pixel as Float / thread_count as Float).ceil() as usize;
     println!("Running {} threads at {} samples for a total of {} samples per pixel",
         thread_count,
         samples_per_thread,
@@ -524,7 +1254,7 @@ fn main() {
 
         let handle = thread::spawn(move || {
             println!("Starting thread #{} of {}", i+1, thread_count);
-            let data = render(&worldref, &camref, image_width, image_height, samples_per_thread, max_depth, background);
+            let data = render(&worldref, &camref, image_width, image_height, samples_per_thread, shading, packets);
             println!("Completed thread #{} of {}", i+1, thread_count);
             data
         });
@@ -1,8 +1,11 @@
+use crate::float::Float;
 use crate::hit::HitRecord;
+use crate::onb::Onb;
 use crate::ray::Ray;
 use crate::texture::{SolidColor, Texture};
-use crate::vec3::{Color, dot, Point3, unit_vector, Vec3};
+use crate::vec3::{Color, cross, dot, Point3, unit_vector, Vec3};
 use rand;
+use crate::float::consts::PI;
 use std::sync::Arc;
 
 
@@ -10,14 +13,14 @@ pub fn reflect(v: Vec3, n: Vec3) -> Vec3 {
     v - 2.*dot(v, n)*n
 }
 
-pub fn refract(uv: Vec3, n: Vec3, etai_over_etat: f64 ) -> Vec3 {
+pub fn refract(uv: Vec3, n: Vec3, etai_over_etat: Float ) -> Vec3 {
     let cos_theta = dot(-uv, n).min(1.0);
     let r_out_perp = etai_over_etat * (uv + cos_theta*n);
-    let r_out_parallel = n * -(1f64 - r_out_perp.length_squared()).abs().sqrt();
+    let r_out_parallel = n * -(1.0 - r_out_perp.length_squared()).abs().sqrt();
     return r_out_perp + r_out_parallel;
 }
 
-pub fn reflectance(cosine: f64, ref_idx: f64) -> f64 {
+pub fn reflectance(cosine: Float, ref_idx: Float) -> Float {
     let r0 = (1.0-ref_idx) / (1.0+ref_idx);
     let r0 = r0*r0;
     return r0 + (1.0-r0)*(1.0 - cosine).powi(5);
@@ -27,11 +30,11 @@ pub trait Scatter {
     fn scatter(
         &self,
         ray_in: Ray,
-        hitrec: &mut HitRecord,
+        hitrec: &HitRecord,
         attenuation: &mut Color,
         ray_scattered: &mut Ray) -> bool;
 
-    fn emitted(&self, _u: f64, _v: f64, _p: Point3) -> Color {
+    fn emitted(&self, _u: Float, _v: Float, _p: Point3) -> Color {
         Color::default()
     }
 
@@ -54,56 +57,196 @@ impl Lambertian {
 }
 
 impl Scatter for Lambertian {
-    fn scatter(&self, ray_in: Ray, hitrec: &mut HitRecord, attenuation: &mut Color, ray_scattered: &mut Ray) -> bool {
+    fn scatter(&self, ray_in: Ray, hitrec: &HitRecord, attenuation: &mut Color, ray_scattered: &mut Ray) -> bool {
         let mut scatter_direction = hitrec.normal + Vec3::random_unit_vector();
 
         if scatter_direction.near_zero() {
             scatter_direction = hitrec.normal;
         }
 
-        ray_scattered.origin.copy(hitrec.point);
-        ray_scattered.direction.copy(scatter_direction);
-        ray_scattered.time = ray_in.time;
-        attenuation.copy(self.albedo.value(hitrec.u, hitrec.v, hitrec.point));
+        *ray_scattered = hitrec.spawn_ray(scatter_direction, ray_in.time);
+        attenuation.copy(self.albedo.value_at(hitrec));
         return true;
     }
 }
 
+/**
+ * The metal of Ray Tracing in One Weekend: a mirror whose reflections are knocked about by up to
+ * `fuzz` in a random direction. It's cheap and looks right at a glance, but it isn't based on any
+ * physical surface, and rough reflections can end up under it. Kept so that the book's scenes
+ * look as they do in the book. See `Conductor` for everything else.
+ */
 pub struct Metal{
     albedo: Color,
-    fuzz: f64
+    fuzz: Float
 }
 
 impl Metal {
-    pub fn new(albedo: Color, fuzz: f64) -> Material {
+    pub fn new(albedo: Color, fuzz: Float) -> Material {
         Arc::new(Metal{ albedo, fuzz })
     }
 }
 
 impl Scatter for Metal {
-    fn scatter(&self, ray_in: Ray, hitrec: &mut HitRecord, attenuation: &mut Color, ray_scattered: &mut Ray) -> bool {
+    fn scatter(&self, ray_in: Ray, hitrec: &HitRecord, attenuation: &mut Color, ray_scattered: &mut Ray) -> bool {
         let fuzz = if self.fuzz < 1.0 { self.fuzz } else { 1.0 };
         let reflected = reflect(unit_vector(ray_in.direction), hitrec.normal);
-        ray_scattered.origin.copy(hitrec.point);
-        ray_scattered.direction.copy(reflected + fuzz*Vec3::random_in_unit_sphere());
-        ray_scattered.time = ray_in.time;
+        *ray_scattered = hitrec.spawn_ray(reflected + fuzz*Vec3::random_in_unit_sphere(), ray_in.time);
         attenuation.copy(self.albedo);
         return true;
     }
 }
 
+/// How much light a conductor reflects at each angle.
+#[derive(Debug, Copy, Clone)]
+pub enum Fresnel {
+    /// Schlick's approximation, from the color the metal reflects looking straight at it.
+    Schlick(Color),
+    /// Exactly, from the metal's complex index of refraction, eta + ik, for each channel.
+    Complex { eta: Color, k: Color },
+}
+
+impl Fresnel {
+    pub fn reflectance(&self, cosine: Float) -> Color {
+        match *self {
+            Fresnel::Schlick(f0) => f0 + (1.0 - cosine).powi(5) * (Color::new(1.0, 1.0, 1.0) - f0),
+            Fresnel::Complex{ eta, k } => Color::new(
+                conductor_reflectance(cosine, eta.x, k.x),
+                conductor_reflectance(cosine, eta.y, k.y),
+                conductor_reflectance(cosine, eta.z, k.z),
+            ),
+        }
+    }
+}
+
+/// The Fresnel equations for light in air meeting a conductor, averaged over both polarizations,
+/// as in pbrt's `FrConductor`.
+fn conductor_reflectance(cosine: Float, eta: Float, k: Float) -> Float {
+    let cos2 = cosine.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let t0 = eta*eta - k*k - sin2;
+    let a2_plus_b2 = (t0*t0 + 4.0*eta*eta*k*k).sqrt();
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let t2 = 2.0 * cosine * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2*a2_plus_b2 + sin2*sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rs + rp)
+}
+
+/// Below this roughness a conductor is taken to be a perfect mirror.
+const SMOOTH: Float = 1e-3;
+
+/**
+ * A metal made of tiny mirror facets, tilted about the normal by the GGX (Trowbridge-Reitz)
+ * distribution. The roughnesses are GGX's alphas along the record's tangent and across it, about
+ * the width of the highlight in radians. Shapes that don't give a tangent get one picked around
+ * the normal, so anisotropic highlights only run a sensible way on curves.
+ *
+ * Rays are reflected off a facet picked from those the incoming ray can see (Heitz, "Sampling the
+ * GGX Distribution of Visible Normals", 2018). That leaves the Fresnel term and the Smith shadowing
+ * of the outgoing ray as the whole weight, so no ray carries more light than it brought. Light
+ * that would bounce between facets more than once is lost instead, which darkens rough metal: a
+ * third of the light head on at a roughness of 0.5, and two thirds at 1. Reflections that would
+ * go under the surface are absorbed.
+ */
+pub struct Conductor {
+    fresnel: Fresnel,
+    alpha_x: Float,
+    alpha_y: Float,
+}
+
+impl Conductor {
+    /// A metal reflecting `color` head on, equally rough every way.
+    pub fn new(color: Color, roughness: Float) -> Material {
+        Conductor::anisotropic(Fresnel::Schlick(color), roughness, roughness)
+    }
+
+    /// A metal whose highlights stretch along the tangent when `along` is rougher than `across`.
+    pub fn anisotropic(fresnel: Fresnel, along: Float, across: Float) -> Material {
+        Arc::new(Conductor{ fresnel, alpha_x: along.max(0.0), alpha_y: across.max(0.0) })
+    }
+
+    /// A metal from its measured complex index of refraction, equally rough every way.
+    pub fn from_ior(eta: Color, k: Color, roughness: Float) -> Material {
+        Conductor::anisotropic(Fresnel::Complex{ eta, k }, roughness, roughness)
+    }
+
+    /// Smith's Λ for GGX, for a direction in the local frame.
+    fn lambda(&self, w: Vec3) -> Float {
+        let tan2 = ((self.alpha_x*w.x).powi(2) + (self.alpha_y*w.y).powi(2)) / (w.z*w.z);
+        0.5 * ((1.0 + tan2).sqrt() - 1.0)
+    }
+
+    /// A facet normal seen from `wo`, in the local frame, picked in proportion to how much of the
+    /// visible surface it covers.
+    fn sample_visible_normal(&self, wo: Vec3) -> Vec3 {
+        // Stretch the view so the facets form a hemisphere, pick a point on the disk it projects
+        // to, warped towards the part of the disk that isn't hidden, and stretch back.
+        let v = unit_vector(Vec3::new(self.alpha_x*wo.x, self.alpha_y*wo.y, wo.z));
+        let across = v.x*v.x + v.y*v.y;
+        let t1 = if across > 0.0 { Vec3::new(-v.y, v.x, 0.0) / across.sqrt() } else { Vec3::new(1.0, 0.0, 0.0) };
+        let t2 = cross(v, t1);
+
+        let r = rand::random::<Float>().sqrt();
+        let phi = 2.0 * PI * rand::random::<Float>();
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + v.z);
+        let p2 = (1.0 - s) * (1.0 - p1*p1).sqrt() + s * r * phi.sin();
+        let n = p1*t1 + p2*t2 + (1.0 - p1*p1 - p2*p2).max(0.0).sqrt()*v;
+        unit_vector(Vec3::new(self.alpha_x*n.x, self.alpha_y*n.y, n.z.max(1e-6)))
+    }
+}
+
+impl Scatter for Conductor {
+    fn scatter(&self, ray_in: Ray, hitrec: &HitRecord, attenuation: &mut Color, ray_scattered: &mut Ray) -> bool {
+        let tangent = hitrec.tangent - dot(hitrec.tangent, hitrec.normal)*hitrec.normal;
+        let frame = if tangent.near_zero() {
+            Onb::from_w(hitrec.normal)
+        } else {
+            let u = unit_vector(tangent);
+            Onb{ u, v: cross(hitrec.normal, u), w: hitrec.normal }
+        };
+
+        // Everything from here on is in the surface's frame, with z out of it.
+        let wo = frame.project(-unit_vector(ray_in.direction));
+        if wo.z <= 0.0 {
+            return false;
+        }
+        let smooth = self.alpha_x < SMOOTH && self.alpha_y < SMOOTH;
+        let facet = if smooth { Vec3::new(0.0, 0.0, 1.0) } else { self.sample_visible_normal(wo) };
+        let wi = reflect(-wo, facet);
+        if wi.z <= 0.0 {
+            return false;
+        }
+
+        // What's left of the Smith height-correlated masking-shadowing once the facets the
+        // incoming ray can't see have been left out of the sampling.
+        let shadowing = if smooth {
+            1.0
+        } else {
+            (1.0 + self.lambda(wo)) / (1.0 + self.lambda(wo) + self.lambda(wi))
+        };
+        *ray_scattered = hitrec.spawn_ray(frame.local(wi), ray_in.time);
+        attenuation.copy(shadowing * self.fresnel.reflectance(dot(wo, facet)));
+        true
+    }
+}
+
 pub struct Dielectric{
-    ior: f64
+    ior: Float
 }
 
 impl Dielectric {
-    pub fn new(ior: f64) -> Material {
+    pub fn new(ior: Float) -> Material {
         Arc::new(Dielectric{ ior })
     }
 }
 
 impl Scatter for Dielectric {
-    fn scatter(&self, ray_in: Ray, hitrec: &mut HitRecord, attenuation: &mut Color, ray_scattered: &mut Ray) -> bool {
+    fn scatter(&self, ray_in: Ray, hitrec: &HitRecord, attenuation: &mut Color, ray_scattered: &mut Ray) -> bool {
         attenuation.set(1.0, 1.0, 1.0);
         let refraction_ratio = if hitrec.front_face { 1.0 / self.ior } else { self.ior };
 
@@ -113,15 +256,13 @@ impl Scatter for Dielectric {
 
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
 
-        let direction = if cannot_refract || reflectance(cos_theta, refraction_ratio) > rand::random::<f64>() {
+        let direction = if cannot_refract || reflectance(cos_theta, refraction_ratio) > rand::random::<Float>() {
             reflect(unit_direction, hitrec.normal)
         } else {
             refract(unit_direction, hitrec.normal, refraction_ratio)
         };
 
-        ray_scattered.origin.copy(hitrec.point);
-        ray_scattered.direction.copy(direction);
-        ray_scattered.time = ray_in.time;
+        *ray_scattered = hitrec.spawn_ray(direction, ray_in.time);
         return true;
     }
 }
@@ -140,14 +281,14 @@ impl Scatter for DiffuseLight {
     fn scatter(
         &self,
         _ray_in: Ray,
-        _hitrec: &mut HitRecord,
+        _hitrec: &HitRecord,
         _attenuation: &mut Color,
         _ray_scattered: &mut Ray) -> bool {
 
         false
     }
 
-    fn emitted(&self, u: f64, v: f64, p: Point3) -> Color {
+    fn emitted(&self, u: Float, v: Float, p: Point3) -> Color {
         self.emit.value(u, v, p)
     }
 }
@@ -170,13 +311,158 @@ impl Scatter for Isotropic {
     fn scatter(
         &self,
         ray_in: Ray,
-        hitrec: &mut HitRecord,
+        hitrec: &HitRecord,
         attenuation: &mut Color,
         ray_scattered: &mut Ray) -> bool {
 
-        *ray_scattered = Ray::new(hitrec.point, Vec3::random_in_unit_sphere(), ray_in.time);
-        *attenuation = self.albedo.value(hitrec.u, hitrec.v, hitrec.point);
+        *ray_scattered = hitrec.spawn_ray(Vec3::random_in_unit_sphere(), ray_in.time);
+        *attenuation = self.albedo.value_at(hitrec);
 
         true
     }
 }
+
+/**
+ * Hair and fur, after Marschner et al. A fiber scatters light three ways: straight off the
+ * surface (R), in one side and out the other (TT), and in, off the back wall and out again (TRT).
+ * Only the two that pass through the fiber pick up its color, and the one that passes through
+ * twice picks up twice as much. Each leaves at roughly the angle it came in at along the fiber,
+ * reflected to the other side, with the scales on the fiber's surface tilting each lobe a little
+ * towards the root or the tip.
+ *
+ * This follows the fiber with the record's tangent, and takes v as where across the fiber the
+ * ray landed, both of which Curve fills in. Curves should run root to tip for the tilt to go the
+ * right way.
+ */
+pub struct Hair {
+    color: Color,
+    roughness: Float,
+    tilt: Float,
+    ior: Float,
+}
+
+impl Hair {
+    /**
+     * `color` is what's left of white light after going straight through a fiber once.
+     * `roughness` is how wide the lobes are in radians, and `tilt` is the angle of the scales in
+     * degrees, usually two or three.
+     */
+    pub fn new(color: Color, roughness: Float, tilt: Float) -> Material {
+        Arc::new(Hair{ color, roughness, tilt: tilt.to_radians(), ior: 1.55 })
+    }
+}
+
+impl Scatter for Hair {
+    fn scatter(&self, ray_in: Ray, hitrec: &HitRecord, attenuation: &mut Color, ray_scattered: &mut Ray) -> bool {
+        let tangent = if hitrec.tangent.near_zero() { Onb::from_w(hitrec.normal).u } else { hitrec.tangent };
+
+        // Back along the ray, split into along the fiber and around it.
+        let incoming = -unit_vector(ray_in.direction);
+        let sin_i = dot(incoming, tangent).clamp(-1.0, 1.0);
+        let cos_i = (1.0 - sin_i*sin_i).sqrt();
+        let around = incoming - sin_i*tangent;
+        let a = if around.near_zero() { hitrec.normal } else { unit_vector(around) };
+        let b = cross(tangent, a);
+
+        // Where across the fiber the ray landed, and the angles it meets and enters the surface at
+        // as seen looking down the fiber, with the index corrected for coming in at a slant.
+        let h = (2.0*hitrec.v - 1.0).clamp(-1.0, 1.0);
+        let gamma_i = h.asin();
+        let ior = (self.ior*self.ior - sin_i*sin_i).sqrt() / cos_i.max(1e-6);
+        let gamma_t = (h / ior).clamp(-1.0, 1.0).asin();
+        let fresnel = reflectance(cos_i * gamma_i.cos(), self.ior);
+
+        // Pick a lobe by how much light goes each way. One pass through the fiber at h takes a
+        // path cos(gamma_t) as long as one through the middle.
+        let absorbed = Color::new(
+            self.color.x.powf(gamma_t.cos()),
+            self.color.y.powf(gamma_t.cos()),
+            self.color.z.powf(gamma_t.cos()),
+        );
+        let (p, color, shift, width) = if rand::random::<Float>() < fresnel {
+            (0.0, Color::new(1.0, 1.0, 1.0), -2.0*self.tilt, self.roughness)
+        } else if rand::random::<Float>() >= fresnel {
+            (1.0, absorbed, self.tilt, 0.5*self.roughness)
+        } else {
+            (2.0, absorbed * absorbed, 3.0*self.tilt, 2.0*self.roughness)
+        };
+
+        // Along the fiber the light leaves mirrored, and around it turns by the angle a ray
+        // refracting p times through a circle would.
+        let theta = (-sin_i).asin() + shift + width*gaussian();
+        let phi = 2.0*p*gamma_t - 2.0*gamma_i + p*PI + width*gaussian();
+        let direction = theta.cos()*(phi.cos()*a + phi.sin()*b) + theta.sin()*tangent;
+
+        *ray_scattered = hitrec.spawn_ray(direction, ray_in.time);
+        attenuation.copy(color);
+        return true;
+    }
+}
+
+/// A standard normal sample, by the Box-Muller transform.
+fn gaussian() -> Float {
+    let u1 = 1.0 - rand::random::<Float>();
+    let u2 = rand::random::<Float>();
+    (-2.0*u1.ln()).sqrt() * (2.0*PI*u2).cos()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::float::Float;
+    use crate::hit::HitRecord;
+    use crate::material::{Conductor, Fresnel};
+    use crate::ray::Ray;
+    use crate::vec3::{Color, dot, Point3, unit_vector, Vec3};
+
+    #[test]
+    fn fresnel() {
+        // Head on, the exact equations come down to ((n - 1)^2 + k^2) / ((n + 1)^2 + k^2).
+        let (eta, k) = (Color::new(0.2, 0.9, 1.1), Color::new(3.9, 2.5, 2.1));
+        let head_on = Fresnel::Complex{ eta, k }.reflectance(1.0);
+        let expected = |n: Float, k: Float| ((n - 1.0).powi(2) + k*k) / ((n + 1.0).powi(2) + k*k);
+        assert!((head_on.x - expected(0.2, 3.9)).abs() < 1e-6 && (head_on.z - expected(1.1, 2.1)).abs() < 1e-6);
+        // Everything reflects all the light at grazing angles.
+        assert!((Fresnel::Complex{ eta, k }.reflectance(0.0).y - 1.0).abs() < 1e-6);
+        assert!((Fresnel::Schlick(Color::new(0.5, 0.5, 0.5)).reflectance(0.0).x - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn conductor_conserves_energy() {
+        // A white metal under a white sky, seen from overhead and at a slant.
+        let normal = Vec3::new(0.0, 1.0, 0.0);
+        let tangent = Vec3::new(1.0, 0.0, 0.0);
+        // Only the light that would bounce between the facets goes missing, which is hardly any for
+        // smooth metal and most of it for the roughest.
+        let cases = [(0.0, 0.0, 1.0), (0.05, 0.05, 0.98), (0.5, 0.5, 0.65), (1.0, 1.0, 0.28), (0.6, 0.05, 0.75)];
+        for (along, across, least) in cases {
+            let metal = Conductor::anisotropic(Fresnel::Schlick(Color::new(1.0, 1.0, 1.0)), along, across);
+            for direction in [Vec3::new(0.0, -1.0, 0.0), Vec3::new(1.0, -0.5, 0.3)] {
+                let ray = Ray::new(Point3::new(0.0, 1.0, 0.0) - direction, direction, 0.0);
+                let mut hitrec = HitRecord::new(ray, 1.0, normal, 0.0, 0.0, &metal);
+                hitrec.tangent = tangent;
+
+                let (mut total, mut spread) = (0.0, [0.0; 2]);
+                let samples = 10000;
+                for _ in 0..samples {
+                    let (mut attenuation, mut scattered) = (Color::default(), Ray::default());
+                    if metal.scatter(ray, &hitrec, &mut attenuation, &mut scattered) {
+                        let out = unit_vector(scattered.direction);
+                        assert!(dot(out, normal) > 0.0);
+                        assert!(attenuation.x <= 1.0 + 1e-6);
+                        total += attenuation.x;
+                        let mirror = unit_vector(direction) - 2.0*dot(unit_vector(direction), normal)*normal;
+                        let off = out - mirror;
+                        spread[0] += off.x.abs();
+                        spread[1] += off.z.abs();
+                    }
+                }
+                let albedo = total / samples as Float;
+                assert!(albedo >= least - 1e-6, "{} {} {}", along, across, albedo);
+                if along > across {
+                    assert!(spread[0] > 3.0 * spread[1]);
+                }
+            }
+        }
+    }
+}
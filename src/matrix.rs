@@ -0,0 +1,301 @@
+use crate::aabb::AABB;
+use crate::float::Float;
+use crate::quaternion::Quaternion;
+use crate::vec3::{cross, Point3, unit_vector, Vec3};
+use std::ops;
+
+/// A row-major 4x4 matrix for affine transforms. Points are treated as columns with w = 1.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Matrix4 {
+    pub m: [[Float; 4]; 4],
+}
+
+impl Default for Matrix4 {
+    fn default() -> Matrix4 {
+        Matrix4::identity()
+    }
+}
+
+impl Matrix4 {
+    pub fn new(m: [[Float; 4]; 4]) -> Matrix4 {
+        Matrix4{ m }
+    }
+
+    pub fn identity() -> Matrix4 {
+        Matrix4::new([
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn translate(offset: Vec3) -> Matrix4 {
+        Matrix4::new([
+            [1.0, 0.0, 0.0, offset.x],
+            [0.0, 1.0, 0.0, offset.y],
+            [0.0, 0.0, 1.0, offset.z],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn scale(factors: Vec3) -> Matrix4 {
+        Matrix4::new([
+            [factors.x, 0.0, 0.0, 0.0],
+            [0.0, factors.y, 0.0, 0.0],
+            [0.0, 0.0, factors.z, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn rotate_x(theta: Float) -> Matrix4 {
+        let (sin, cos) = theta.sin_cos();
+        Matrix4::new([
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, cos, -sin, 0.0],
+            [0.0, sin, cos, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn rotate_y(theta: Float) -> Matrix4 {
+        let (sin, cos) = theta.sin_cos();
+        Matrix4::new([
+            [cos, 0.0, sin, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [-sin, 0.0, cos, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn rotate_z(theta: Float) -> Matrix4 {
+        let (sin, cos) = theta.sin_cos();
+        Matrix4::new([
+            [cos, -sin, 0.0, 0.0],
+            [sin, cos, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    /// A counterclockwise rotation by theta radians about an arbitrary axis through the origin.
+    pub fn rotate(axis: Vec3, theta: Float) -> Matrix4 {
+        Matrix4::from_quaternion(Quaternion::from_axis_angle(axis, theta))
+    }
+
+    /// Euler angles in radians, applied about X first, then Y, then Z.
+    pub fn euler(x: Float, y: Float, z: Float) -> Matrix4 {
+        Matrix4::rotate_z(z) * Matrix4::rotate_y(y) * Matrix4::rotate_x(x)
+    }
+
+    pub fn from_quaternion(q: Quaternion) -> Matrix4 {
+        let q = q.normalized();
+        let (w, x, y, z) = (q.w, q.x, q.y, q.z);
+        Matrix4::new([
+            [1.0 - 2.0*(y*y + z*z), 2.0*(x*y - w*z), 2.0*(x*z + w*y), 0.0],
+            [2.0*(x*y + w*z), 1.0 - 2.0*(x*x + z*z), 2.0*(y*z - w*x), 0.0],
+            [2.0*(x*z - w*y), 2.0*(y*z + w*x), 1.0 - 2.0*(x*x + y*y), 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    /**
+     * Places an object at `from`, turned so that its +Z axis points at `to` and its +Y axis is as
+     * close to `up` as it can be. This is the object-to-world matrix, not a view matrix.
+     */
+    pub fn look_at(from: Point3, to: Point3, up: Vec3) -> Matrix4 {
+        let z = unit_vector(to - from);
+        let x = unit_vector(cross(up, z));
+        let y = cross(z, x);
+        Matrix4::new([
+            [x.x, y.x, z.x, from.x],
+            [x.y, y.y, z.y, from.y],
+            [x.z, y.z, z.z, from.z],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    /// Gauss-Jordan elimination with partial pivoting. Returns None for singular matrices.
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let mut a = self.m;
+        let mut inv = Matrix4::identity().m;
+
+        for col in 0..4 {
+            let pivot = (col..4)
+                .max_by(|&i, &j| a[i][col].abs().partial_cmp(&a[j][col].abs()).unwrap())
+                .unwrap();
+            if a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let scale = 1.0 / a[col][col];
+            for j in 0..4 {
+                a[col][j] *= scale;
+                inv[col][j] *= scale;
+            }
+
+            for row in 0..4 {
+                if row != col {
+                    let factor = a[row][col];
+                    for j in 0..4 {
+                        a[row][j] -= factor * a[col][j];
+                        inv[row][j] -= factor * inv[col][j];
+                    }
+                }
+            }
+        }
+
+        Some(Matrix4::new(inv))
+    }
+
+    pub fn transform_point(&self, p: Point3) -> Point3 {
+        let m = &self.m;
+        Point3::new(
+            m[0][0]*p.x + m[0][1]*p.y + m[0][2]*p.z + m[0][3],
+            m[1][0]*p.x + m[1][1]*p.y + m[1][2]*p.z + m[1][3],
+            m[2][0]*p.x + m[2][1]*p.y + m[2][2]*p.z + m[2][3],
+        )
+    }
+
+    pub fn transform_vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0]*v.x + m[0][1]*v.y + m[0][2]*v.z,
+            m[1][0]*v.x + m[1][1]*v.y + m[1][2]*v.z,
+            m[2][0]*v.x + m[2][1]*v.y + m[2][2]*v.z,
+        )
+    }
+
+    /**
+     * Normals have to go through the inverse transpose to stay perpendicular to their surface
+     * under non-uniform scaling. This multiplies by the transpose, so call it on the inverse of
+     * the matrix the points went through. The result isn't normalized.
+     */
+    pub fn transform_normal(&self, n: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0]*n.x + m[1][0]*n.y + m[2][0]*n.z,
+            m[0][1]*n.x + m[1][1]*n.y + m[2][1]*n.z,
+            m[0][2]*n.x + m[1][2]*n.y + m[2][2]*n.z,
+        )
+    }
+
+    /// The tightest box around a transformed box, following Arvo's method of taking the extremes
+    /// of each matrix term separately rather than transforming all eight corners.
+    pub fn transform_box(&self, aabb: AABB) -> AABB {
+        let min = aabb.min.as_array();
+        let max = aabb.max.as_array();
+        let mut new_min = [0.0; 3];
+        let mut new_max = [0.0; 3];
+
+        for i in 0..3 {
+            new_min[i] = self.m[i][3];
+            new_max[i] = self.m[i][3];
+            for j in 0..3 {
+                let a = self.m[i][j] * min[j];
+                let b = self.m[i][j] * max[j];
+                new_min[i] += a.min(b);
+                new_max[i] += a.max(b);
+            }
+        }
+
+        AABB::new(Point3::from_array(new_min), Point3::from_array(new_max))
+    }
+}
+
+impl ops::Mul<Matrix4> for Matrix4 {
+    type Output = Matrix4;
+
+    fn mul(self, rhs: Matrix4) -> Matrix4 {
+        let mut product = [[0.0; 4]; 4];
+        for (i, row) in product.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.m[i][k] * rhs.m[k][j]).sum();
+            }
+        }
+        Matrix4::new(product)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::aabb::AABB;
+    use crate::float::Float;
+    use crate::matrix::Matrix4;
+    use crate::quaternion::Quaternion;
+    use crate::vec3::{cross, dot, Point3, unit_vector, Vec3};
+
+    /// How close results have to be, which f32 can't get to f64's 1e-9.
+    #[cfg(not(feature = "f32"))]
+    const TOLERANCE: Float = 1e-9;
+    #[cfg(feature = "f32")]
+    const TOLERANCE: Float = 1e-4;
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < TOLERANCE
+    }
+
+    #[test]
+    fn inverse() {
+        let m = Matrix4::translate(Vec3::new(1.0, 2.0, 3.0))
+            * Matrix4::euler(0.3, -1.2, 2.0)
+            * Matrix4::scale(Vec3::new(2.0, 0.5, 3.0));
+        let identity = m * m.inverse().unwrap();
+        for i in 0..4 {
+            for j in 0..4 {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((identity.m[i][j] - expected).abs() < TOLERANCE);
+            }
+        }
+
+        assert!(Matrix4::scale(Vec3::new(1.0, 0.0, 1.0)).inverse().is_none());
+    }
+
+    #[test]
+    fn rotations_agree() {
+        let p = Point3::new(1.0, 2.0, 3.0);
+        let axis = Vec3::new(0.0, 1.0, 0.0);
+        let a = Matrix4::rotate_y(0.7).transform_point(p);
+        let b = Matrix4::rotate(axis, 0.7).transform_point(p);
+        let c = Matrix4::from_quaternion(Quaternion::from_axis_angle(axis, 0.7)).transform_point(p);
+        let d = Matrix4::euler(0.0, 0.7, 0.0).transform_point(p);
+        assert!(close(a, b) && close(a, c) && close(a, d));
+
+        // A quarter turn about Z takes X to Y.
+        let x = Matrix4::rotate_z(crate::float::consts::FRAC_PI_2).transform_vector(Vec3::new(1.0, 0.0, 0.0));
+        assert!(close(x, Vec3::new(0.0, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn normals_stay_perpendicular() {
+        let m = Matrix4::rotate_x(0.4) * Matrix4::scale(Vec3::new(4.0, 1.0, 0.25));
+        let inverse = m.inverse().unwrap();
+
+        let tangent = Vec3::new(1.0, 1.0, 0.0);
+        let normal = Vec3::new(1.0, -1.0, 0.0);
+        let tangent = m.transform_vector(tangent);
+        let normal = inverse.transform_normal(normal);
+        assert!(dot(tangent, normal).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn look_at_and_boxes() {
+        let from = Point3::new(1.0, 1.0, 1.0);
+        let to = Point3::new(4.0, 5.0, 1.0);
+        let m = Matrix4::look_at(from, to, Vec3::new(0.0, 0.0, 1.0));
+        assert!(close(m.transform_point(Point3::default()), from));
+        assert!(close(m.transform_vector(Vec3::new(0.0, 0.0, 1.0)), unit_vector(to - from)));
+        let x = m.transform_vector(Vec3::new(1.0, 0.0, 0.0));
+        let y = m.transform_vector(Vec3::new(0.0, 1.0, 0.0));
+        assert!(close(cross(x, y), unit_vector(to - from)));
+
+        let unit = AABB::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
+        let rotated = Matrix4::rotate_z(crate::float::consts::FRAC_PI_4).transform_box(unit);
+        let r = Float::sqrt(2.0);
+        assert!(close(rotated.min, Point3::new(-r, -r, -1.0)));
+        assert!(close(rotated.max, Point3::new(r, r, 1.0)));
+    }
+}
@@ -0,0 +1,312 @@
+use crate::aabb::AABB;
+use crate::bvh::BVHNode;
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitRecord};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::triangle::{intersect_triangle, shading_normal, triangle_box};
+use crate::vec3::{Color, cross, dot, Point3, unit_vector, Vec3};
+use std::{error, fmt, io};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Meshes with at least this many triangles keep their BVH on disk between runs, in the cache
+/// directory. Reading a tree back is several times quicker than building it, but smaller meshes
+/// build in a few hundredths of a second, which isn't worth a file each.
+const CACHED_TRIANGLES: usize = 50_000;
+
+/// Where big meshes' trees are kept, relative to where the renderer runs. It can be deleted at any
+/// time, and trees for geometry that has since changed are never read again.
+const CACHE_DIRECTORY: &str = "cache";
+
+/// One triangle of a mesh. Each attribute has its own set of indices into the mesh buffers, the
+/// way Wavefront OBJ files store them.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Face {
+    pub vertices: [usize; 3],
+    pub normals: Option<[usize; 3]>,
+    pub uvs: Option<[usize; 3]>,
+    pub material: usize,
+}
+
+struct MeshData {
+    positions: Vec<Point3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(Float, Float)>,
+    colors: Vec<Color>,
+    faces: Vec<Face>,
+    materials: Vec<Material>,
+}
+
+/**
+ * An indexed triangle mesh. The vertex, normal, UV and color buffers are shared between all the
+ * faces and the mesh builds a BVH over its own triangles, so a whole model can be added to a scene
+ * as a single object.
+ *
+ * For animation, place a mesh with Instances and put those in a BVHNode of their own. When only
+ * the placements change, that top-level tree over a handful of instances is cheap to build again
+ * each frame, and the meshes' trees are left alone. A mesh whose vertices move can be deformed,
+ * which refits its tree rather than building it again.
+ */
+pub struct TriangleMesh {
+    data: Arc<MeshData>,
+    bvh: BVHNode,
+}
+
+impl TriangleMesh {
+    /// Faces must have at least one entry and all their indices must be in range. Colors are
+    /// optional, but when given there must be one per position. They're interpolated into
+    /// `HitRecord::color` for the VertexColor texture.
+    pub fn new(
+        positions: Vec<Point3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<(Float, Float)>,
+        colors: Vec<Color>,
+        faces: Vec<Face>,
+        materials: Vec<Material>,
+    ) -> HitArc {
+        Arc::new(TriangleMesh::deformable(positions, normals, uvs, colors, faces, materials))
+    }
+
+    /// Like `new`, but keeps the mesh to hand so that it can be deformed. It can go in a scene once
+    /// it's in an Arc, and `Arc::get_mut` gets it back when the scene is done with it.
+    pub fn deformable(
+        positions: Vec<Point3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<(Float, Float)>,
+        colors: Vec<Color>,
+        faces: Vec<Face>,
+        materials: Vec<Material>,
+    ) -> TriangleMesh {
+        let data = Arc::new(MeshData{ positions, normals, uvs, colors, faces, materials });
+        let triangles = MeshTriangle::all(&data);
+        let bvh = if triangles.len() >= CACHED_TRIANGLES {
+            BVHNode::cached(&triangles, 0.0, 1.0, Path::new(CACHE_DIRECTORY))
+        } else {
+            BVHNode::from_vec(&triangles, 0, triangles.len(), 0.0, 1.0)
+        };
+        TriangleMesh{ data, bvh }
+    }
+
+    /// Moves the vertices, and their normals if the mesh has any, keeping the faces. Panics unless
+    /// there are as many of each as before.
+    pub fn deform(&mut self, positions: Vec<Point3>, normals: Vec<Vec3>) {
+        let old = &self.data;
+        assert!(positions.len() == old.positions.len() && normals.len() == old.normals.len(),
+            "A deformed mesh needs the same number of positions and normals");
+        self.data = Arc::new(MeshData{
+            positions,
+            normals,
+            uvs: old.uvs.clone(),
+            colors: old.colors.clone(),
+            faces: old.faces.clone(),
+            materials: old.materials.clone(),
+        });
+        let triangles = MeshTriangle::all(&self.data);
+        self.bvh.refit(&triangles, 0.0, 1.0);
+    }
+}
+
+impl Hit for TriangleMesh {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        self.bvh.hit(ray, t_min, t_max)
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        self.bvh.occluded(ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self, time0: Float, time1: Float) -> Option<AABB> {
+        self.bvh.bounding_box(time0, time1)
+    }
+}
+
+struct MeshTriangle {
+    mesh: Arc<MeshData>,
+    face: usize,
+}
+
+impl MeshTriangle {
+    fn all(mesh: &Arc<MeshData>) -> Vec<HitArc> {
+        (0..mesh.faces.len())
+            .map(|face| Arc::new(MeshTriangle{ mesh: Arc::clone(mesh), face }) as HitArc)
+            .collect()
+    }
+
+    fn vertices(&self) -> [Point3; 3] {
+        let face = &self.mesh.faces[self.face];
+        [
+            self.mesh.positions[face.vertices[0]],
+            self.mesh.positions[face.vertices[1]],
+            self.mesh.positions[face.vertices[2]],
+        ]
+    }
+}
+
+impl Hit for MeshTriangle {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let [p0, p1, p2] = self.vertices();
+        let (t, b) = intersect_triangle(ray, p0, p1, p2, t_min, t_max)?;
+
+        let mesh = &self.mesh;
+        let face = &mesh.faces[self.face];
+
+        let (u, v) = match face.uvs {
+            Some(uv) => {
+                let (uv0, uv1, uv2) = (mesh.uvs[uv[0]], mesh.uvs[uv[1]], mesh.uvs[uv[2]]);
+                (b[0]*uv0.0 + b[1]*uv1.0 + b[2]*uv2.0, b[0]*uv0.1 + b[1]*uv1.1 + b[2]*uv2.1)
+            }
+            None => (b[1] + b[2], b[2]),
+        };
+
+        let mut hitrec = HitRecord::new(ray, t, unit_vector(cross(p1 - p0, p2 - p0)), u, v,
+            &mesh.materials[face.material]);
+        hitrec.point = b[0]*p0 + b[1]*p1 + b[2]*p2;
+        if let Some(n) = face.normals {
+            let interpolated = b[0]*mesh.normals[n[0]] + b[1]*mesh.normals[n[1]] + b[2]*mesh.normals[n[2]];
+            hitrec.normal = shading_normal(hitrec.normal, interpolated);
+        }
+
+        if !mesh.colors.is_empty() {
+            let v = face.vertices;
+            hitrec.color = Some(b[0]*mesh.colors[v[0]] + b[1]*mesh.colors[v[1]] + b[2]*mesh.colors[v[2]]);
+        }
+
+        Some(hitrec)
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        let [p0, p1, p2] = self.vertices();
+        intersect_triangle(ray, p0, p1, p2, t_min, t_max).is_some()
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        let [p0, p1, p2] = self.vertices();
+        Some(triangle_box(p0, p1, p2))
+    }
+}
+
+/**
+ * Splits a polygon into triangles by ear clipping in the plane of its Newell normal, so concave
+ * faces come out right. Falls back to a fan for whatever is left if the polygon is too degenerate
+ * to find an ear.
+ */
+pub fn triangulate(points: &[Point3]) -> Vec<[usize; 3]> {
+    let n = points.len();
+    if n == 3 {
+        return vec![[0, 1, 2]];
+    }
+
+    let mut normal = Vec3::default();
+    for i in 0..n {
+        let (a, b) = (points[i], points[(i + 1) % n]);
+        normal += Vec3::new((a.y - b.y)*(a.z + b.z), (a.z - b.z)*(a.x + b.x), (a.x - b.x)*(a.y + b.y));
+    }
+
+    let convex = |a: Point3, b: Point3, c: Point3| dot(cross(b - a, c - b), normal) > 0.0;
+    let inside = |p: Point3, a: Point3, b: Point3, c: Point3| {
+        dot(cross(b - a, p - a), normal) >= 0.0 &&
+        dot(cross(c - b, p - b), normal) >= 0.0 &&
+        dot(cross(a - c, p - c), normal) >= 0.0
+    };
+
+    let mut remaining: Vec<usize> = (0..n).collect();
+    let mut triangles = Vec::with_capacity(n - 2);
+
+    'clipping: while remaining.len() > 3 {
+        let m = remaining.len();
+        for i in 0..m {
+            let (ia, ib, ic) = (remaining[(i + m - 1) % m], remaining[i], remaining[(i + 1) % m]);
+            let (a, b, c) = (points[ia], points[ib], points[ic]);
+            if !convex(a, b, c) {
+                continue;
+            }
+            let blocked = remaining.iter()
+                .filter(|&&j| j != ia && j != ib && j != ic)
+                .any(|&j| inside(points[j], a, b, c));
+            if !blocked {
+                triangles.push([ia, ib, ic]);
+                remaining.remove(i);
+                continue 'clipping;
+            }
+        }
+        break;
+    }
+
+    for i in 1..remaining.len() - 1 {
+        triangles.push([remaining[0], remaining[i], remaining[i + 1]]);
+    }
+
+    triangles
+}
+
+/// Errors from reading mesh files.
+#[derive(Debug)]
+pub enum MeshError {
+    Io(io::Error),
+    Image(image::ImageError),
+    Parse { path: String, line: usize, message: String },
+}
+
+impl MeshError {
+    pub fn parse(path: &str, line: usize, message: impl Into<String>) -> MeshError {
+        MeshError::Parse{ path: path.to_string(), line, message: message.into() }
+    }
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MeshError::Io(error) => write!(f, "{}", error),
+            MeshError::Image(error) => write!(f, "{}", error),
+            MeshError::Parse{ path, line: 0, message } => write!(f, "{}: {}", path, message),
+            MeshError::Parse{ path, line, message } => write!(f, "{}:{}: {}", path, line, message),
+        }
+    }
+}
+
+impl error::Error for MeshError {}
+
+impl From<io::Error> for MeshError {
+    fn from(error: io::Error) -> MeshError {
+        MeshError::Io(error)
+    }
+}
+
+impl From<image::ImageError> for MeshError {
+    fn from(error: image::ImageError) -> MeshError {
+        MeshError::Image(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::float::Float;
+    use crate::mesh::triangulate;
+    use crate::vec3::Point3;
+
+    #[test]
+    fn concave_polygon() {
+        // An L shape starting next to the notch, where a fan from the first vertex would cover
+        // area outside the polygon.
+        let points = [
+            Point3::new(2.0, 1.0, 0.0),
+            Point3::new(1.0, 1.0, 0.0),
+            Point3::new(1.0, 2.0, 0.0),
+            Point3::new(0.0, 2.0, 0.0),
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(2.0, 0.0, 0.0),
+        ];
+        let triangles = triangulate(&points);
+        assert_eq!(triangles.len(), 4);
+
+        let areas: Vec<Float> = triangles.iter().map(|&[a, b, c]| {
+            let (a, b, c) = (points[a], points[b], points[c]);
+            0.5 * ((b.x - a.x)*(c.y - a.y) - (c.x - a.x)*(b.y - a.y))
+        }).collect();
+        assert!(areas.iter().all(|&area| area > 0.0));
+        let area: Float = areas.iter().sum();
+        assert!((area - 3.0).abs() < 1e-12);
+    }
+}
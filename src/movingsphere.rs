@@ -1,64 +1,53 @@
 use crate::aabb::{AABB, surrounding_box};
+use crate::float::Float;
 use crate::hit::{Hit, HitArc, HitRecord};
 use crate::material::Material;
 use crate::ray::Ray;
-use crate::vec3::{dot, Point3, Vec3};
+use crate::sphere::{get_sphere_uv, sphere_hit};
+use crate::vec3::{Point3, unit_vector, Vec3};
 use std::sync::Arc;
 
 pub struct MovingSphere {
     center0: Point3, // center at time0
     center1: Point3, // center at time1
-    time0: f64,
-    time1: f64,
-    radius: f64,
+    time0: Float,
+    time1: Float,
+    radius: Float,
     material: Material,
 }
 
 impl MovingSphere {
     pub fn new(
         center0: Point3, center1: Point3,
-        time0: f64, time1: f64,
-        radius: f64,
+        time0: Float, time1: Float,
+        radius: Float,
         material: Material
     ) -> HitArc {
         Arc::new(MovingSphere { center0, center1, time0, time1, radius, material })
     }
 
-    fn center(&self, time: f64) -> Point3 {
+    fn center(&self, time: Float) -> Point3 {
         self.center0 + ((time - self.time0) / (self.time1 - self.time0)*(self.center1 - self.center0))
     }
 }
 
 impl Hit for MovingSphere {
-    fn hit(&self, ray: Ray, t_min: f64, t_max: f64, hitrec: &mut HitRecord) -> bool {
-        let oc = ray.origin - self.center(ray.time);
-        let a = ray.direction.length_squared();
-        let half_b = dot(oc, ray.direction);
-        let c = oc.length_squared() - self.radius*self.radius;
-
-        let discriminant = half_b*half_b - a*c;
-        if discriminant < 0. {
-            return false;
-        }
-
-        let sqrtd = discriminant.sqrt();
-        let root = (-half_b - sqrtd) / a;
-        if root < t_min || t_max < root {
-            return false;
-        }
-
-        hitrec.t = root;
-        hitrec.point = ray.at(hitrec.t);
-        let outward_normal = (hitrec.point - self.center(ray.time)) / self.radius;
-        hitrec.set_face_normal(ray, outward_normal);
-
-        // Note that we're cloning the enclosing Arc, not the material itself.
-        hitrec.material = Some(self.material.clone());
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let root = sphere_hit(self.center(ray.time), self.radius, ray, t_min, t_max)?;
+        let center = self.center(ray.time);
+        let outward_normal = unit_vector(ray.at(root) - center);
+        let (mut u, mut v) = (0.0, 0.0);
+        get_sphere_uv(outward_normal, &mut u, &mut v);
+        let mut hitrec = HitRecord::new(ray, root, outward_normal, u, v, &self.material);
+        hitrec.point = center + self.radius*outward_normal;
+        Some(hitrec)
+    }
 
-        true
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        sphere_hit(self.center(ray.time), self.radius, ray, t_min, t_max).is_some()
     }
 
-    fn bounding_box(&self, time0: f64, time1: f64, aabb: &mut AABB) -> bool {
+    fn bounding_box(&self, time0: Float, time1: Float) -> Option<AABB> {
         let box0 = AABB::new(
             self.center(time0) - Vec3::new(self.radius, self.radius, self.radius),
             self.center(time0) + Vec3::new(self.radius, self.radius, self.radius),
@@ -67,7 +56,6 @@ impl Hit for MovingSphere {
             self.center(time1) - Vec3::new(self.radius, self.radius, self.radius),
             self.center(time1) + Vec3::new(self.radius, self.radius, self.radius),
         );
-        *aabb = surrounding_box(box0, box1);
-        true // indicates we have a bouding box (infinite planes don't)
+        Some(surrounding_box(box0, box1))
     }
 }
@@ -0,0 +1,417 @@
+use crate::float::Float;
+use crate::hit::{HitArc, HitList};
+use crate::material::{Conductor, Dielectric, DiffuseLight, Lambertian, Material};
+use crate::mesh::{Face, MeshError, TriangleMesh, triangulate};
+use crate::texture::{Image, SolidColor, Texture};
+use crate::vec3::{Color, Point3, Vec3};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+/// A corner of a polygon as position, UV and normal indices into the file-wide buffers.
+type Corner = (usize, Option<usize>, Option<usize>);
+
+struct Polygon {
+    corners: Vec<Corner>,
+    material: usize,
+}
+
+/**
+ * Loads a Wavefront OBJ file. Every group (`g` or `o`) becomes its own TriangleMesh in the
+ * returned list. Materials from `mtllib` files are mapped onto the closest material we have, and
+ * faces that come before any `usemtl` get the default material.
+ */
+pub fn load(filename: &str, default_material: Material) -> Result<HitList, MeshError> {
+    let reader = BufReader::new(File::open(filename)?);
+    let directory = Path::new(filename).parent().unwrap_or_else(|| Path::new(""));
+
+    parse(reader, filename, default_material, |mtllib| {
+        let path = directory.join(mtllib);
+        let path = path.to_string_lossy();
+        load_mtl(&path)
+    })
+}
+
+fn parse<R, F>(reader: R, filename: &str, default_material: Material, mut mtllib: F) -> Result<HitList, MeshError>
+where
+    R: BufRead,
+    F: FnMut(&str) -> Result<HashMap<String, Material>, MeshError>,
+{
+    let mut positions: Vec<Point3> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut uvs: Vec<(Float, Float)> = Vec::new();
+
+    let mut library: HashMap<String, Material> = HashMap::new();
+    let mut materials: Vec<Material> = vec![default_material];
+    let mut material_indices: HashMap<String, usize> = HashMap::new();
+    let mut current_material = 0;
+
+    let mut groups: Vec<Vec<Polygon>> = vec![Vec::new()];
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let number = number + 1;
+        let error = |message: String| MeshError::parse(filename, number, message);
+
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+
+        match keyword {
+            "v" => {
+                let v = parse_floats(&args, 3, filename, number)?;
+                positions.push(Point3::new(v[0], v[1], v[2]));
+            }
+            "vn" => {
+                let v = parse_floats(&args, 3, filename, number)?;
+                normals.push(Vec3::new(v[0], v[1], v[2]));
+            }
+            "vt" => {
+                let v = parse_floats(&args, 1, filename, number)?;
+                uvs.push((v[0], if v.len() > 1 { v[1] } else { 0.0 }));
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(error(format!("face needs at least 3 vertices, found {}", args.len())));
+                }
+                let mut corners = Vec::with_capacity(args.len());
+                for arg in &args {
+                    let mut parts = arg.split('/');
+                    let v = parse_index(parts.next(), positions.len(), filename, number)?
+                        .ok_or_else(|| error(format!("face vertex '{}' has no position", arg)))?;
+                    let vt = parse_index(parts.next(), uvs.len(), filename, number)?;
+                    let vn = parse_index(parts.next(), normals.len(), filename, number)?;
+                    corners.push((v, vt, vn));
+                }
+                let group = groups.last_mut().unwrap();
+                group.push(Polygon{ corners, material: current_material });
+            }
+            "g" | "o" => {
+                groups.push(Vec::new());
+            }
+            "mtllib" => {
+                for lib in &args {
+                    library.extend(mtllib(lib)?);
+                }
+            }
+            "usemtl" => {
+                let name = args.join(" ");
+                current_material = match material_indices.get(&name) {
+                    Some(&index) => index,
+                    None => {
+                        let material = library.get(&name)
+                            .ok_or_else(|| error(format!("unknown material '{}'", name)))?;
+                        materials.push(Arc::clone(material));
+                        material_indices.insert(name, materials.len() - 1);
+                        materials.len() - 1
+                    }
+                };
+            }
+            // Smoothing groups, line elements and the like don't mean anything to us.
+            _ => {}
+        }
+    }
+
+    let mut list = HitList::default();
+    for group in groups {
+        if !group.is_empty() {
+            list.add(build_group(&group, &positions, &normals, &uvs, &materials));
+        }
+    }
+
+    if list.objects.is_empty() {
+        return Err(MeshError::parse(filename, 0, "no faces found"));
+    }
+
+    Ok(list)
+}
+
+/// Triangulates the polygons of one group and compacts the buffers so that each mesh only holds
+/// the data it uses.
+fn build_group(
+    polygons: &[Polygon],
+    positions: &[Point3],
+    normals: &[Vec3],
+    uvs: &[(Float, Float)],
+    materials: &[Material],
+) -> HitArc {
+    let mut position_map: HashMap<usize, usize> = HashMap::new();
+    let mut normal_map: HashMap<usize, usize> = HashMap::new();
+    let mut uv_map: HashMap<usize, usize> = HashMap::new();
+
+    let mut mesh_positions = Vec::new();
+    let mut mesh_normals = Vec::new();
+    let mut mesh_uvs = Vec::new();
+
+    let mut material_map: HashMap<usize, usize> = HashMap::new();
+    let mut mesh_materials = Vec::new();
+
+    let mut faces = Vec::new();
+
+    for polygon in polygons {
+        let points: Vec<Point3> = polygon.corners.iter().map(|c| positions[c.0]).collect();
+        let has_uvs = polygon.corners.iter().all(|c| c.1.is_some());
+        let has_normals = polygon.corners.iter().all(|c| c.2.is_some());
+
+        let material = *material_map.entry(polygon.material).or_insert_with(|| {
+            mesh_materials.push(Arc::clone(&materials[polygon.material]));
+            mesh_materials.len() - 1
+        });
+
+        for [a, b, c] in triangulate(&points) {
+            let corners = [polygon.corners[a], polygon.corners[b], polygon.corners[c]];
+            let mut face = Face{ material, ..Face::default() };
+
+            for (k, corner) in corners.iter().enumerate() {
+                face.vertices[k] = remap(corner.0, &mut position_map, &mut mesh_positions, positions);
+            }
+            if has_uvs {
+                let mut indices = [0; 3];
+                for (k, corner) in corners.iter().enumerate() {
+                    indices[k] = remap(corner.1.unwrap(), &mut uv_map, &mut mesh_uvs, uvs);
+                }
+                face.uvs = Some(indices);
+            }
+            if has_normals {
+                let mut indices = [0; 3];
+                for (k, corner) in corners.iter().enumerate() {
+                    indices[k] = remap(corner.2.unwrap(), &mut normal_map, &mut mesh_normals, normals);
+                }
+                face.normals = Some(indices);
+            }
+
+            faces.push(face);
+        }
+    }
+
+    TriangleMesh::new(mesh_positions, mesh_normals, mesh_uvs, Vec::new(), faces, mesh_materials)
+}
+
+fn remap<T: Copy>(index: usize, map: &mut HashMap<usize, usize>, dest: &mut Vec<T>, src: &[T]) -> usize {
+    *map.entry(index).or_insert_with(|| {
+        dest.push(src[index]);
+        dest.len() - 1
+    })
+}
+
+fn parse_floats(args: &[&str], min: usize, filename: &str, line: usize) -> Result<Vec<Float>, MeshError> {
+    if args.len() < min {
+        return Err(MeshError::parse(filename, line, format!(
+            "expected at least {} numbers, found {}", min, args.len())));
+    }
+    args.iter()
+        .map(|arg| arg.parse::<Float>().map_err(|_| MeshError::parse(
+            filename, line, format!("'{}' is not a number", arg))))
+        .collect()
+}
+
+/// Resolves a 1-based (or negative, relative to the end) OBJ index against the current count.
+fn parse_index(token: Option<&str>, count: usize, filename: &str, line: usize) -> Result<Option<usize>, MeshError> {
+    let token = match token {
+        Some(token) if !token.is_empty() => token,
+        _ => return Ok(None),
+    };
+    let index: i64 = token.parse().map_err(|_| MeshError::parse(
+        filename, line, format!("'{}' is not an index", token)))?;
+
+    let resolved = if index > 0 { index - 1 } else { count as i64 + index };
+    if index == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(MeshError::parse(filename, line, format!(
+            "index {} is out of range, there are {} elements", index, count)));
+    }
+
+    Ok(Some(resolved as usize))
+}
+
+#[derive(Default)]
+struct MtlSpec {
+    kd: Color,
+    ks: Color,
+    ke: Color,
+    ns: Float,
+    ni: Float,
+    dissolve: Float,
+    illum: u32,
+    map_kd: Option<String>,
+    map_ke: Option<String>,
+}
+
+/**
+ * Reads a Wavefront MTL file and maps each entry onto one of our materials. Emissive entries
+ * become DiffuseLight, transparent ones Dielectric, mirror-like ones Conductor and everything
+ * else Lambertian. Diffuse and emission maps are loaded as Image textures.
+ */
+pub fn load_mtl(filename: &str) -> Result<HashMap<String, Material>, MeshError> {
+    let reader = BufReader::new(File::open(filename)?);
+    let directory = Path::new(filename).parent().unwrap_or_else(|| Path::new(""));
+
+    let mut specs: Vec<(String, MtlSpec)> = Vec::new();
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let number = number + 1;
+
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let args: Vec<&str> = tokens.collect();
+
+        if keyword == "newmtl" {
+            specs.push((args.join(" "), MtlSpec{ ni: 1.0, dissolve: 1.0, ..MtlSpec::default() }));
+            continue;
+        }
+
+        let spec = match specs.last_mut() {
+            Some((_, spec)) => spec,
+            None => return Err(MeshError::parse(filename, number, format!(
+                "'{}' before any newmtl", keyword))),
+        };
+
+        let color = |args: &[&str]| -> Result<Color, MeshError> {
+            let v = parse_floats(args, 1, filename, number)?;
+            Ok(if v.len() >= 3 { Color::new(v[0], v[1], v[2]) } else { Color::new(v[0], v[0], v[0]) })
+        };
+        let map = |args: &[&str]| -> Result<String, MeshError> {
+            // Option flags come before the file name, so the name is the last token.
+            args.last()
+                .map(|name| directory.join(name).to_string_lossy().into_owned())
+                .ok_or_else(|| MeshError::parse(filename, number, "missing texture file name"))
+        };
+
+        match keyword {
+            "Kd" => spec.kd = color(&args)?,
+            "Ks" => spec.ks = color(&args)?,
+            "Ke" => spec.ke = color(&args)?,
+            "Ns" => spec.ns = parse_floats(&args, 1, filename, number)?[0],
+            "Ni" => spec.ni = parse_floats(&args, 1, filename, number)?[0],
+            "d" => spec.dissolve = parse_floats(&args, 1, filename, number)?[0],
+            "Tr" => spec.dissolve = 1.0 - parse_floats(&args, 1, filename, number)?[0],
+            "illum" => spec.illum = parse_floats(&args, 1, filename, number)?[0] as u32,
+            "map_Kd" => spec.map_kd = Some(map(&args)?),
+            "map_Ke" => spec.map_ke = Some(map(&args)?),
+            _ => {}
+        }
+    }
+
+    let mut materials = HashMap::new();
+    for (name, spec) in specs {
+        materials.insert(name, mtl_material(&spec)?);
+    }
+    Ok(materials)
+}
+
+fn mtl_material(spec: &MtlSpec) -> Result<Material, MeshError> {
+    let texture = |map: &Option<String>, color: Color| -> Result<Texture, MeshError> {
+        match map {
+            Some(path) => Ok(Image::open(path)?),
+            None => Ok(SolidColor::new(color)),
+        }
+    };
+
+    let emissive = spec.ke.x > 0.0 || spec.ke.y > 0.0 || spec.ke.z > 0.0 || spec.map_ke.is_some();
+    let transparent = spec.dissolve < 1.0 || matches!(spec.illum, 4 | 6 | 7 | 9);
+    let black = |c: Color| c.x <= 0.0 && c.y <= 0.0 && c.z <= 0.0;
+    let mirror = matches!(spec.illum, 3 | 5 | 8) || (black(spec.kd) && !black(spec.ks));
+
+    Ok(if emissive {
+        DiffuseLight::new(texture(&spec.map_ke, spec.ke)?)
+    } else if transparent {
+        Dielectric::new(spec.ni)
+    } else if mirror {
+        // Map the Phong exponent onto a roughness the way it's usually converted to Beckmann.
+        Conductor::new(spec.ks, (2.0 / (spec.ns + 2.0)).sqrt())
+    } else {
+        Lambertian::new(texture(&spec.map_kd, spec.kd)?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::float::Float;
+    use crate::hit::{Hit, HitList};
+    use crate::material::Lambertian;
+    use crate::mesh::MeshError;
+    use crate::obj::parse;
+    use crate::ray::Ray;
+    use crate::vec3::{Color, Point3, Vec3};
+    use std::collections::HashMap;
+
+    fn parse_str(text: &str) -> Result<HitList, MeshError> {
+        let default = Lambertian::from_color(Color::new(0.5, 0.5, 0.5));
+        parse(text.as_bytes(), "test.obj", default, |_| Ok(HashMap::new()))
+    }
+
+    #[test]
+    fn quad_with_negative_indices() {
+        let list = parse_str("
+            v -1 -1 0
+            v  1 -1 0
+            v  1  1 0
+            v -1  1 0
+            vt 0 0
+            vt 1 0
+            vt 1 1
+            vt 0 1
+            vn 0 0 1
+            f -4/-4/1 -3/-3/1 -2/-2/1 -1/-1/1
+        ").unwrap();
+        assert_eq!(list.objects.len(), 1);
+
+        let aabb = list.bounding_box(0.0, 1.0).unwrap();
+        assert!(aabb.min.x <= -1.0 && aabb.max.y >= 1.0);
+
+        let ray = Ray::new(Point3::new(0.5, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hitrec = list.hit(ray, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.u - 0.75).abs() < 1e-9);
+        assert!((hitrec.v - 0.625).abs() < 1e-9);
+    }
+
+    #[test]
+    fn groups_become_meshes() {
+        let list = parse_str("
+            v 0 0 0
+            v 1 0 0
+            v 0 1 0
+            g first
+            f 1 2 3
+            g empty
+            o second
+            f 1//1 2//1 3//1
+            vn 0 0 1
+        ");
+        // The normal is referenced before it's defined.
+        assert!(matches!(list, Err(MeshError::Parse{ line: 9, .. })));
+
+        let list = parse_str("
+            v 0 0 0
+            v 1 0 0
+            v 0 1 0
+            vn 0 0 1
+            g first
+            f 1 2 3
+            g empty
+            o second
+            f 1//1 2//1 3//1
+        ").unwrap();
+        assert_eq!(list.objects.len(), 2);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(parse_str("v 0 0"), Err(MeshError::Parse{ line: 1, .. })));
+        assert!(matches!(parse_str("v 0 0 x"), Err(MeshError::Parse{ line: 1, .. })));
+        assert!(matches!(parse_str("v 0 0 0\nf 1 2 3"), Err(MeshError::Parse{ line: 2, .. })));
+        assert!(matches!(parse_str("usemtl nothing"), Err(MeshError::Parse{ line: 1, .. })));
+        assert!(matches!(parse_str("v 0 0 0"), Err(MeshError::Parse{ .. })));
+    }
+}
@@ -0,0 +1,30 @@
+use crate::vec3::{cross, dot, unit_vector, Vec3};
+
+/// An orthonormal basis built around a single direction, for working in a shape's own frame.
+#[derive(Debug, Copy, Clone)]
+pub struct Onb {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+}
+
+impl Onb {
+    /// Any w will do; the other two axes are picked arbitrarily around it.
+    pub fn from_w(w: Vec3) -> Onb {
+        let w = unit_vector(w);
+        let a = if w.x.abs() > 0.9 { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::new(1.0, 0.0, 0.0) };
+        let v = unit_vector(cross(w, a));
+        let u = cross(w, v);
+        Onb{ u, v, w }
+    }
+
+    /// Takes coordinates in this basis to world space.
+    pub fn local(&self, a: Vec3) -> Vec3 {
+        a.x*self.u + a.y*self.v + a.z*self.w
+    }
+
+    /// Takes a world space vector to coordinates in this basis.
+    pub fn project(&self, a: Vec3) -> Vec3 {
+        Vec3::new(dot(a, self.u), dot(a, self.v), dot(a, self.w))
+    }
+}
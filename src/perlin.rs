@@ -1,3 +1,4 @@
+use crate::float::Float;
 use crate::rand::randidx;
 use crate::vec3::{dot, Point3, Vec3};
 
@@ -22,7 +23,7 @@ impl PerlinNoise {
         PerlinNoise{ ranvec, perm_x, perm_y, perm_z }
     }
 
-    pub fn noise(&self, p: Point3) -> f64 {
+    pub fn noise(&self, p: Point3) -> Float {
         let u = p.x - p.x.floor();
         let v = p.y - p.y.floor();
         let w = p.z - p.z.floor();
@@ -48,7 +49,7 @@ impl PerlinNoise {
         trilinear_interp(c, u, v, w)
     }
 
-    pub fn turb(&self, p: Point3, depth: usize) -> f64 {
+    pub fn turb(&self, p: Point3, depth: usize) -> Float {
         let mut accum = 0.0;
         let mut temp_p = p;
         let mut weight = 1.0;
@@ -83,15 +84,15 @@ fn permute(p: &mut Vec<usize>) {
     }
 }
 
-fn trilinear_interp(c: [[[Vec3; 2]; 2]; 2], u: f64, v: f64, w: f64) -> f64 {
-    let mut accum = 0.0f64;
+fn trilinear_interp(c: [[[Vec3; 2]; 2]; 2], u: Float, v: Float, w: Float) -> Float {
+    let mut accum: Float = 0.0;
 
     for iu in 0..2 {
-        let i = iu as f64;
+        let i = iu as Float;
         for ju in 0..2 {
-            let j = ju as f64;
+            let j = ju as Float;
             for ku in 0..2 {
-                let k = ku as f64;
+                let k = ku as Float;
                 let weight_v = Vec3::new(u-i, v-j, w-k);
                 accum +=
                     (i*u + (1.0-i)*(1.0-u))*
@@ -0,0 +1,578 @@
+use crate::float::Float;
+use crate::hit::HitArc;
+use crate::material::Material;
+use crate::mesh::{Face, MeshError, TriangleMesh, triangulate};
+use crate::vec3::{Color, Point3, Vec3};
+use std::convert::TryFrom;
+use std::fs;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Scalar {
+    I8, U8, I16, U16, I32, U32, F32, F64,
+}
+
+impl Scalar {
+    fn from_name(name: &str) -> Option<Scalar> {
+        match name {
+            "char" | "int8" => Some(Scalar::I8),
+            "uchar" | "uint8" => Some(Scalar::U8),
+            "short" | "int16" => Some(Scalar::I16),
+            "ushort" | "uint16" => Some(Scalar::U16),
+            "int" | "int32" => Some(Scalar::I32),
+            "uint" | "uint32" => Some(Scalar::U32),
+            "float" | "float32" => Some(Scalar::F32),
+            "double" | "float64" => Some(Scalar::F64),
+            _ => None,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+
+    /// The smallest and largest values of an integer kind, None for floating point ones.
+    fn integer_range(&self) -> Option<(i64, i64)> {
+        match self {
+            Scalar::I8 => Some((i8::MIN as i64, i8::MAX as i64)),
+            Scalar::U8 => Some((0, u8::MAX as i64)),
+            Scalar::I16 => Some((i16::MIN as i64, i16::MAX as i64)),
+            Scalar::U16 => Some((0, u16::MAX as i64)),
+            Scalar::I32 => Some((i32::MIN as i64, i32::MAX as i64)),
+            Scalar::U32 => Some((0, u32::MAX as i64)),
+            Scalar::F32 | Scalar::F64 => None,
+        }
+    }
+}
+
+enum Property {
+    Scalar { name: String, kind: Scalar },
+    List { name: String, count: Scalar, item: Scalar },
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+/**
+ * Reads the scalars of the body one at a time, whatever the encoding. List counts and vertex
+ * indices are read with `integer`, so that they're never rounded through a Float, which with f32
+ * can't tell neighbouring indices above 2^24 apart.
+ */
+trait Body {
+    fn read(&mut self, kind: Scalar) -> Result<Float, MeshError>;
+
+    /// Reads a value of an integer kind, failing for one that isn't a whole number in its range.
+    fn integer(&mut self, kind: Scalar) -> Result<i64, MeshError>;
+
+    /// An error at the value last read.
+    fn error(&self, message: String) -> MeshError;
+
+    /// Reads the number of items in a list.
+    fn count(&mut self, kind: Scalar) -> Result<usize, MeshError> {
+        let n = self.integer(kind)?;
+        usize::try_from(n).map_err(|_| self.error(format!("a list can't have {} items", n)))
+    }
+}
+
+/// The error for a list count or index stored as a floating point number.
+fn not_integer_kind(body: &dyn Body, kind: Scalar) -> MeshError {
+    body.error(format!("list counts and indices must be integers, not {:?}", kind))
+}
+
+struct AsciiBody<'a> {
+    filename: &'a str,
+    tokens: Box<dyn Iterator<Item = (usize, &'a str)> + 'a>,
+    line: usize,
+}
+
+impl<'a> Body for AsciiBody<'a> {
+    fn read(&mut self, _kind: Scalar) -> Result<Float, MeshError> {
+        match self.tokens.next() {
+            Some((line, token)) => {
+                self.line = line;
+                token.parse::<Float>().map_err(|_| MeshError::parse(
+                    self.filename, line, format!("'{}' is not a number", token)))
+            }
+            None => Err(MeshError::parse(self.filename, self.line, "unexpected end of file")),
+        }
+    }
+
+    fn integer(&mut self, kind: Scalar) -> Result<i64, MeshError> {
+        let (min, max) = kind.integer_range().ok_or_else(|| not_integer_kind(self, kind))?;
+        match self.tokens.next() {
+            Some((line, token)) => {
+                self.line = line;
+                match token.parse::<i64>() {
+                    Ok(value) if (min..=max).contains(&value) => Ok(value),
+                    _ => Err(self.error(format!("'{}' is not a {:?}", token, kind))),
+                }
+            }
+            None => Err(MeshError::parse(self.filename, self.line, "unexpected end of file")),
+        }
+    }
+
+    fn error(&self, message: String) -> MeshError {
+        MeshError::parse(self.filename, self.line, message)
+    }
+}
+
+struct BinaryBody<'a> {
+    filename: &'a str,
+    data: &'a [u8],
+    offset: usize,
+    big_endian: bool,
+}
+
+impl<'a> BinaryBody<'a> {
+    /// The next value's bytes, little endian first, in the first `kind.size()` of eight.
+    fn take(&mut self, kind: Scalar) -> Result<[u8; 8], MeshError> {
+        let size = kind.size();
+        if self.offset + size > self.data.len() {
+            return Err(MeshError::parse(self.filename, 0, format!(
+                "unexpected end of file at byte {}", self.offset)));
+        }
+
+        let mut bytes = [0u8; 8];
+        bytes[..size].copy_from_slice(&self.data[self.offset..self.offset + size]);
+        if self.big_endian {
+            bytes[..size].reverse();
+        }
+        self.offset += size;
+        Ok(bytes)
+    }
+}
+
+impl<'a> Body for BinaryBody<'a> {
+    fn read(&mut self, kind: Scalar) -> Result<Float, MeshError> {
+        let bytes = self.take(kind)?;
+        let value = match kind {
+            Scalar::I8 => bytes[0] as i8 as Float,
+            Scalar::U8 => bytes[0] as Float,
+            Scalar::I16 => i16::from_le_bytes([bytes[0], bytes[1]]) as Float,
+            Scalar::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as Float,
+            Scalar::I32 => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as Float,
+            Scalar::U32 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as Float,
+            Scalar::F32 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as Float,
+            Scalar::F64 => f64::from_le_bytes(bytes) as Float,
+        };
+        Ok(value)
+    }
+
+    fn integer(&mut self, kind: Scalar) -> Result<i64, MeshError> {
+        if kind.integer_range().is_none() {
+            return Err(not_integer_kind(self, kind));
+        }
+        let bytes = self.take(kind)?;
+        let value = match kind {
+            Scalar::I8 => bytes[0] as i8 as i64,
+            Scalar::U8 => bytes[0] as i64,
+            Scalar::I16 => i16::from_le_bytes([bytes[0], bytes[1]]) as i64,
+            Scalar::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as i64,
+            Scalar::I32 => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64,
+            Scalar::U32 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64,
+            Scalar::F32 | Scalar::F64 => unreachable!(),
+        };
+        Ok(value)
+    }
+
+    fn error(&self, message: String) -> MeshError {
+        MeshError::parse(self.filename, 0, format!("{} at byte {}", message, self.offset))
+    }
+}
+
+/**
+ * Loads a Stanford PLY file in ASCII or binary (either endianness) form as a TriangleMesh. Vertex
+ * normals, texture coordinates and colors are picked up when the file has them. The colors are
+ * exposed through the VertexColor texture.
+ */
+pub fn load(filename: &str, material: Material) -> Result<HitArc, MeshError> {
+    let data = fs::read(filename)?;
+    parse(&data, filename, material)
+}
+
+fn parse(data: &[u8], filename: &str, material: Material) -> Result<HitArc, MeshError> {
+    let (format, elements, header_lines, body_start) = parse_header(data, filename)?;
+
+    let mut body: Box<dyn Body> = match format {
+        Format::Ascii => {
+            let text = std::str::from_utf8(&data[body_start..])
+                .map_err(|_| MeshError::parse(filename, header_lines, "ASCII body isn't valid text"))?;
+            let tokens: Box<dyn Iterator<Item = (usize, &str)>> = Box::new(text.lines()
+                .enumerate()
+                .flat_map(move |(i, line)| line.split_whitespace().map(move |t| (header_lines + i + 1, t))));
+            Box::new(AsciiBody{ filename, tokens, line: header_lines })
+        }
+        _ => Box::new(BinaryBody{
+            filename,
+            data: &data[body_start..],
+            offset: 0,
+            big_endian: format == Format::BinaryBigEndian,
+        }),
+    };
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut colors = Vec::new();
+    let mut faces = Vec::new();
+
+    for element in &elements {
+        match element.name.as_str() {
+            "vertex" => read_vertices(element, body.as_mut(), filename,
+                &mut positions, &mut normals, &mut uvs, &mut colors)?,
+            "face" => read_faces(element, body.as_mut(), filename, &positions, &mut faces)?,
+            _ => skip(element, body.as_mut())?,
+        }
+    }
+
+    if faces.is_empty() {
+        return Err(MeshError::parse(filename, 0, "no faces found"));
+    }
+
+    let has_normals = !normals.is_empty();
+    let has_uvs = !uvs.is_empty();
+    for face in faces.iter_mut() {
+        if has_normals {
+            face.normals = Some(face.vertices);
+        }
+        if has_uvs {
+            face.uvs = Some(face.vertices);
+        }
+    }
+
+    Ok(TriangleMesh::new(positions, normals, uvs, colors, faces, vec![material]))
+}
+
+fn parse_header(data: &[u8], filename: &str) -> Result<(Format, Vec<Element>, usize, usize), MeshError> {
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut offset = 0;
+    let mut number = 0;
+
+    loop {
+        let end = match data[offset..].iter().position(|&b| b == b'\n') {
+            Some(end) => offset + end,
+            None => return Err(MeshError::parse(filename, number, "header has no end_header")),
+        };
+        let line = String::from_utf8_lossy(&data[offset..end]);
+        offset = end + 1;
+        number += 1;
+
+        let error = |message: String| MeshError::parse(filename, number, message);
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+
+        if number == 1 {
+            if tokens != ["ply"] {
+                return Err(error(String::from("not a PLY file")));
+            }
+            continue;
+        }
+
+        match tokens.first().copied() {
+            Some("format") => {
+                format = Some(match tokens.get(1).copied() {
+                    Some("ascii") => Format::Ascii,
+                    Some("binary_little_endian") => Format::BinaryLittleEndian,
+                    Some("binary_big_endian") => Format::BinaryBigEndian,
+                    other => return Err(error(format!("unknown format {:?}", other))),
+                });
+            }
+            Some("element") => {
+                if tokens.len() != 3 {
+                    return Err(error(String::from("element needs a name and a count")));
+                }
+                let count = tokens[2].parse::<usize>()
+                    .map_err(|_| error(format!("'{}' is not a count", tokens[2])))?;
+                elements.push(Element{ name: tokens[1].to_string(), count, properties: Vec::new() });
+            }
+            Some("property") => {
+                let element = elements.last_mut()
+                    .ok_or_else(|| error(String::from("property before any element")))?;
+                let scalar = |name: &str| Scalar::from_name(name)
+                    .ok_or_else(|| error(format!("unknown property type '{}'", name)));
+
+                let property = match tokens.as_slice() {
+                    ["property", "list", count, item, name] => Property::List{
+                        name: name.to_string(),
+                        count: scalar(count)?,
+                        item: scalar(item)?,
+                    },
+                    ["property", kind, name] => Property::Scalar{
+                        name: name.to_string(),
+                        kind: scalar(kind)?,
+                    },
+                    _ => return Err(error(String::from("malformed property"))),
+                };
+                element.properties.push(property);
+            }
+            Some("end_header") => break,
+            _ => {}
+        }
+    }
+
+    let format = format.ok_or_else(|| MeshError::parse(filename, number, "header has no format"))?;
+    Ok((format, elements, number, offset))
+}
+
+fn read_vertices(
+    element: &Element,
+    body: &mut dyn Body,
+    filename: &str,
+    positions: &mut Vec<Point3>,
+    normals: &mut Vec<Vec3>,
+    uvs: &mut Vec<(Float, Float)>,
+    colors: &mut Vec<Color>,
+) -> Result<(), MeshError> {
+    let find = |names: &[&str]| element.properties.iter().position(|p| match p {
+        Property::Scalar{ name, .. } => names.contains(&name.as_str()),
+        _ => false,
+    });
+
+    let position = [find(&["x"]), find(&["y"]), find(&["z"])];
+    let normal = [find(&["nx"]), find(&["ny"]), find(&["nz"])];
+    let uv = [find(&["u", "s", "texture_u", "texture_s"]), find(&["v", "t", "texture_v", "texture_t"])];
+    let color = [find(&["red", "r"]), find(&["green", "g"]), find(&["blue", "b"])];
+
+    let position = match position {
+        [Some(x), Some(y), Some(z)] => [x, y, z],
+        _ => return Err(MeshError::parse(filename, 0, "vertex element is missing x, y or z")),
+    };
+    let normal = match normal {
+        [Some(x), Some(y), Some(z)] => Some([x, y, z]),
+        _ => None,
+    };
+    let uv = match uv {
+        [Some(u), Some(v)] => Some([u, v]),
+        _ => None,
+    };
+    let color = match color {
+        [Some(r), Some(g), Some(b)] => Some([r, g, b]),
+        _ => None,
+    };
+
+    let mut values = vec![0.0; element.properties.len()];
+    for _ in 0..element.count {
+        for (i, property) in element.properties.iter().enumerate() {
+            match property {
+                Property::Scalar{ kind, .. } => values[i] = body.read(*kind)?,
+                Property::List{ count, item, .. } => skip_list(body, *count, *item)?,
+            }
+        }
+
+        positions.push(Point3::new(values[position[0]], values[position[1]], values[position[2]]));
+        if let Some(n) = normal {
+            normals.push(Vec3::new(values[n[0]], values[n[1]], values[n[2]]));
+        }
+        if let Some(uv) = uv {
+            uvs.push((values[uv[0]], values[uv[1]]));
+        }
+        if let Some(c) = color {
+            // Integer colors are 0-255, floating point ones are already 0-1.
+            let scale = match &element.properties[c[0]] {
+                Property::Scalar{ kind: Scalar::F32, .. } | Property::Scalar{ kind: Scalar::F64, .. } => 1.0,
+                _ => 1.0 / 255.0,
+            };
+            colors.push(scale * Color::new(values[c[0]], values[c[1]], values[c[2]]));
+        }
+    }
+
+    Ok(())
+}
+
+fn read_faces(
+    element: &Element,
+    body: &mut dyn Body,
+    filename: &str,
+    positions: &[Point3],
+    faces: &mut Vec<Face>,
+) -> Result<(), MeshError> {
+    for f in 0..element.count {
+        for property in &element.properties {
+            match property {
+                Property::List{ name, count, item } if name == "vertex_indices" || name == "vertex_index" => {
+                    let n = body.count(*count)?;
+                    if n < 3 {
+                        return Err(MeshError::parse(filename, 0, format!(
+                            "face {} has {} vertices", f, n)));
+                    }
+                    // The count may be nonsense, so the list only grows as its items are read.
+                    let mut indices = Vec::new();
+                    for _ in 0..n {
+                        let index = body.integer(*item)?;
+                        match usize::try_from(index) {
+                            Ok(index) if index < positions.len() => indices.push(index),
+                            _ => return Err(MeshError::parse(filename, 0, format!(
+                                "face {} refers to vertex {}, there are {}", f, index, positions.len()))),
+                        }
+                    }
+
+                    let points: Vec<Point3> = indices.iter().map(|&i| positions[i]).collect();
+                    for [a, b, c] in triangulate(&points) {
+                        faces.push(Face{ vertices: [indices[a], indices[b], indices[c]], ..Face::default() });
+                    }
+                }
+                Property::List{ count, item, .. } => skip_list(body, *count, *item)?,
+                Property::Scalar{ kind, .. } => { body.read(*kind)?; }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn skip(element: &Element, body: &mut dyn Body) -> Result<(), MeshError> {
+    for _ in 0..element.count {
+        for property in &element.properties {
+            match property {
+                Property::Scalar{ kind, .. } => { body.read(*kind)?; }
+                Property::List{ count, item, .. } => skip_list(body, *count, *item)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn skip_list(body: &mut dyn Body, count: Scalar, item: Scalar) -> Result<(), MeshError> {
+    let n = body.count(count)?;
+    for _ in 0..n {
+        body.read(item)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::float::Float;
+    use crate::material::Lambertian;
+    use crate::mesh::MeshError;
+    use crate::ply::{AsciiBody, BinaryBody, Body, parse, Scalar};
+    use crate::ray::Ray;
+    use crate::vec3::{Color, Point3, Vec3};
+
+    const HEADER: &str = "element vertex 4
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+";
+
+    fn quad_vertices() -> Vec<(f32, f32, f32, [u8; 3])> {
+        vec![
+            (-1.0, -1.0, 0.0, [255, 0, 0]),
+            ( 1.0, -1.0, 0.0, [255, 0, 0]),
+            ( 1.0,  1.0, 0.0, [0, 0, 255]),
+            (-1.0,  1.0, 0.0, [0, 0, 255]),
+        ]
+    }
+
+    fn binary(big_endian: bool) -> Vec<u8> {
+        let format = if big_endian { "binary_big_endian" } else { "binary_little_endian" };
+        let mut data = format!("ply\nformat {} 1.0\n{}", format, HEADER).into_bytes();
+        let float = |x: f32| if big_endian { x.to_be_bytes() } else { x.to_le_bytes() };
+        for (x, y, z, c) in quad_vertices() {
+            data.extend_from_slice(&float(x));
+            data.extend_from_slice(&float(y));
+            data.extend_from_slice(&float(z));
+            data.extend_from_slice(&c);
+        }
+        data.push(4);
+        for i in 0..4i32 {
+            data.extend_from_slice(&if big_endian { i.to_be_bytes() } else { i.to_le_bytes() });
+        }
+        data
+    }
+
+    fn center_color(data: &[u8]) -> Color {
+        let mesh = parse(data, "test.ply", Lambertian::from_color(Color::default())).unwrap();
+        let ray = Ray::new(Point3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        mesh.hit(ray, 0.001, Float::INFINITY).unwrap().color.unwrap()
+    }
+
+    #[test]
+    fn all_formats_agree() {
+        let mut ascii = format!("ply\nformat ascii 1.0\ncomment a test\n{}", HEADER);
+        for (x, y, z, c) in quad_vertices() {
+            ascii += &format!("{} {} {} {} {} {}\n", x, y, z, c[0], c[1], c[2]);
+        }
+        ascii += "4 0 1 2 3\n";
+
+        for data in &[ascii.into_bytes(), binary(false), binary(true)] {
+            let color = center_color(data);
+            assert!((color - Color::new(0.5, 0.0, 0.5)).length() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn integers_are_read_exactly() {
+        // Past 2^24, where an f32 can't hold every whole number.
+        let tokens = Box::new("16777217 4294967295".split(' ').map(|t| (1, t)));
+        let mut ascii = AsciiBody{ filename: "test.ply", tokens, line: 0 };
+        assert_eq!(ascii.integer(Scalar::I32).unwrap(), 16777217);
+        assert_eq!(ascii.integer(Scalar::U32).unwrap(), 4294967295);
+
+        let data = [16777217u32.to_be_bytes(), u32::MAX.to_be_bytes()].concat();
+        let mut binary = BinaryBody{ filename: "test.ply", data: &data, offset: 0, big_endian: true };
+        assert_eq!(binary.integer(Scalar::U32).unwrap(), 16777217);
+        assert_eq!(binary.integer(Scalar::U32).unwrap(), 4294967295);
+        assert!(matches!(binary.integer(Scalar::F32), Err(MeshError::Parse{ .. })));
+    }
+
+    #[test]
+    fn errors() {
+        let material = || Lambertian::from_color(Color::default());
+
+        let mut truncated = binary(false);
+        truncated.truncate(truncated.len() - 2);
+        assert!(matches!(parse(&truncated, "test.ply", material()), Err(MeshError::Parse{ .. })));
+
+        let mut bad_index = binary(true);
+        let len = bad_index.len();
+        bad_index[len - 1] = 9;
+        assert!(matches!(parse(&bad_index, "test.ply", material()), Err(MeshError::Parse{ .. })));
+
+        let ascii = format!("ply\nformat ascii 1.0\n{}0 0 0 1 1 1\n", HEADER);
+        assert!(matches!(parse(ascii.as_bytes(), "test.ply", material()), Err(MeshError::Parse{ line: 13, .. })));
+
+        // A face claiming four billion vertices, in a file that holds four.
+        let mut huge = format!("ply\nformat ascii 1.0\n{}", HEADER.replace("list uchar", "list uint"));
+        for (x, y, z, c) in quad_vertices() {
+            huge += &format!("{} {} {} {} {} {}\n", x, y, z, c[0], c[1], c[2]);
+        }
+        huge += "4000000000 0 1 2 3\n";
+        assert!(matches!(parse(huge.as_bytes(), "test.ply", material()), Err(MeshError::Parse{ .. })));
+
+        // Counts and indices that aren't whole numbers in range for their kind, or for the vertices.
+        for face in &["4 0 1 2 3.7", "4 0 1 nan 3", "4 0 1 2 -1", "300 0 1 2 3", "4.0 0 1 2 3", "4 0 1 2 4"] {
+            let mut ascii = format!("ply\nformat ascii 1.0\n{}", HEADER);
+            for (x, y, z, c) in quad_vertices() {
+                ascii += &format!("{} {} {} {} {} {}\n", x, y, z, c[0], c[1], c[2]);
+            }
+            ascii += face;
+            let error = parse(ascii.as_bytes(), "test.ply", material());
+            assert!(matches!(error, Err(MeshError::Parse{ .. })), "{}", face);
+        }
+
+        assert!(matches!(parse(b"obj\n", "test.ply", material()), Err(MeshError::Parse{ line: 1, .. })));
+        assert!(matches!(parse(b"ply\nformat ascii 1.0\n", "test.ply", material()), Err(MeshError::Parse{ .. })));
+    }
+}
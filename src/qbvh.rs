@@ -0,0 +1,380 @@
+use crate::aabb::AABB;
+use crate::accelerator::{self, Traverse};
+use crate::bvh::{self, BVHNode, Node, TraversalCounts};
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitList, HitRecord, PACKET};
+use crate::ray::Ray;
+use crate::simd::{Box4, Slabs};
+
+/// Stacks are set up afresh for every ray, so trees shallow enough to get by with this many entries
+/// keep theirs in an array, and only deeper ones pay to allocate a bigger one.
+const SMALL_STACK: usize = 64;
+
+/**
+ * A bounding volume hierarchy with four children to a node, made by collapsing a `BVHNode`: each
+ * node takes in its children's children, biggest box first, until it has four. A ray tests all
+ * four boxes at once (see `simd::Box4`) and goes on into the ones it hits, nearest first, so it
+ * visits about half as many nodes as in the binary tree for one test each instead of two.
+ *
+ * Packets of rays walk it together with `hit_packet`. Every ray in the packet tests each node's
+ * boxes, and each child is visited by only the rays that hit it, so rays headed the same way, like
+ * camera rays through neighbouring pixels, share the work of finding and fetching the nodes.
+ */
+pub struct Qbvh {
+    nodes: Vec<QuadNode>,
+    root: Child,
+    objects: Vec<HitArc>,
+    unbounded: HitList,
+    bounds: Option<AABB>,
+    /// How many entries a walk through the tree can leave on its stack.
+    stack_size: usize,
+}
+
+#[derive(Copy, Clone, Default)]
+enum Child {
+    #[default]
+    Empty,
+    Node(u32),
+    /// Where its objects start and how many there are.
+    Leaf(u32, u16),
+}
+
+#[derive(Default)]
+struct QuadNode {
+    boxes: Box4,
+    children: [Child; 4],
+}
+
+/// A child waiting on the stack, with where the nearest of the rays that hit its box enters it and
+/// which of them those were, one bit for each ray of a packet.
+#[derive(Copy, Clone, Default)]
+struct Entry {
+    child: Child,
+    near: Float,
+    rays: u32,
+}
+
+impl Qbvh {
+    pub fn new(bvh: &BVHNode) -> Qbvh {
+        let mut nodes = Vec::new();
+        let root = match bvh.node(0) {
+            Some(_) => collapse(bvh, 0, &mut nodes),
+            None => Child::Empty,
+        };
+
+        // Children always come after their parents. Each node leaves at most three of its children
+        // on the stack while the fourth is visited, and then four at the deepest level.
+        let mut depths = vec![1; nodes.len()];
+        for index in 0..nodes.len() {
+            for child in nodes[index].children {
+                if let Child::Node(child) = child {
+                    depths[child as usize] = depths[index] + 1;
+                }
+            }
+        }
+        let depth = depths.iter().copied().max().unwrap_or(0);
+
+        Qbvh{
+            nodes,
+            root,
+            objects: bvh.objects().to_vec(),
+            unbounded: HitList{ objects: bvh.unbounded().objects.clone() },
+            bounds: bvh.bounding_box(0.0, 0.0),
+            stack_size: 3 * depth + 1,
+        }
+    }
+
+    pub fn from_hitlist(list: &HitList, time0: Float, time1: Float) -> Qbvh {
+        Qbvh::new(&BVHNode::from_hitlist(list, time0, time1))
+    }
+
+    /// Calls `walk` with an empty stack big enough for the tree.
+    fn with_stack<R, F: FnOnce(&mut [Entry]) -> R>(&self, walk: F) -> R {
+        if self.stack_size <= SMALL_STACK {
+            walk(&mut [Entry::default(); SMALL_STACK])
+        } else {
+            walk(&mut vec![Entry::default(); self.stack_size])
+        }
+    }
+
+    fn walk<'a, F>(&'a self, stack: &mut [Entry], ray: Ray, t_min: Float, mut t_max: Float, mut visit: F)
+        where F: FnMut(&'a [HitArc], Float) -> Float {
+
+        let slabs = Slabs::new(ray);
+        let mut top = 0;
+        let mut current = self.root;
+        let mut counts = TraversalCounts::default();
+        'walk: loop {
+            match current {
+                Child::Node(index) => {
+                    let node = &self.nodes[index as usize];
+                    counts.nodes += 1;
+                    let (mask, near) = node.boxes.hit(&slabs, t_min, t_max);
+                    push(stack, &mut top, node, near, [0, 1, 2, 3].map(|lane| mask >> lane & 1));
+                }
+                Child::Leaf(offset, count) => {
+                    counts.objects += count as u64;
+                    let start = offset as usize;
+                    t_max = visit(&self.objects[start..start + count as usize], t_max);
+                    if t_max < t_min {
+                        break;
+                    }
+                }
+                Child::Empty => {}
+            }
+            // Anything the ray enters beyond a hit found since it was pushed can be passed over.
+            loop {
+                if top == 0 {
+                    break 'walk;
+                }
+                top -= 1;
+                if stack[top].near < t_max {
+                    current = stack[top].child;
+                    break;
+                }
+            }
+        }
+        bvh::add_counts(counts);
+    }
+
+    /// `Hit::hit_packet`. Leaves pass the rays that reached them on to their objects as a packet
+    /// too, so that a tree in a leaf that can walk packets does.
+    fn walk_packet(&self, stack: &mut [Entry], rays: &[Ray; PACKET], t_min: Float, t_max: [Float; PACKET])
+        -> [Option<HitRecord<'_>>; PACKET] {
+
+        let mut nearest = self.unbounded.hit_packet(rays, t_min, t_max);
+        let mut t_max = t_max;
+        for lane in 0..PACKET {
+            if let Some(hitrec) = nearest[lane] {
+                t_max[lane] = hitrec.t;
+            }
+        }
+        let slabs = rays.map(Slabs::new);
+        let active = (0..PACKET).filter(|&lane| t_max[lane] >= t_min).fold(0, |mask, lane| mask | 1 << lane);
+
+        let mut top = 0;
+        let mut current = Entry{ child: self.root, near: t_min, rays: active };
+        let mut counts = TraversalCounts::default();
+        'walk: loop {
+            let in_packet = |lane: usize| current.rays & 1 << lane != 0;
+            match current.child {
+                Child::Node(index) => {
+                    let node = &self.nodes[index as usize];
+                    counts.nodes += 1;
+                    let mut near = [Float::INFINITY; 4];
+                    let mut hit_by = [0; 4];
+                    for lane in (0..PACKET).filter(|&lane| in_packet(lane)) {
+                        let (mask, entry) = node.boxes.hit(&slabs[lane], t_min, t_max[lane]);
+                        for child in (0..4).filter(|&child| mask & 1 << child != 0) {
+                            hit_by[child] |= 1 << lane;
+                            near[child] = near[child].min(entry[child]);
+                        }
+                    }
+                    push(stack, &mut top, node, near, hit_by);
+                }
+                Child::Leaf(offset, count) => {
+                    counts.objects += count as u64 * current.rays.count_ones() as u64;
+                    let start = offset as usize;
+                    let mut reach = [Float::NEG_INFINITY; PACKET];
+                    for lane in (0..PACKET).filter(|&lane| in_packet(lane)) {
+                        reach[lane] = t_max[lane];
+                    }
+                    for object in &self.objects[start..start + count as usize] {
+                        for (lane, hit) in object.hit_packet(rays, t_min, reach).iter().enumerate() {
+                            if let Some(hitrec) = hit {
+                                reach[lane] = hitrec.t;
+                                t_max[lane] = hitrec.t;
+                                nearest[lane] = Some(*hitrec);
+                            }
+                        }
+                    }
+                }
+                Child::Empty => {}
+            }
+            // Rays drop out of a child they enter beyond a hit found since it was pushed.
+            loop {
+                if top == 0 {
+                    break 'walk;
+                }
+                top -= 1;
+                let entry = stack[top];
+                let rays = (0..PACKET)
+                    .filter(|&lane| entry.rays & 1 << lane != 0 && entry.near < t_max[lane])
+                    .fold(0, |mask, lane| mask | 1 << lane);
+                if rays != 0 {
+                    current = Entry{ rays, ..entry };
+                    break;
+                }
+            }
+        }
+        bvh::add_counts(counts);
+        nearest
+    }
+}
+
+/// The inner node's two children, or None for a leaf.
+fn children(bvh: &BVHNode, index: usize) -> Option<(usize, usize)> {
+    match bvh.node(index) {
+        Some((_, Node::Inner(first, second))) => Some((first, second)),
+        _ => None,
+    }
+}
+
+/// Appends the four-wide nodes over the binary subtree at `index`, depth first, and returns what
+/// refers to them.
+fn collapse(bvh: &BVHNode, index: usize, nodes: &mut Vec<QuadNode>) -> Child {
+    if let Some((_, Node::Leaf(range))) = bvh.node(index) {
+        return Child::Leaf(range.start as u32, range.len() as u16);
+    }
+
+    let area = |index: usize| bvh.node(index).unwrap().0.surface_area();
+    let mut lanes = vec![index];
+    while lanes.len() < 4 {
+        let biggest = (0..lanes.len())
+            .filter(|&lane| children(bvh, lanes[lane]).is_some())
+            .max_by(|&a, &b| area(lanes[a]).total_cmp(&area(lanes[b])));
+        match biggest {
+            Some(lane) => {
+                let (first, second) = children(bvh, lanes[lane]).unwrap();
+                lanes[lane] = first;
+                lanes.push(second);
+            }
+            None => break,
+        }
+    }
+
+    let slot = nodes.len();
+    nodes.push(QuadNode::default());
+    let mut node = QuadNode::default();
+    for (lane, &child) in lanes.iter().enumerate() {
+        node.boxes.set(lane, bvh.node(child).unwrap().0);
+        node.children[lane] = collapse(bvh, child, nodes);
+    }
+    nodes[slot] = node;
+    Child::Node(slot as u32)
+}
+
+/// Pushes the children that any ray hit, farthest first so that the nearest comes off next. `rays`
+/// says which rays hit each child, and `near` where the first of them enters it.
+fn push(stack: &mut [Entry], top: &mut usize, node: &QuadNode, near: [Float; 4], rays: [u32; 4]) {
+    let bottom = *top;
+    for lane in 0..4 {
+        if rays[lane] == 0 {
+            continue;
+        }
+        let mut i = *top;
+        while i > bottom && stack[i - 1].near < near[lane] {
+            stack[i] = stack[i - 1];
+            i -= 1;
+        }
+        stack[i] = Entry{ child: node.children[lane], near: near[lane], rays: rays[lane] };
+        *top += 1;
+    }
+}
+
+impl Traverse for Qbvh {
+    type Leaf<'a> = &'a [HitArc];
+
+    fn unbounded(&self) -> &HitList {
+        &self.unbounded
+    }
+
+    /// Like `BVHNode`, nearest side first.
+    fn traverse<'a, F>(&'a self, ray: Ray, t_min: Float, t_max: Float, visit: F)
+        where F: FnMut(Self::Leaf<'a>, Float) -> Float {
+
+        self.with_stack(|stack| self.walk(stack, ray, t_min, t_max, visit));
+    }
+}
+
+impl Hit for Qbvh {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        accelerator::hit(self, ray, t_min, t_max)
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        accelerator::occluded(self, ray, t_min, t_max)
+    }
+
+    fn hit_all<'a>(&'a self, ray: Ray, t_min: Float, t_max: Float, hits: &mut Vec<HitRecord<'a>>) {
+        accelerator::hit_all(self, ray, t_min, t_max, hits);
+    }
+
+    fn hit_packet(&self, rays: &[Ray; PACKET], t_min: Float, t_max: [Float; PACKET])
+        -> [Option<HitRecord<'_>>; PACKET] {
+
+        self.with_stack(|stack| self.walk_packet(stack, rays, t_min, t_max))
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        self.bounds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::accelerator::tests::{assert_same_hits, objects, random_ray};
+    use crate::float::Float;
+    use crate::hit::{Hit, HitList, PACKET};
+    use crate::material::Lambertian;
+    use crate::qbvh::{Qbvh, SMALL_STACK};
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+    use crate::vec3::{Color, Point3, Vec3};
+
+    #[test]
+    fn same_hits_as_a_list() {
+        // The plane and three spheres, too few to fill a node, and then everything.
+        let all = objects(300);
+        for count in [4, all.objects.len()] {
+            let list = HitList{ objects: all.objects[..count].to_vec() };
+            let qbvh = Qbvh::from_hitlist(&list, 0.0, 1.0);
+
+            for _ in 0..200 {
+                // Rays from nearby points in nearby directions, like camera rays through
+                // neighbouring pixels, and one going its own way.
+                let ray = random_ray();
+                let mut rays = [0; PACKET].map(|_| {
+                    Ray::new(ray.origin + Vec3::randrange(-0.1, 0.1), ray.direction + Vec3::randrange(-0.1, 0.1), 0.0)
+                });
+                rays[2] = random_ray();
+                let t_max = [Float::INFINITY, 5.0, Float::INFINITY, -1.0];
+
+                let packet = qbvh.hit_packet(&rays, 0.001, t_max);
+                for (lane, ray) in rays.iter().enumerate() {
+                    assert_same_hits(&list, &qbvh, *ray, 0.001, t_max[lane], count);
+                    let expected = list.hit(*ray, 0.001, t_max[lane]).map(|hitrec| hitrec.t);
+                    assert_eq!(packet[lane].map(|hitrec| hitrec.t), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn deep_trees() {
+        // Spheres further and further apart make a lopsided tree, too deep for the small stack.
+        // They grow as they go, to stay well clear of rounding.
+        let mut list = HitList::default();
+        let radius = |i: i32| 0.1 * (1.5 as Float).powi(i);
+        for i in 0..200 {
+            let center = Point3::new(10.0 * radius(i), 0.0, 0.0);
+            list.add(Sphere::new(center, radius(i), Lambertian::from_color(Color::default())));
+        }
+        let qbvh = Qbvh::from_hitlist(&list, 0.0, 1.0);
+        assert!(qbvh.stack_size > SMALL_STACK);
+
+        let along = Ray::new(Point3::new(-1.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        let (mut expected, mut found) = (Vec::new(), Vec::new());
+        list.hit_all(along, 0.0, Float::INFINITY, &mut expected);
+        qbvh.hit_all(along, 0.0, Float::INFINITY, &mut found);
+        assert!(!expected.is_empty() && expected.iter().map(|h| h.t).eq(found.iter().map(|h| h.t)));
+
+        let down = [0, 1, 2, 3].map(|i| {
+            Ray::new(Point3::new(10.0 * radius(100 + i), 2.0 * radius(100 + i), 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0)
+        });
+        for (i, hit) in qbvh.hit_packet(&down, 0.0, [Float::INFINITY; PACKET]).iter().enumerate() {
+            assert!((hit.unwrap().t / radius(100 + i as i32) - 1.0).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use crate::aabb::AABB;
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitRecord};
+use crate::material::Material;
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::vec3::{cross, dot, Point3, unit_vector, Vec3};
+use crate::float::consts::PI;
+use std::sync::Arc;
+
+/// Flat shapes are padded by this much so their boxes never have zero thickness.
+const PAD: Float = 0.0001;
+
+/**
+ * A parallelogram with one corner at `corner` and its two edges along `u` and `v`. The normal is
+ * u cross v, and the texture coordinates run from 0 to 1 along each edge.
+ */
+pub struct Quad {
+    corner: Point3,
+    u: Vec3,
+    v: Vec3,
+    normal: Vec3,
+    d: Float,
+    w: Vec3,
+    material: Material,
+}
+
+impl Quad {
+    pub fn new(corner: Point3, u: Vec3, v: Vec3, material: Material) -> HitArc {
+        let n = cross(u, v);
+        let normal = unit_vector(n);
+        let d = dot(normal, corner);
+        // Dotting with w gives the coordinates of a point in the plane along u and v.
+        let w = n / dot(n, n);
+        Arc::new(Quad{ corner, u, v, normal, d, w, material })
+    }
+}
+
+impl Quad {
+    /// Where the ray crosses the quad, and how far that is along each edge.
+    fn intersect(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<(Float, Float, Float)> {
+        let t = plane_hit(ray, self.normal, self.d, t_min, t_max)?;
+        let point = ray.at(t);
+        let planar = point - self.corner;
+        let alpha = dot(self.w, cross(planar, self.v));
+        let beta = dot(self.w, cross(self.u, planar));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return None;
+        }
+        Some((t, alpha, beta))
+    }
+}
+
+impl Hit for Quad {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let (t, alpha, beta) = self.intersect(ray, t_min, t_max)?;
+        Some(HitRecord::new(ray, t, self.normal, alpha, beta, &self.material))
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        self.intersect(ray, t_min, t_max).is_some()
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        let corners = [self.corner + self.u, self.corner + self.v, self.corner + self.u + self.v];
+        let mut min = self.corner;
+        let mut max = self.corner;
+        for c in corners.iter() {
+            min = Point3::new(min.x.min(c.x), min.y.min(c.y), min.z.min(c.z));
+            max = Point3::new(max.x.max(c.x), max.y.max(c.y), max.z.max(c.z));
+        }
+        Some(AABB::new(min - Vec3::new(PAD, PAD, PAD), max + PAD))
+    }
+}
+
+/**
+ * A flat disk facing along `normal`. The texture wraps around it, with u as the angle and v as the
+ * distance from the center over the radius.
+ */
+pub struct Disk {
+    center: Point3,
+    radius: Float,
+    frame: Onb,
+    material: Material,
+}
+
+impl Disk {
+    pub fn new(center: Point3, normal: Vec3, radius: Float, material: Material) -> HitArc {
+        Arc::new(Disk{ center, radius, frame: Onb::from_w(normal), material })
+    }
+}
+
+impl Disk {
+    /// Where the ray crosses the disk, and where that is in the disk's own frame.
+    fn intersect(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<(Float, Vec3)> {
+        let normal = self.frame.w;
+        let t = plane_hit(ray, normal, dot(normal, self.center), t_min, t_max)?;
+        let local = self.frame.project(ray.at(t) - self.center);
+        if local.x*local.x + local.y*local.y > self.radius*self.radius {
+            return None;
+        }
+        Some((t, local))
+    }
+}
+
+impl Hit for Disk {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let (t, local) = self.intersect(ray, t_min, t_max)?;
+        let r = (local.x*local.x + local.y*local.y).sqrt();
+        let u = (local.y.atan2(local.x) + PI) / (2.0*PI);
+        Some(HitRecord::new(ray, t, self.frame.w, u, r / self.radius, &self.material))
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        self.intersect(ray, t_min, t_max).is_some()
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        let extent = disk_extent(self.frame.w, self.radius) + PAD;
+        Some(AABB::new(self.center - extent, self.center + extent))
+    }
+}
+
+/**
+ * An infinite plane through `point`. It has no bounding box, so BVHNode keeps it outside the tree.
+ * The texture coordinates are distances along two arbitrary directions in the plane, so they keep
+ * growing rather than wrapping.
+ */
+pub struct Plane {
+    point: Point3,
+    frame: Onb,
+    material: Material,
+}
+
+impl Plane {
+    pub fn new(point: Point3, normal: Vec3, material: Material) -> HitArc {
+        Arc::new(Plane{ point, frame: Onb::from_w(normal), material })
+    }
+}
+
+impl Hit for Plane {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let normal = self.frame.w;
+        let t = plane_hit(ray, normal, dot(normal, self.point), t_min, t_max)?;
+        let local = self.frame.project(ray.at(t) - self.point);
+        Some(HitRecord::new(ray, t, normal, local.x, local.y, &self.material))
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        let normal = self.frame.w;
+        plane_hit(ray, normal, dot(normal, self.point), t_min, t_max).is_some()
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        None
+    }
+}
+
+/// Where a ray meets the plane dot(normal, p) = d, if it does within range. Rays running parallel
+/// to the plane never hit it.
+pub fn plane_hit(ray: Ray, normal: Vec3, d: Float, t_min: Float, t_max: Float) -> Option<Float> {
+    let denom = dot(normal, ray.direction);
+    if denom.abs() < 1e-8 {
+        return None;
+    }
+
+    let t = (d - dot(normal, ray.origin)) / denom;
+    if t < t_min || t > t_max {
+        None
+    } else {
+        Some(t)
+    }
+}
+
+/// How far a circle of the given radius reaches along each world axis when it faces `normal`.
+pub fn disk_extent(normal: Vec3, radius: Float) -> Vec3 {
+    let n = unit_vector(normal);
+    radius * Vec3::new(
+        (1.0 - n.x*n.x).max(0.0).sqrt(),
+        (1.0 - n.y*n.y).max(0.0).sqrt(),
+        (1.0 - n.z*n.z).max(0.0).sqrt(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::float::Float;
+    use crate::material::Lambertian;
+    use crate::quad::{Disk, Quad};
+    use crate::ray::Ray;
+    use crate::vec3::{Color, Point3, Vec3};
+
+    #[test]
+    fn quad_and_disk() {
+        let material = || Lambertian::from_color(Color::default());
+        let quad = Quad::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), material());
+
+        let ray = Ray::new(Point3::new(1.5, 0.25, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hitrec = quad.hit(ray, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 5.0).abs() < 1e-9);
+        assert!((hitrec.u - 0.75).abs() < 1e-9 && (hitrec.v - 0.25).abs() < 1e-9);
+        assert!(hitrec.front_face && (hitrec.normal - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-9);
+
+        let ray = Ray::new(Point3::new(2.5, 0.25, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(quad.hit(ray, 0.001, Float::INFINITY).is_none());
+
+        // A disk tilted to face along (1, 1, 0), seen from behind.
+        let disk = Disk::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 0.0), 1.0, material());
+        let ray = Ray::new(Point3::new(-3.0, -3.0, 0.5), Vec3::new(1.0, 1.0, 0.0), 0.0);
+        let hitrec = disk.hit(ray, 0.001, Float::INFINITY).unwrap();
+        assert!(!hitrec.front_face && (hitrec.t - 3.0).abs() < 1e-9 && (hitrec.v - 0.5).abs() < 1e-9);
+        let ray = Ray::new(Point3::new(-3.0, -3.0, 1.5), Vec3::new(1.0, 1.0, 0.0), 0.0);
+        assert!(disk.hit(ray, 0.001, Float::INFINITY).is_none());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::float::Float;
+use crate::vec3::{unit_vector, Vec3};
+use std::ops;
+
+/// Rotations as unit quaternions, w + xi + yj + zk.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Quaternion {
+    pub w: Float,
+    pub x: Float,
+    pub y: Float,
+    pub z: Float,
+}
+
+impl Default for Quaternion {
+    fn default() -> Quaternion {
+        Quaternion::identity()
+    }
+}
+
+impl Quaternion {
+    pub fn new(w: Float, x: Float, y: Float, z: Float) -> Quaternion {
+        Quaternion{ w, x, y, z }
+    }
+
+    pub fn identity() -> Quaternion {
+        Quaternion::new(1.0, 0.0, 0.0, 0.0)
+    }
+
+    /// A counterclockwise rotation by theta radians about the given axis.
+    pub fn from_axis_angle(axis: Vec3, theta: Float) -> Quaternion {
+        let axis = unit_vector(axis);
+        let (sin, cos) = (theta / 2.0).sin_cos();
+        Quaternion::new(cos, axis.x*sin, axis.y*sin, axis.z*sin)
+    }
+
+    pub fn length(&self) -> Float {
+        (self.w*self.w + self.x*self.x + self.y*self.y + self.z*self.z).sqrt()
+    }
+
+    pub fn normalized(&self) -> Quaternion {
+        let length = self.length();
+        Quaternion::new(self.w / length, self.x / length, self.y / length, self.z / length)
+    }
+
+    /// The opposite rotation, for unit quaternions.
+    pub fn conjugate(&self) -> Quaternion {
+        Quaternion::new(self.w, -self.x, -self.y, -self.z)
+    }
+
+    pub fn dot(&self, other: Quaternion) -> Float {
+        self.w*other.w + self.x*other.x + self.y*other.y + self.z*other.z
+    }
+
+    /// How far apart two rotations are, in radians.
+    pub fn angle_to(&self, other: Quaternion) -> Float {
+        2.0 * self.normalized().dot(other.normalized()).abs().min(1.0).acos()
+    }
+
+    /**
+     * Spherical linear interpolation, turning at a constant rate from self at t = 0 to other at
+     * t = 1 the short way round. Nearly equal rotations are lerped instead, where slerp divides by
+     * almost zero.
+     */
+    pub fn slerp(&self, other: Quaternion, t: Float) -> Quaternion {
+        let a = self.normalized();
+        let mut b = other.normalized();
+        let mut cos = a.dot(b);
+        if cos < 0.0 {
+            b = Quaternion::new(-b.w, -b.x, -b.y, -b.z);
+            cos = -cos;
+        }
+
+        let (wa, wb) = if cos > 0.9995 {
+            (1.0 - t, t)
+        } else {
+            let theta = cos.acos();
+            let sin = theta.sin();
+            (((1.0 - t)*theta).sin() / sin, (t*theta).sin() / sin)
+        };
+        Quaternion::new(wa*a.w + wb*b.w, wa*a.x + wb*b.x, wa*a.y + wb*b.y, wa*a.z + wb*b.z).normalized()
+    }
+}
+
+/// Composes rotations so that `a * b` rotates by b first and then by a.
+impl ops::Mul<Quaternion> for Quaternion {
+    type Output = Quaternion;
+
+    fn mul(self, rhs: Quaternion) -> Quaternion {
+        Quaternion::new(
+            self.w*rhs.w - self.x*rhs.x - self.y*rhs.y - self.z*rhs.z,
+            self.w*rhs.x + self.x*rhs.w + self.y*rhs.z - self.z*rhs.y,
+            self.w*rhs.y - self.x*rhs.z + self.y*rhs.w + self.z*rhs.x,
+            self.w*rhs.z + self.x*rhs.y - self.y*rhs.x + self.z*rhs.w,
+        )
+    }
+}
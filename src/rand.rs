@@ -1,10 +1,11 @@
+use crate::float::Float;
 use rand;
 use rand::Rng;
 
-pub fn rf64() -> f64 { rand::random::<f64>() }
+pub fn rfloat() -> Float { rand::random::<Float>() }
 
 // The min is inclusive and the max is exclusive, as per the gen_range() documentation
-pub fn randrange(min: f64, max: f64) -> f64 {
+pub fn randrange(min: Float, max: Float) -> Float {
     let mut rng = rand::thread_rng();
     rng.gen_range(min..max)
 }
@@ -1,20 +1,68 @@
+use crate::float::Float;
 use crate::vec3::{Vec3, Point3};
 
+/// Below this distance from the origin, points are offset by a fixed amount rather than by ulps.
+/// Near zero, a few ulps is far too little to cover the error in anything computed there.
+const ORIGIN: Float = 1.0 / 32.0;
+const FLOAT_SCALE: Float = 1.0 / 65536.0;
+const INT_SCALE: Float = 256.0;
+
 #[derive(Debug, Default, Copy, Clone)]
 pub struct Ray {
     pub origin: Point3,
     pub direction: Vec3,
-    pub time: f64,
+    pub time: Float,
 }
 
 impl Ray {
 
-    pub fn new(origin: Point3, direction: Vec3, time: f64) -> Ray {
+    pub fn new(origin: Point3, direction: Vec3, time: Float) -> Ray {
         Ray{origin, direction, time}
     }
 
-    pub fn at(&self, t: f64) -> Point3 {
+    pub fn at(&self, t: Float) -> Point3 {
         self.origin + t*self.direction
     }
 
 }
+
+/**
+ * Moves a point just off a surface along `normal`, so that a ray started there and traced from
+ * t = 0 can't hit the same surface where it left, whatever precision the hit was found in. The
+ * step is a number of ulps in each coordinate, so it grows with the point's distance from the
+ * origin just as the rounding error in finding it does. This is Wächter and Binder's method from
+ * Ray Tracing Gems, chapter 6.
+ */
+pub fn offset_origin(point: Point3, normal: Vec3) -> Point3 {
+    let offset = |p: Float, n: Float| {
+        if p.abs() < ORIGIN {
+            return p + FLOAT_SCALE * n;
+        }
+        // Stepping the bits of a negative float makes it more negative, so the step flips to
+        // move the same way as the normal.
+        let ulps = (INT_SCALE * n) as i64;
+        let bits = p.to_bits() as i64 + if p < 0.0 { -ulps } else { ulps };
+        Float::from_bits(bits as _)
+    };
+    Point3::new(offset(point.x, normal.x), offset(point.y, normal.y), offset(point.z, normal.z))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::ray::offset_origin;
+    use crate::vec3::{dot, Point3, Vec3};
+
+    #[test]
+    fn offsets() {
+        // Every point ends up strictly on the side the normal points to, however big it is.
+        let normal = Vec3::new(0.6, -0.8, 0.0);
+        for scale in [0.0, 1e-3, 1.0, 1e3, 1e6] {
+            let point = Point3::new(scale, -2.0*scale, 3.0*scale);
+            let moved = offset_origin(point, normal);
+            assert!(dot(moved - point, normal) > 0.0);
+            assert_eq!(moved.z, point.z);
+            assert!((moved - point).length() <= 1e-3 * (1.0 + scale));
+        }
+    }
+}
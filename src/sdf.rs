@@ -0,0 +1,262 @@
+use crate::aabb::AABB;
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitRecord};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::sphere::get_sphere_uv;
+use crate::vec3::{dot, Point3, unit_vector, Vec3};
+use std::sync::Arc;
+
+/**
+ * A signed distance function: how far a point is from the nearest surface, negative inside. It
+ * only has to be a lower bound for sphere tracing to work, so approximations that never overshoot
+ * are fine.
+ */
+pub type Sdf = Arc<dyn Fn(Point3) -> Float + Send + Sync>;
+
+/// Sphere tracing gives up after this many steps and calls it a miss.
+const MAX_STEPS: usize = 512;
+
+/// How close to the surface counts as on it.
+const EPSILON: Float = 1e-5;
+
+/**
+ * Renders any distance function by sphere tracing: stepping along the ray by the distance to the
+ * nearest surface until it's close enough to call a hit. The bounding box is required, both to
+ * give BVHNode something to work with and to limit the search, so it has to contain the whole
+ * surface. Normals come from the gradient of the distance, and the texture coordinates are the
+ * sphere's, measured from the middle of the box.
+ */
+pub struct SdfObject {
+    sdf: Sdf,
+    bounds: AABB,
+    material: Material,
+}
+
+impl SdfObject {
+    pub fn new(sdf: Sdf, bounds: AABB, material: Material) -> HitArc {
+        Arc::new(SdfObject{ sdf, bounds, material })
+    }
+
+    /// The gradient by differences along a tetrahedron's corners, which needs four evaluations
+    /// instead of six.
+    fn normal(&self, p: Point3) -> Vec3 {
+        // The error is first order in the step, and the rounding in each difference goes as
+        // epsilon over the step, so the square root of epsilon balances the two. A fixed small
+        // step is all rounding in f32.
+        let h = Float::EPSILON.sqrt() * (1.0 + p.length());
+        let corners = [
+            Vec3::new(1.0, -1.0, -1.0), Vec3::new(-1.0, -1.0, 1.0),
+            Vec3::new(-1.0, 1.0, -1.0), Vec3::new(1.0, 1.0, 1.0),
+        ];
+        let gradient = corners.iter().fold(Vec3::default(), |sum, &k| sum + k * (self.sdf)(p + h*k));
+        unit_vector(gradient)
+    }
+}
+
+impl Hit for SdfObject {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let (enter, exit) = self.bounds.clip(ray, t_min, t_max)?;
+
+        // Distances are along the ray in world units, so t moves by distance over speed. Rays
+        // starting inside march out on the absolute distance just the same.
+        let speed = ray.direction.length();
+        let mut t = enter;
+        for _ in 0..MAX_STEPS {
+            let point = ray.at(t);
+            let distance = (self.sdf)(point).abs();
+
+            let tolerance = EPSILON * (1.0 + t*speed);
+            if distance < tolerance {
+                let center = 0.5 * (self.bounds.min + self.bounds.max);
+                let (mut u, mut v) = (0.0, 0.0);
+                get_sphere_uv(unit_vector(point - center), &mut u, &mut v);
+                let mut hitrec = HitRecord::new(ray, t, self.normal(point), u, v, &self.material);
+                // The point can be up to the tolerance inside, and a ray leaving it has to get
+                // more than the tolerance clear to march away rather than stop where it starts.
+                hitrec.error = 2.0 * tolerance;
+                return Some(hitrec);
+            }
+
+            t += distance / speed;
+            if t > exit {
+                break;
+            }
+        }
+
+        None
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        Some(self.bounds)
+    }
+}
+
+pub fn sphere(center: Point3, radius: Float) -> Sdf {
+    Arc::new(move |p| (p - center).length() - radius)
+}
+
+/// An axis-aligned box reaching `half_extents` from its center each way.
+pub fn cuboid(center: Point3, half_extents: Vec3) -> Sdf {
+    Arc::new(move |p| {
+        let p = p - center;
+        let q = Vec3::new(p.x.abs(), p.y.abs(), p.z.abs()) - half_extents;
+        let outside = Vec3::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).length();
+        let inside = q.x.max(q.y).max(q.z).min(0.0);
+        outside + inside
+    })
+}
+
+/// A torus lying flat, around the y axis.
+pub fn torus(center: Point3, major_radius: Float, minor_radius: Float) -> Sdf {
+    Arc::new(move |p| {
+        let p = p - center;
+        let ring = (p.x*p.x + p.z*p.z).sqrt() - major_radius;
+        (ring*ring + p.y*p.y).sqrt() - minor_radius
+    })
+}
+
+/// A cylinder from a to b with rounded ends.
+pub fn capsule(a: Point3, b: Point3, radius: Float) -> Sdf {
+    Arc::new(move |p| {
+        let pa = p - a;
+        let ba = b - a;
+        let h = (dot(pa, ba) / dot(ba, ba)).clamp(0.0, 1.0);
+        (pa - h*ba).length() - radius
+    })
+}
+
+/// Joins two shapes with a fillet about k wide where they meet.
+pub fn smooth_union(a: Sdf, b: Sdf, k: Float) -> Sdf {
+    Arc::new(move |p| {
+        let (da, db) = (a(p), b(p));
+        let h = (0.5 + 0.5*(db - da)/k).clamp(0.0, 1.0);
+        lerp(db, da, h) - k*h*(1.0 - h)
+    })
+}
+
+/// Carves b out of a, rounding the edges of the cut by about k.
+pub fn smooth_subtract(a: Sdf, b: Sdf, k: Float) -> Sdf {
+    Arc::new(move |p| {
+        let (da, db) = (a(p), b(p));
+        let h = (0.5 - 0.5*(da + db)/k).clamp(0.0, 1.0);
+        lerp(da, -db, h) + k*h*(1.0 - h)
+    })
+}
+
+/**
+ * Repeats a shape forever on a grid with the given spacing. The shape should sit in the cell
+ * around the origin and fit inside it, and the bounding box decides how many copies are seen.
+ */
+pub fn repeat(sdf: Sdf, spacing: Vec3) -> Sdf {
+    Arc::new(move |p| {
+        let cell = |x: Float, s: Float| x - s * (x / s).round();
+        sdf(Vec3::new(cell(p.x, spacing.x), cell(p.y, spacing.y), cell(p.z, spacing.z)))
+    })
+}
+
+/**
+ * Twists a shape around the y axis by `rate` radians per unit of height. Twisting stretches space,
+ * so the distance is shrunk by how much it stretches at that radius to stay a lower bound.
+ */
+pub fn twist(sdf: Sdf, rate: Float) -> Sdf {
+    Arc::new(move |p| {
+        let (sin, cos) = (rate * p.y).sin_cos();
+        let q = Vec3::new(cos*p.x + sin*p.z, p.y, -sin*p.x + cos*p.z);
+        let radius = (p.x*p.x + p.z*p.z).sqrt();
+        sdf(q) / (1.0 + (rate*radius).powi(2)).sqrt()
+    })
+}
+
+/**
+ * The Mandelbulb fractal, as big as a sphere of about `scale` radius. This uses the usual
+ * distance estimate from the running derivative of the iteration, which is close but not exact,
+ * so it's halved to keep the steps from overshooting the finer detail.
+ */
+pub fn mandelbulb(center: Point3, scale: Float, power: Float, iterations: usize) -> Sdf {
+    Arc::new(move |p| {
+        let c = (p - center) / scale;
+        let mut z = c;
+        let mut dr = 1.0;
+        let mut r = z.length();
+
+        for _ in 0..iterations {
+            if r > 2.0 {
+                break;
+            }
+            let theta = (z.z / r).acos() * power;
+            let phi = z.y.atan2(z.x) * power;
+            dr = r.powf(power - 1.0) * power * dr + 1.0;
+            let zr = r.powf(power);
+            z = zr * Vec3::new(theta.sin()*phi.cos(), theta.sin()*phi.sin(), theta.cos()) + c;
+            r = z.length();
+        }
+
+        // Points right at the center never escape, and the estimate there is zero.
+        if r == 0.0 {
+            return 0.0;
+        }
+        0.25 * r.ln() * r / dr * scale
+    })
+}
+
+fn lerp(a: Float, b: Float, t: Float) -> Float {
+    a + (b - a)*t
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::aabb::AABB;
+    use crate::float::Float;
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+    use crate::sdf::{cuboid, repeat, sphere, smooth_union, twist, SdfObject};
+    use crate::vec3::{Color, Point3, Vec3};
+
+    #[test]
+    fn sphere_tracing() {
+        let material = || Lambertian::from_color(Color::default());
+        let bounds = AABB::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
+        let ball = SdfObject::new(sphere(Point3::default(), 1.0), bounds, material());
+
+        let ray = Ray::new(Point3::new(0.3, 0.2, 10.0), Vec3::new(0.0, 0.0, -2.0), 0.0);
+        let hitrec = ball.hit(ray, 0.001, Float::INFINITY).unwrap();
+        let expected = (10.0 - Float::sqrt(1.0 - 0.13)) / 2.0;
+        assert!((hitrec.t - expected).abs() < 1e-4);
+        assert!((hitrec.normal - hitrec.point).length() < 1e-3);
+
+        // From inside, the first surface is on the way out.
+        let ray = Ray::new(Point3::default(), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        let hitrec = ball.hit(ray, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 1.0).abs() < 1e-4 && !hitrec.front_face);
+
+        // Copies of a small sphere every two units, with the ray passing between them.
+        let grid = repeat(sphere(Point3::default(), 0.5), Vec3::new(2.0, 2.0, 2.0));
+        let bounds = AABB::new(Point3::new(-5.0, -5.0, -5.0), Point3::new(5.0, 5.0, 5.0));
+        let grid = SdfObject::new(grid, bounds, material());
+        let ray = Ray::new(Point3::new(1.0, 1.0, 10.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(grid.hit(ray, 0.001, Float::INFINITY).is_none());
+        let ray = Ray::new(Point3::new(4.0, 2.0, 10.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hitrec = grid.hit(ray, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 5.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn combinators() {
+        let at = |x, y, z| Point3::new(x, y, z);
+        let a = sphere(at(-0.5, 0.0, 0.0), 0.6);
+        let b = sphere(at(0.5, 0.0, 0.0), 0.6);
+        let blend = smooth_union(a.clone(), b.clone(), 0.3);
+        // The blend is never further out than the plain union, and bulges in between.
+        for x in &[-1.5, -0.5, 0.0, 0.5, 1.5] {
+            let p = at(*x, 0.3, 0.0);
+            assert!(blend(p) <= a(p).min(b(p)) + 1e-12);
+        }
+        assert!(blend(at(0.0, 0.45, 0.0)) < a(at(0.0, 0.45, 0.0)).min(b(at(0.0, 0.45, 0.0))));
+
+        // Twisting leaves the axis where it was.
+        let bar = twist(cuboid(Point3::default(), Vec3::new(1.0, 2.0, 0.2)), 0.5);
+        assert!((bar(at(0.0, 1.0, 0.0)) + 0.2).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,313 @@
+use crate::aabb::AABB;
+use crate::float::Float;
+use crate::ray::Ray;
+use lanes::F4;
+
+/**
+ * Four boxes stored axis by axis, so that a ray can be tested against all of them at once. A lane
+ * without a box holds one that's inside out, from infinity to minus infinity, which no ray hits.
+ */
+#[derive(Debug, Copy, Clone)]
+pub struct Box4 {
+    min: [[Float; 4]; 3],
+    max: [[Float; 4]; 3],
+}
+
+impl Default for Box4 {
+    fn default() -> Box4 {
+        Box4{ min: [[Float::INFINITY; 4]; 3], max: [[Float::NEG_INFINITY; 4]; 3] }
+    }
+}
+
+impl Box4 {
+    pub fn set(&mut self, lane: usize, aabb: AABB) {
+        let (min, max) = (aabb.min.as_array(), aabb.max.as_array());
+        for axis in 0..3 {
+            self.min[axis][lane] = min[axis];
+            self.max[axis][lane] = max[axis];
+        }
+    }
+
+    /**
+     * The slab test of `AABB::hit` on all four boxes at once. Returns a mask with a bit set for
+     * each box the ray passes through between t_min and t_max, and where it enters each of them.
+     * Like `AABB::hit`, a NaN from a ray lying in the plane of a slab it runs along is ignored.
+     */
+    pub fn hit(&self, ray: &Slabs, t_min: Float, t_max: Float) -> (u32, [Float; 4]) {
+        let mut t0 = F4::splat(t_min);
+        let mut t1 = F4::splat(t_max);
+        for axis in 0..3 {
+            let (near, far) = if ray.negative[axis] {
+                (&self.max[axis], &self.min[axis])
+            } else {
+                (&self.min[axis], &self.max[axis])
+            };
+            t0 = ((F4::load(near) - ray.origin[axis]) * ray.inv_d[axis]).max(t0);
+            t1 = ((F4::load(far) - ray.origin[axis]) * ray.inv_d[axis]).min(t1);
+        }
+        (t0.less(t1), t0.store())
+    }
+}
+
+/// A ray made ready for testing against boxes four at a time, with everything that depends only
+/// on the ray worked out once.
+pub struct Slabs {
+    origin: [F4; 3],
+    inv_d: [F4; 3],
+    negative: [bool; 3],
+}
+
+impl Slabs {
+    pub fn new(ray: Ray) -> Slabs {
+        let (origin, direction) = (ray.origin.as_array(), ray.direction.as_array());
+        let inv_d = direction.map(|d| 1.0 / d);
+        Slabs{ origin: origin.map(F4::splat), inv_d: inv_d.map(F4::splat), negative: inv_d.map(|d| d < 0.0) }
+    }
+}
+
+/**
+ * Four floats operated on together. On x86_64 they live in SSE2 registers, which every x86_64
+ * processor has, so there's nothing to detect at run time. Anywhere else they're a plain array
+ * that the compiler may or may not vectorize.
+ */
+#[cfg(all(target_arch = "x86_64", not(feature = "f32")))]
+mod lanes {
+    use std::arch::x86_64::*;
+    use std::ops;
+
+    /// A register holds two f64s, so four take a pair of them.
+    #[derive(Copy, Clone)]
+    pub struct F4(__m128d, __m128d);
+
+    // SAFETY: the intrinsics only need SSE2, which is part of the x86_64 baseline, and the loads
+    // and stores stay within the arrays they're given.
+    impl F4 {
+        pub fn splat(x: f64) -> F4 {
+            unsafe { F4(_mm_set1_pd(x), _mm_set1_pd(x)) }
+        }
+
+        pub fn load(x: &[f64; 4]) -> F4 {
+            unsafe { F4(_mm_loadu_pd(x.as_ptr()), _mm_loadu_pd(x[2..].as_ptr())) }
+        }
+
+        pub fn store(self) -> [f64; 4] {
+            let mut x = [0.0; 4];
+            unsafe {
+                _mm_storeu_pd(x.as_mut_ptr(), self.0);
+                _mm_storeu_pd(x[2..].as_mut_ptr(), self.1);
+            }
+            x
+        }
+
+        /// Lane by lane, `other` wherever either is NaN.
+        pub fn min(self, other: F4) -> F4 {
+            unsafe { F4(_mm_min_pd(self.0, other.0), _mm_min_pd(self.1, other.1)) }
+        }
+
+        /// Lane by lane, `other` wherever either is NaN.
+        pub fn max(self, other: F4) -> F4 {
+            unsafe { F4(_mm_max_pd(self.0, other.0), _mm_max_pd(self.1, other.1)) }
+        }
+
+        /// A bit for each lane that's less than the same lane of `other`.
+        pub fn less(self, other: F4) -> u32 {
+            unsafe {
+                let low = _mm_movemask_pd(_mm_cmplt_pd(self.0, other.0));
+                let high = _mm_movemask_pd(_mm_cmplt_pd(self.1, other.1));
+                (low | high << 2) as u32
+            }
+        }
+    }
+
+    impl ops::Sub for F4 {
+        type Output = F4;
+
+        fn sub(self, rhs: F4) -> F4 {
+            unsafe { F4(_mm_sub_pd(self.0, rhs.0), _mm_sub_pd(self.1, rhs.1)) }
+        }
+    }
+
+    impl ops::Mul for F4 {
+        type Output = F4;
+
+        fn mul(self, rhs: F4) -> F4 {
+            unsafe { F4(_mm_mul_pd(self.0, rhs.0), _mm_mul_pd(self.1, rhs.1)) }
+        }
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "f32"))]
+mod lanes {
+    use std::arch::x86_64::*;
+    use std::ops;
+
+    /// Four f32s fill one register.
+    #[derive(Copy, Clone)]
+    pub struct F4(__m128);
+
+    // SAFETY: as for the f64 lanes.
+    impl F4 {
+        pub fn splat(x: f32) -> F4 {
+            unsafe { F4(_mm_set1_ps(x)) }
+        }
+
+        pub fn load(x: &[f32; 4]) -> F4 {
+            unsafe { F4(_mm_loadu_ps(x.as_ptr())) }
+        }
+
+        pub fn store(self) -> [f32; 4] {
+            let mut x = [0.0; 4];
+            unsafe { _mm_storeu_ps(x.as_mut_ptr(), self.0) };
+            x
+        }
+
+        /// Lane by lane, `other` wherever either is NaN.
+        pub fn min(self, other: F4) -> F4 {
+            unsafe { F4(_mm_min_ps(self.0, other.0)) }
+        }
+
+        /// Lane by lane, `other` wherever either is NaN.
+        pub fn max(self, other: F4) -> F4 {
+            unsafe { F4(_mm_max_ps(self.0, other.0)) }
+        }
+
+        /// A bit for each lane that's less than the same lane of `other`.
+        pub fn less(self, other: F4) -> u32 {
+            unsafe { _mm_movemask_ps(_mm_cmplt_ps(self.0, other.0)) as u32 }
+        }
+    }
+
+    impl ops::Sub for F4 {
+        type Output = F4;
+
+        fn sub(self, rhs: F4) -> F4 {
+            unsafe { F4(_mm_sub_ps(self.0, rhs.0)) }
+        }
+    }
+
+    impl ops::Mul for F4 {
+        type Output = F4;
+
+        fn mul(self, rhs: F4) -> F4 {
+            unsafe { F4(_mm_mul_ps(self.0, rhs.0)) }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod lanes {
+    use crate::float::Float;
+    use std::ops;
+
+    #[derive(Copy, Clone)]
+    pub struct F4([Float; 4]);
+
+    impl F4 {
+        pub fn splat(x: Float) -> F4 {
+            F4([x; 4])
+        }
+
+        pub fn load(x: &[Float; 4]) -> F4 {
+            F4(*x)
+        }
+
+        pub fn store(self) -> [Float; 4] {
+            self.0
+        }
+
+        /// Lane by lane, `other` wherever either is NaN.
+        pub fn min(self, other: F4) -> F4 {
+            F4([0, 1, 2, 3].map(|i| if self.0[i] < other.0[i] { self.0[i] } else { other.0[i] }))
+        }
+
+        /// Lane by lane, `other` wherever either is NaN.
+        pub fn max(self, other: F4) -> F4 {
+            F4([0, 1, 2, 3].map(|i| if self.0[i] > other.0[i] { self.0[i] } else { other.0[i] }))
+        }
+
+        /// A bit for each lane that's less than the same lane of `other`.
+        pub fn less(self, other: F4) -> u32 {
+            (0..4).filter(|&i| self.0[i] < other.0[i]).fold(0, |mask, i| mask | 1 << i)
+        }
+    }
+
+    impl ops::Sub for F4 {
+        type Output = F4;
+
+        fn sub(self, rhs: F4) -> F4 {
+            F4([0, 1, 2, 3].map(|i| self.0[i] - rhs.0[i]))
+        }
+    }
+
+    impl ops::Mul for F4 {
+        type Output = F4;
+
+        fn mul(self, rhs: F4) -> F4 {
+            F4([0, 1, 2, 3].map(|i| self.0[i] * rhs.0[i]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::aabb::AABB;
+    use crate::float::Float;
+    use crate::rand::rfloat;
+    use crate::ray::Ray;
+    use crate::simd::{Box4, Slabs};
+    use crate::vec3::{Point3, Vec3};
+
+    #[test]
+    fn agrees_with_aabb() {
+        // Some rays run along an axis, and some of those start in the plane of a face.
+        let ray = |i: usize| {
+            let origin = Vec3::randrange(-3.0, 3.0);
+            let mut direction = Vec3::randrange(-1.0, 1.0);
+            match i % 4 {
+                1 => direction = Vec3::new(0.0, direction.y, 0.0),
+                2 => direction = Vec3::new(direction.x, 0.0, -0.0),
+                _ => {}
+            }
+            Ray::new(origin, direction, 0.0)
+        };
+        for i in 0..2000 {
+            let boxes = [0, 1, 2, 3].map(|_| {
+                let corner = Vec3::randrange(-2.0, 2.0);
+                AABB::new(corner, corner + Vec3::randrange(0.0, 2.0))
+            });
+            let mut box4 = Box4::default();
+            for (lane, aabb) in boxes.iter().enumerate() {
+                box4.set(lane, *aabb);
+            }
+            let mut ray = ray(i);
+            if i % 4 == 3 {
+                ray.origin = Point3::new(boxes[0].min.x, ray.origin.y, ray.origin.z);
+                ray.direction = Vec3::new(0.0, ray.direction.y, ray.direction.z);
+            }
+            let (t_min, t_max) = (rfloat(), 1.0 + 5.0 * rfloat());
+
+            let (mask, near) = box4.hit(&Slabs::new(ray), t_min, t_max);
+            let (origin, direction) = (ray.origin.as_array(), ray.direction.as_array());
+            let inv_d = direction.map(|d| 1.0 / d);
+            for (lane, aabb) in boxes.iter().enumerate() {
+                let hit = aabb.hit(origin, inv_d, t_min, t_max);
+                assert_eq!(mask & 1 << lane != 0, hit);
+                if hit {
+                    assert_eq!(Some(near[lane]), aabb.clip(ray, t_min, t_max).map(|(t0, _)| t0));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_lanes_are_never_hit() {
+        let mut box4 = Box4::default();
+        box4.set(2, AABB::new(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0)));
+        for direction in [Vec3::new(1.0, 0.0, 0.0), Vec3::new(-0.0, -1.0, 0.0), Vec3::new(0.3, -0.2, 0.9)] {
+            let ray = Ray::new(Point3::new(0.0, 0.0, 0.0), direction, 0.0);
+            let (mask, _) = box4.hit(&Slabs::new(ray), 0.0, Float::INFINITY);
+            assert_eq!(mask, 1 << 2);
+        }
+    }
+}
@@ -1,70 +1,93 @@
 use crate::aabb::AABB;
+use crate::float::Float;
 use crate::hit::{Hit, HitArc, HitRecord};
 use crate::ray::Ray;
-use crate::vec3::{dot, Point3, Vec3};
+use crate::vec3::{dot, Point3, unit_vector, Vec3};
 use crate::material::Material;
 use std::sync::Arc;
 
 pub struct Sphere {
     pub center: Point3,
-    pub radius: f64,
+    pub radius: Float,
     pub material: Material,
 }
 
 impl Sphere {
-    pub fn new(center: Point3, radius: f64, material: Material) -> HitArc {
+    pub fn new(center: Point3, radius: Float, material: Material) -> HitArc {
         Arc::new(Sphere{ center, radius, material })
     }
+
+    fn record(&self, ray: Ray, t: Float) -> HitRecord<'_> {
+        let outward_normal = unit_vector(ray.at(t) - self.center);
+        let (mut u, mut v) = (0.0, 0.0);
+        get_sphere_uv(outward_normal, &mut u, &mut v);
+        let mut hitrec = HitRecord::new(ray, t, outward_normal, u, v, &self.material);
+        // The root of the quadratic can be well off for rays from far away, so the point is put
+        // back on the surface rather than left wherever ray.at(t) lands.
+        hitrec.point = self.center + self.radius*outward_normal;
+        hitrec
+    }
 }
 
-impl Hit for Sphere {
-    fn hit(&self, ray: Ray, t_min: f64, t_max: f64, hitrec: &mut HitRecord) -> bool {
-        let oc = ray.origin - self.center;
-        let a = ray.direction.length_squared();
-        let half_b = dot(oc, ray.direction);
-        let c = oc.length_squared() - self.radius*self.radius;
+/// Where a ray first meets a sphere within range, if it does.
+pub fn sphere_hit(center: Point3, radius: Float, ray: Ray, t_min: Float, t_max: Float) -> Option<Float> {
+    let (near, far) = sphere_roots(center, radius, ray)?;
+    [near, far].iter().copied().find(|&root| t_min <= root && root <= t_max)
+}
 
-        let discriminant = half_b*half_b - a*c;
-        if discriminant < 0. {
-            return false;
-        }
+/// Where the whole line of a ray goes into a sphere and out again, if it meets it at all.
+fn sphere_roots(center: Point3, radius: Float, ray: Ray) -> Option<(Float, Float)> {
+    let oc = ray.origin - center;
+    let a = ray.direction.length_squared();
+    let half_b = dot(oc, ray.direction);
+    let c = oc.length_squared() - radius*radius;
 
-        let sqrtd = discriminant.sqrt();
-        let mut root = (-half_b - sqrtd) / a; // subtract sqrtd
-        if root < t_min || t_max < root {
-            root = (-half_b + sqrtd) / a; // plus this time
-            if root < t_min || t_max < root {
-                return false;
-            }
-        }
+    let discriminant = half_b*half_b - a*c;
+    if discriminant < 0. {
+        return None;
+    }
 
-        hitrec.t = root;
-        hitrec.point = ray.at(hitrec.t);
-        let outward_normal = (hitrec.point - self.center) / self.radius;
-        hitrec.set_face_normal(ray, outward_normal);
+    let sqrtd = discriminant.sqrt();
+    Some(((-half_b - sqrtd) / a, (-half_b + sqrtd) / a))
+}
+
+impl Hit for Sphere {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        sphere_hit(self.center, self.radius, ray, t_min, t_max).map(|t| self.record(ray, t))
+    }
 
-        get_sphere_uv(outward_normal, &mut hitrec.u, &mut hitrec.v);
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        sphere_hit(self.center, self.radius, ray, t_min, t_max).is_some()
+    }
 
-        // Note that we're cloning the enclosing Arc, not the material itself.
-        hitrec.material = Some(self.material.clone());
+    fn hit_all<'a>(&'a self, ray: Ray, t_min: Float, t_max: Float, hits: &mut Vec<HitRecord<'a>>) {
+        if let Some((near, far)) = sphere_roots(self.center, self.radius, ray) {
+            for root in [near, far] {
+                if t_min <= root && root <= t_max {
+                    hits.push(self.record(ray, root));
+                }
+            }
+        }
+    }
 
-        true
+    fn span(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<(Float, Float)> {
+        let (near, far) = sphere_roots(self.center, self.radius, ray)?;
+        if t_min <= near && far <= t_max { Some((near, far)) } else { None }
     }
 
-    fn bounding_box(&self, _time0: f64, _time1: f64, aabb: &mut AABB) -> bool {
-        *aabb = AABB::new(
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        Some(AABB::new(
             self.center - Vec3::new(self.radius, self.radius, self.radius),
             self.center + Vec3::new(self.radius, self.radius, self.radius)
-        );
-        true
+        ))
     }
 
 }
 
-pub fn get_sphere_uv(p: Point3, u: &mut f64, v: &mut f64) {
+pub fn get_sphere_uv(p: Point3, u: &mut Float, v: &mut Float) {
     let theta = (-p.y).acos();
-    let phi = (-p.z).atan2(p.x) + std::f64::consts::PI;
+    let phi = (-p.z).atan2(p.x) + crate::float::consts::PI;
 
-    *u = phi / (2.0*std::f64::consts::PI);
-    *v = theta / std::f64::consts::PI;
+    *u = phi / (2.0*crate::float::consts::PI);
+    *v = theta / crate::float::consts::PI;
 }
@@ -0,0 +1,180 @@
+use crate::float::Float;
+use crate::hit::HitArc;
+use crate::material::Material;
+use crate::mesh::{Face, MeshError, TriangleMesh};
+use crate::vec3::Point3;
+use std::collections::HashMap;
+use std::fs;
+
+/**
+ * Loads an STL file, ASCII or binary, as a TriangleMesh. STL stores every triangle on its own, so
+ * identical vertices are welded back together to share them. The stored facet normals are often
+ * unreliable and are ignored in favor of the winding order.
+ */
+pub fn load(filename: &str, material: Material) -> Result<HitArc, MeshError> {
+    let data = fs::read(filename)?;
+    parse(&data, filename, material)
+}
+
+fn parse(data: &[u8], filename: &str, material: Material) -> Result<HitArc, MeshError> {
+    // Binary files are allowed to start with "solid" too, so the size is the reliable test.
+    let binary_size = if data.len() >= 84 {
+        Some(84 + 50 * u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize)
+    } else {
+        None
+    };
+
+    let triangles = if binary_size == Some(data.len()) {
+        parse_binary(data)
+    } else if data.starts_with(b"solid") {
+        parse_ascii(data, filename)?
+    } else if let Some(size) = binary_size {
+        return Err(MeshError::parse(filename, 0, format!(
+            "binary STL should be {} bytes for its triangle count, found {}", size, data.len())));
+    } else {
+        return Err(MeshError::parse(filename, 0, "too short to be an STL file"));
+    };
+
+    if triangles.is_empty() {
+        return Err(MeshError::parse(filename, 0, "no facets found"));
+    }
+
+    let mut welded: HashMap<[_; 3], usize> = HashMap::new();
+    let mut positions = Vec::new();
+    let mut faces = Vec::with_capacity(triangles.len());
+
+    for triangle in triangles {
+        let mut face = Face::default();
+        for (k, p) in triangle.iter().enumerate() {
+            let key = [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+            face.vertices[k] = *welded.entry(key).or_insert_with(|| {
+                positions.push(*p);
+                positions.len() - 1
+            });
+        }
+        faces.push(face);
+    }
+
+    Ok(TriangleMesh::new(positions, Vec::new(), Vec::new(), Vec::new(), faces, vec![material]))
+}
+
+fn parse_binary(data: &[u8]) -> Vec<[Point3; 3]> {
+    data[84..].chunks_exact(50).map(|record| {
+        let float = |offset: usize| f32::from_le_bytes([
+            record[offset], record[offset+1], record[offset+2], record[offset+3],
+        ]) as Float;
+        // Each record is a normal, three vertices and a two byte attribute count.
+        let vertex = |k: usize| {
+            let offset = 12 + 12*k;
+            Point3::new(float(offset), float(offset + 4), float(offset + 8))
+        };
+        [vertex(0), vertex(1), vertex(2)]
+    }).collect()
+}
+
+fn parse_ascii(data: &[u8], filename: &str) -> Result<Vec<[Point3; 3]>, MeshError> {
+    let text = std::str::from_utf8(data)
+        .map_err(|_| MeshError::parse(filename, 0, "ASCII STL isn't valid text"))?;
+
+    let mut triangles = Vec::new();
+    let mut vertices: Vec<Point3> = Vec::with_capacity(3);
+    let mut in_loop = false;
+
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let error = |message: String| MeshError::parse(filename, number, message);
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+
+        match tokens.first().copied() {
+            Some("outer") => {
+                if in_loop {
+                    return Err(error(String::from("nested outer loop")));
+                }
+                in_loop = true;
+                vertices.clear();
+            }
+            Some("vertex") => {
+                if !in_loop {
+                    return Err(error(String::from("vertex outside of an outer loop")));
+                }
+                if tokens.len() != 4 {
+                    return Err(error(format!("vertex needs 3 coordinates, found {}", tokens.len() - 1)));
+                }
+                let mut v = [0.0; 3];
+                for (k, token) in tokens[1..].iter().enumerate() {
+                    v[k] = token.parse::<Float>().map_err(|_| error(format!("'{}' is not a number", token)))?;
+                }
+                vertices.push(Point3::from_array(v));
+            }
+            Some("endloop") => {
+                if !in_loop || vertices.len() != 3 {
+                    return Err(error(format!("facet has {} vertices, expected 3", vertices.len())));
+                }
+                in_loop = false;
+                triangles.push([vertices[0], vertices[1], vertices[2]]);
+            }
+            Some("solid") | Some("endsolid") | Some("facet") | Some("endfacet") | None => {}
+            Some(other) => return Err(error(format!("unexpected '{}'", other))),
+        }
+    }
+
+    if in_loop {
+        return Err(MeshError::parse(filename, 0, "file ends inside an outer loop"));
+    }
+
+    Ok(triangles)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::material::Lambertian;
+    use crate::mesh::MeshError;
+    use crate::stl::parse;
+    use crate::vec3::Color;
+
+    const TETRAHEDRON: [[f32; 3]; 4] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    const FACES: [[usize; 3]; 4] = [[0, 2, 1], [0, 1, 3], [0, 3, 2], [1, 2, 3]];
+
+    #[test]
+    fn ascii_and_binary() {
+        let material = || Lambertian::from_color(Color::default());
+
+        let mut ascii = String::from("solid tetrahedron\n");
+        for face in FACES.iter() {
+            ascii += "facet normal 0 0 0\nouter loop\n";
+            for &i in face {
+                let p = TETRAHEDRON[i];
+                ascii += &format!("vertex {} {} {}\n", p[0], p[1], p[2]);
+            }
+            ascii += "endloop\nendfacet\n";
+        }
+        ascii += "endsolid tetrahedron\n";
+
+        // A binary header that starts with "solid" like plenty of exporters write.
+        let mut binary = b"solid".to_vec();
+        binary.resize(80, 0);
+        binary.extend_from_slice(&(FACES.len() as u32).to_le_bytes());
+        for face in FACES.iter() {
+            binary.extend_from_slice(&[0; 12]);
+            for &i in face {
+                for c in TETRAHEDRON[i].iter() {
+                    binary.extend_from_slice(&c.to_le_bytes());
+                }
+            }
+            binary.extend_from_slice(&[0; 2]);
+        }
+
+        for data in &[ascii.into_bytes(), binary.clone()] {
+            let mesh = parse(data, "test.stl", material()).unwrap();
+            let aabb = mesh.bounding_box(0.0, 1.0).unwrap();
+            assert!(aabb.max.x >= 1.0 && aabb.max.y >= 1.0 && aabb.max.z >= 1.0);
+        }
+
+        binary.pop();
+        assert!(matches!(parse(&binary, "test.stl", material()), Err(MeshError::Parse{ .. })));
+
+        let broken = "solid x\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0\n";
+        assert!(matches!(parse(broken.as_bytes(), "test.stl", material()), Err(MeshError::Parse{ line: 5, .. })));
+    }
+}
@@ -1,13 +1,21 @@
+use crate::float::Float;
+use crate::hit::HitRecord;
 use crate::perlin::PerlinNoise;
 use crate::vec3::{Color, Point3};
 use image::io::Reader as ImageReader;
-use image::RgbImage;
+use image::{ImageResult, RgbImage};
 use std::sync::Arc;
 
 pub type Texture = Arc<dyn Value + Sync + Send>;
 
 pub trait Value {
-    fn value(&self, u: f64, v: f64, p: Point3) -> Color;
+    fn value(&self, u: Float, v: Float, p: Point3) -> Color;
+
+    /// Looks the texture up for a hit. Most textures only need the UV and point, but this lets
+    /// some read other attributes of the hit.
+    fn value_at(&self, hitrec: &HitRecord) -> Color {
+        self.value(hitrec.u, hitrec.v, hitrec.point)
+    }
 }
 
 #[derive(Default)]
@@ -20,13 +28,13 @@ impl SolidColor {
         Arc::new(SolidColor{ color })
     }
 
-    pub fn from_rgb(r: f64, g: f64, b: f64) -> Texture {
+    pub fn from_rgb(r: Float, g: Float, b: Float) -> Texture {
         SolidColor::new(Color::new(r, g, b))
     }
 }
 
 impl Value for SolidColor {
-    fn value(&self, _u: f64, _v: f64, _p: Point3) -> Color {
+    fn value(&self, _u: Float, _v: Float, _p: Point3) -> Color {
         self.color
     }
 }
@@ -46,7 +54,7 @@ impl Checker {
 }
 
 impl Value for Checker {
-    fn value(&self, u: f64, v: f64, p: Point3) -> Color {
+    fn value(&self, u: Float, v: Float, p: Point3) -> Color {
         let sines = (10.0*p.x).sin() * (10.0*p.y).sin() * (10.0*p.z).sin();
         if sines < 0.0 {
             self.odd.value(u, v, p)
@@ -58,17 +66,17 @@ impl Value for Checker {
 
 pub struct Perlin {
     noise: PerlinNoise,
-    scale: f64,
+    scale: Float,
 }
 
 impl Perlin {
-    pub fn new(scale: f64) -> Texture {
+    pub fn new(scale: Float) -> Texture {
         Arc::new(Perlin{ noise: PerlinNoise::new(), scale })
     }
 }
 
 impl Value for Perlin {
-    fn value(&self, _u: f64, _v: f64, p: Point3) -> Color {
+    fn value(&self, _u: Float, _v: Float, p: Point3) -> Color {
         Color::new(1.0, 1.0, 1.0) * 0.5 * (1.0 + (self.scale * p.z + 10.0*self.noise.turb(p, 7)).sin())
     }
 }
@@ -81,33 +89,58 @@ pub struct Image {
 
 impl Image {
     pub fn new(filename: &str) -> Texture {
-        // Why is this not working with the question mark operator?
-        let data = ImageReader::open(filename).unwrap().decode().unwrap().into_rgb8();
+        Image::open(filename).unwrap()
+    }
+
+    pub fn open(filename: &str) -> ImageResult<Texture> {
+        let data = ImageReader::open(filename)?.decode()?.into_rgb8();
         let width = data.width();
         let height = data.height();
-        Arc::new(Image{ data, width, height })
+        Ok(Arc::new(Image{ data, width, height }))
     }
 }
 
 impl Value for Image {
-    fn value(&self, u: f64, v: f64, _p: Point3) -> Color {
+    fn value(&self, u: Float, v: Float, _p: Point3) -> Color {
         let u = u.max(0.0).min(1.0);
         let v = 1.0 - v.max(0.0).min(1.0);
 
-        let i = (u * self.width as f64) as u32;
-        let j = (v * self.height as f64) as u32;
+        let i = (u * self.width as Float) as u32;
+        let j = (v * self.height as Float) as u32;
 
         let i = i.min(self.width-1);
         let j = j.min(self.height-1);
 
-        const COLOR_SCALE: f64 = 1.0 / 255.0;
+        const COLOR_SCALE: Float = 1.0 / 255.0;
 
         let p = self.data.get_pixel(i, j);
 
         Color::new(
-            COLOR_SCALE * p[0] as f64,
-            COLOR_SCALE * p[1] as f64,
-            COLOR_SCALE * p[2] as f64,
+            COLOR_SCALE * p[0] as Float,
+            COLOR_SCALE * p[1] as Float,
+            COLOR_SCALE * p[2] as Float,
         )
     }
 }
+
+/// The color interpolated from the vertices of a mesh, for meshes that carry per-vertex colors.
+/// Anything else gets the fallback color.
+pub struct VertexColor {
+    fallback: Color,
+}
+
+impl VertexColor {
+    pub fn new(fallback: Color) -> Texture {
+        Arc::new(VertexColor{ fallback })
+    }
+}
+
+impl Value for VertexColor {
+    fn value(&self, _u: Float, _v: Float, _p: Point3) -> Color {
+        self.fallback
+    }
+
+    fn value_at(&self, hitrec: &HitRecord) -> Color {
+        hitrec.color.unwrap_or(self.fallback)
+    }
+}
@@ -0,0 +1,215 @@
+use crate::aabb::AABB;
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitRecord};
+use crate::material::Material;
+use crate::onb::Onb;
+use crate::quad::disk_extent;
+use crate::ray::Ray;
+use crate::vec3::{dot, Point3, unit_vector, Vec3};
+use crate::float::consts::PI;
+use std::sync::Arc;
+
+/**
+ * A ring around `axis`, with the tube's center line `major_radius` from the center and the tube
+ * itself `minor_radius` thick. u goes the long way around the ring and v around the tube.
+ */
+pub struct Torus {
+    center: Point3,
+    major_radius: Float,
+    minor_radius: Float,
+    frame: Onb,
+    material: Material,
+}
+
+impl Torus {
+    pub fn new(center: Point3, axis: Vec3, major_radius: Float, minor_radius: Float, material: Material) -> HitArc {
+        Arc::new(Torus{ center, major_radius, minor_radius, frame: Onb::from_w(axis), material })
+    }
+}
+
+impl Hit for Torus {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let (big_r, small_r) = (self.major_radius, self.minor_radius);
+
+        // The quartic is solved for a unit direction, so lengths along the ray get rescaled at the
+        // end.
+        let scale = ray.direction.length();
+        let d = self.frame.project(ray.direction / scale);
+        let mut o = self.frame.project(ray.origin - self.center);
+
+        // Skip rays that miss the bounding sphere, and start the others close to it. Roots of a
+        // quartic lose a lot of precision when they're far from the origin.
+        let bound = big_r + small_r;
+        let od = dot(o, d);
+        if o.length_squared() - od*od > bound*bound {
+            return None;
+        }
+        let shift = (-od - bound).max(0.0);
+        o += shift*d;
+
+        // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + y^2), expanded in t.
+        let od = dot(o, d);
+        let k = o.length_squared() + big_r*big_r - small_r*small_r;
+        let r2 = 4.0*big_r*big_r;
+        let c3 = 4.0*od;
+        let c2 = 4.0*od*od + 2.0*k - r2*(d.x*d.x + d.y*d.y);
+        let c1 = 4.0*od*k - 2.0*r2*(o.x*d.x + o.y*d.y);
+        let c0 = k*k - r2*(o.x*o.x + o.y*o.y);
+
+        let nearest = solve_quartic(c3, c2, c1, c0)
+            .into_iter()
+            .map(|t| (t + shift) / scale)
+            .filter(|t| *t >= t_min && *t <= t_max)
+            .fold(None, |best: Option<Float>, t| Some(best.map_or(t, |b| b.min(t))));
+        let t = nearest?;
+
+        let p = self.frame.project(ray.at(t) - self.center);
+        let ring = Vec3::new(p.x, p.y, 0.0);
+        let ring = if ring.near_zero() { Vec3::new(big_r, 0.0, 0.0) } else { big_r * unit_vector(ring) };
+        let outward = unit_vector(p - ring);
+
+        let u = (p.y.atan2(p.x) + PI) / (2.0*PI);
+        let v = (p.z.atan2(dot(p - ring, unit_vector(ring))) + PI) / (2.0*PI);
+        let mut hitrec = HitRecord::new(ray, t, self.frame.local(outward), u, v, &self.material);
+        // The quartic's roots are rough, so the point is put back on the tube around the ring.
+        hitrec.point = self.center + self.frame.local(ring + small_r*outward);
+        Some(hitrec)
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        let extent = disk_extent(self.frame.w, self.major_radius) + self.minor_radius;
+        Some(AABB::new(self.center - extent, self.center + extent))
+    }
+}
+
+/**
+ * The real roots of t^4 + c3 t^3 + c2 t^2 + c1 t + c0 by Ferrari's method, in no particular order.
+ * Each root gets polished with a couple of Newton steps, since the closed form loses precision
+ * exactly where the torus is thin.
+ */
+pub fn solve_quartic(c3: Float, c2: Float, c1: Float, c0: Float) -> Vec<Float> {
+    // Substitute t = y - c3/4 to get y^4 + p y^2 + q y + r.
+    let shift = c3 / 4.0;
+    let p = c2 - 6.0*shift*shift;
+    let q = c1 - 2.0*c2*shift + 8.0*shift*shift*shift;
+    let r = c0 - c1*shift + c2*shift*shift - 3.0*shift*shift*shift*shift;
+
+    let mut roots = Vec::with_capacity(4);
+    if q.abs() < 1e-12 {
+        // Biquadratic, so it's a quadratic in y^2.
+        for z in solve_quadratic(p, r) {
+            if z >= 0.0 {
+                roots.push(z.sqrt());
+                roots.push(-z.sqrt());
+            }
+        }
+    } else {
+        // Adding m to y^2 + p/2 on both sides makes the right a perfect square when m is a root of
+        // this cubic. It always has a positive root when q isn't zero.
+        let m = solve_cubic(p, p*p/4.0 - r, -q*q/8.0)
+            .into_iter()
+            .fold(Float::NEG_INFINITY, Float::max);
+        if m <= 0.0 {
+            return roots;
+        }
+        let s = (2.0*m).sqrt();
+        roots.extend(solve_quadratic(-s, p/2.0 + m + q/(2.0*s)));
+        roots.extend(solve_quadratic(s, p/2.0 + m - q/(2.0*s)));
+    }
+
+    let f = |t: Float| (((t + c3)*t + c2)*t + c1)*t + c0;
+    let df = |t: Float| ((4.0*t + 3.0*c3)*t + 2.0*c2)*t + c1;
+    roots.into_iter().map(|y| {
+        let mut t = y - shift;
+        for _ in 0..2 {
+            let slope = df(t);
+            if slope != 0.0 {
+                t -= f(t) / slope;
+            }
+        }
+        t
+    }).collect()
+}
+
+/// The real roots of t^2 + b t + c.
+fn solve_quadratic(b: Float, c: Float) -> Vec<Float> {
+    let discriminant = b*b/4.0 - c;
+    if discriminant < 0.0 {
+        Vec::new()
+    } else {
+        let sqrtd = discriminant.sqrt();
+        vec![-b/2.0 - sqrtd, -b/2.0 + sqrtd]
+    }
+}
+
+/// The real roots of t^3 + b t^2 + c t + d, by Cardano's formula or the trigonometric form when
+/// there are three of them.
+fn solve_cubic(b: Float, c: Float, d: Float) -> Vec<Float> {
+    let shift = b / 3.0;
+    let p = c - b*b/3.0;
+    let q = 2.0*b*b*b/27.0 - b*c/3.0 + d;
+    let discriminant = q*q/4.0 + p*p*p/27.0;
+
+    if discriminant > 0.0 {
+        let sqrtd = discriminant.sqrt();
+        vec![(-q/2.0 + sqrtd).cbrt() + (-q/2.0 - sqrtd).cbrt() - shift]
+    } else if p == 0.0 {
+        vec![-shift]
+    } else {
+        let radius = 2.0*(-p/3.0).sqrt();
+        let phi = (3.0*q / (p*radius)).clamp(-1.0, 1.0).acos() / 3.0;
+        (0..3).map(|k| radius * (phi - 2.0*PI*k as Float / 3.0).cos() - shift).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::float::Float;
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+    use crate::torus::{solve_quartic, Torus};
+    use crate::vec3::{Color, Point3, Vec3};
+
+    /// How close results have to be, which f32 can't get to f64's 1e-9.
+    #[cfg(not(feature = "f32"))]
+    const TOLERANCE: Float = 1e-9;
+    #[cfg(feature = "f32")]
+    const TOLERANCE: Float = 1e-4;
+
+    #[test]
+    fn quartic_roots() {
+        // (t - 1)(t - 2)(t + 3)(t - 0.5)
+        let mut roots = solve_quartic(-0.5, -7.0, 9.5, -3.0);
+        roots.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let expected = [-3.0, 0.5, 1.0, 2.0];
+        assert_eq!(roots.len(), 4);
+        for (root, e) in roots.iter().zip(expected.iter()) {
+            assert!((root - e).abs() < TOLERANCE);
+        }
+
+        // t^4 + 1 has no real roots, and (t^2 - 4)(t^2 + 1) has two.
+        assert!(solve_quartic(0.0, 0.0, 0.0, 1.0).is_empty());
+        let mut roots = solve_quartic(0.0, -3.0, 0.0, -4.0);
+        roots.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert!(roots.len() == 2 && (roots[0] + 2.0).abs() < TOLERANCE && (roots[1] - 2.0).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn through_the_hole() {
+        let torus = Torus::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 2.0, 0.5,
+            Lambertian::from_color(Color::default()));
+
+        let down = Ray::new(Point3::new(0.0, 10.0, 0.0), Vec3::new(0.0, -2.0, 0.0), 0.0);
+        assert!(torus.hit(down, 0.001, Float::INFINITY).is_none());
+
+        // From far away along x the ray meets the outside of the ring, then the inside of the tube
+        // on the far side of the hole.
+        let across = Ray::new(Point3::new(1000.0, 0.0, 0.0), Vec3::new(-1.0, 0.0, 0.0), 0.0);
+        let hitrec = torus.hit(across, 0.001, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 997.5).abs() < 1e-9);
+        assert!((hitrec.normal - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-9);
+        let hitrec = torus.hit(across, 999.0, Float::INFINITY).unwrap();
+        assert!((hitrec.t - 1001.5).abs() < 1e-9);
+    }
+}
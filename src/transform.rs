@@ -0,0 +1,76 @@
+use crate::aabb::AABB;
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitRecord};
+use crate::matrix::Matrix4;
+use crate::ray::Ray;
+use crate::vec3::unit_vector;
+use std::sync::Arc;
+
+/**
+ * Places an object in the world with an affine matrix. Rays are taken into object space with the
+ * inverse, so the wrapped object never knows it moved. The ray direction isn't renormalized, which
+ * keeps the hit distance the same in both spaces.
+ */
+pub struct Transform {
+    thing: HitArc,
+    matrix: Matrix4,
+    inverse: Matrix4,
+}
+
+impl Transform {
+    /// Panics if the matrix can't be inverted, like a scale of zero along some axis.
+    pub fn new(thing: HitArc, matrix: Matrix4) -> HitArc {
+        let inverse = matrix.inverse().expect("Transform needs an invertible matrix");
+        Arc::new(Transform{ thing, matrix, inverse })
+    }
+
+    fn to_local(&self, ray: Ray) -> Ray {
+        Ray::new(
+            self.inverse.transform_point(ray.origin),
+            self.inverse.transform_vector(ray.direction),
+            ray.time,
+        )
+    }
+
+    fn to_world(&self, hitrec: &mut HitRecord) {
+        hitrec.point = self.matrix.transform_point(hitrec.point);
+        // The error is a distance along the object's own normal, so it stretches with it.
+        if hitrec.error > 0.0 {
+            hitrec.error *= self.matrix.transform_vector(hitrec.normal).length();
+        }
+        // The inverse transpose keeps the sign of dot(direction, normal), so the normal is still
+        // facing the right way for front_face.
+        hitrec.normal = unit_vector(self.inverse.transform_normal(hitrec.normal));
+        if !hitrec.tangent.near_zero() {
+            hitrec.tangent = unit_vector(self.matrix.transform_vector(hitrec.tangent));
+        }
+    }
+}
+
+impl Hit for Transform {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let mut hitrec = self.thing.hit(self.to_local(ray), t_min, t_max)?;
+        self.to_world(&mut hitrec);
+        Some(hitrec)
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        self.thing.occluded(self.to_local(ray), t_min, t_max)
+    }
+
+    fn hit_all<'a>(&'a self, ray: Ray, t_min: Float, t_max: Float, hits: &mut Vec<HitRecord<'a>>) {
+        let start = hits.len();
+        self.thing.hit_all(self.to_local(ray), t_min, t_max, hits);
+        for hitrec in &mut hits[start..] {
+            self.to_world(hitrec);
+        }
+    }
+
+    fn span(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<(Float, Float)> {
+        self.thing.span(self.to_local(ray), t_min, t_max)
+    }
+
+    fn bounding_box(&self, time0: Float, time1: Float) -> Option<AABB> {
+        self.thing.bounding_box(time0, time1).map(|local| self.matrix.transform_box(local))
+    }
+}
@@ -0,0 +1,204 @@
+use crate::aabb::AABB;
+use crate::float::Float;
+use crate::hit::{Hit, HitArc, HitRecord};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::vec3::{cross, dot, Point3, unit_vector, Vec3};
+use std::sync::Arc;
+
+pub struct Triangle {
+    vertices: [Point3; 3],
+    normals: Option<[Vec3; 3]>,
+    uvs: [(Float, Float); 3],
+    material: Material,
+}
+
+impl Triangle {
+    pub fn new(p0: Point3, p1: Point3, p2: Point3, material: Material) -> HitArc {
+        Triangle::with_attributes([p0, p1, p2], None, [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0)], material)
+    }
+
+    /// Per-vertex normals are interpolated for smooth shading when they're given. The UVs are
+    /// interpolated with the barycentric coordinates of the hit.
+    pub fn with_attributes(
+        vertices: [Point3; 3],
+        normals: Option<[Vec3; 3]>,
+        uvs: [(Float, Float); 3],
+        material: Material,
+    ) -> HitArc {
+        Arc::new(Triangle{ vertices, normals, uvs, material })
+    }
+}
+
+impl Hit for Triangle {
+    fn hit(&self, ray: Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let [p0, p1, p2] = self.vertices;
+        let (t, b) = intersect_triangle(ray, p0, p1, p2, t_min, t_max)?;
+        let u = b[0]*self.uvs[0].0 + b[1]*self.uvs[1].0 + b[2]*self.uvs[2].0;
+        let v = b[0]*self.uvs[0].1 + b[1]*self.uvs[1].1 + b[2]*self.uvs[2].1;
+        let mut hitrec = HitRecord::new(ray, t, unit_vector(cross(p1 - p0, p2 - p0)), u, v, &self.material);
+        // From the barycentrics the point is as close to the plane as the vertices allow, where
+        // ray.at(t) picks up the rounding in t as well.
+        hitrec.point = b[0]*p0 + b[1]*p1 + b[2]*p2;
+        if let Some(n) = self.normals {
+            hitrec.normal = shading_normal(hitrec.normal, b[0]*n[0] + b[1]*n[1] + b[2]*n[2]);
+        }
+        Some(hitrec)
+    }
+
+    fn occluded(&self, ray: Ray, t_min: Float, t_max: Float) -> bool {
+        let [p0, p1, p2] = self.vertices;
+        intersect_triangle(ray, p0, p1, p2, t_min, t_max).is_some()
+    }
+
+    fn bounding_box(&self, _time0: Float, _time1: Float) -> Option<AABB> {
+        Some(triangle_box(self.vertices[0], self.vertices[1], self.vertices[2]))
+    }
+}
+
+/**
+ * Watertight ray/triangle intersection following Woop, Benthin and Wald (2013). The vertices are
+ * translated to the ray origin, permuted so the ray direction's largest component becomes z, and
+ * sheared so that the ray points down +z. The edge functions are then evaluated in 2D, which
+ * guarantees that a ray crossing a shared edge hits at least one of the two triangles.
+ *
+ * Returns the ray parameter and the barycentric weights of p0, p1 and p2.
+ */
+pub fn intersect_triangle(
+    ray: Ray,
+    p0: Point3,
+    p1: Point3,
+    p2: Point3,
+    t_min: Float,
+    t_max: Float,
+) -> Option<(Float, [Float; 3])> {
+    let d = ray.direction.as_array();
+    let kz = max_dimension(ray.direction);
+    let kx = (kz + 1) % 3;
+    let ky = (kx + 1) % 3;
+
+    let permute = |p: Point3| {
+        let p = (p - ray.origin).as_array();
+        [p[kx], p[ky], p[kz]]
+    };
+    let mut a = permute(p0);
+    let mut b = permute(p1);
+    let mut c = permute(p2);
+
+    let sx = -d[kx] / d[kz];
+    let sy = -d[ky] / d[kz];
+    let sz = 1.0 / d[kz];
+
+    for p in [&mut a, &mut b, &mut c] {
+        p[0] += sx * p[2];
+        p[1] += sy * p[2];
+    }
+
+    let e0 = b[0]*c[1] - b[1]*c[0];
+    let e1 = c[0]*a[1] - c[1]*a[0];
+    let e2 = a[0]*b[1] - a[1]*b[0];
+
+    if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
+        return None;
+    }
+
+    let det = e0 + e1 + e2;
+    if det == 0.0 {
+        return None;
+    }
+
+    let t = (e0*a[2] + e1*b[2] + e2*c[2]) * sz / det;
+    if t < t_min || t_max < t {
+        return None;
+    }
+
+    Some((t, [e0 / det, e1 / det, e2 / det]))
+}
+
+/// Flips an interpolated shading normal into the same hemisphere as the (already face-oriented)
+/// geometric normal.
+pub fn shading_normal(geometric: Vec3, interpolated: Vec3) -> Vec3 {
+    let n = unit_vector(interpolated);
+    if dot(n, geometric) < 0.0 { -n } else { n }
+}
+
+/// The bounding box of three points, padded so that axis aligned triangles still have some volume.
+pub fn triangle_box(p0: Point3, p1: Point3, p2: Point3) -> AABB {
+    let pad = Vec3::new(0.0001, 0.0001, 0.0001);
+    let min = Point3::new(
+        p0.x.min(p1.x).min(p2.x),
+        p0.y.min(p1.y).min(p2.y),
+        p0.z.min(p1.z).min(p2.z),
+    );
+    let max = Point3::new(
+        p0.x.max(p1.x).max(p2.x),
+        p0.y.max(p1.y).max(p2.y),
+        p0.z.max(p1.z).max(p2.z),
+    );
+    AABB::new(min - pad, max + pad)
+}
+
+fn max_dimension(v: Vec3) -> usize {
+    let (x, y, z) = (v.x.abs(), v.y.abs(), v.z.abs());
+    if x > y && x > z {
+        0
+    } else if y > z {
+        1
+    } else {
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::float::Float;
+    use crate::ray::Ray;
+    use crate::triangle::intersect_triangle;
+    use crate::vec3::{Point3, Vec3};
+
+    fn quad() -> [Point3; 4] {
+        [
+            Point3::new(-1.0, -1.0, 0.0),
+            Point3::new( 1.0, -1.0, 0.0),
+            Point3::new( 1.0,  1.0, 0.0),
+            Point3::new(-1.0,  1.0, 0.0),
+        ]
+    }
+
+    #[test]
+    fn hit_and_barycentrics() {
+        let [p0, p1, p2, _] = quad();
+        let ray = Ray::new(Point3::new(0.5, -0.5, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let (t, b) = intersect_triangle(ray, p0, p1, p2, 0.001, Float::INFINITY).unwrap();
+
+        assert!((t - 5.0).abs() < 1e-12);
+        assert!((b[0] + b[1] + b[2] - 1.0).abs() < 1e-12);
+        let p = b[0]*p0 + b[1]*p1 + b[2]*p2;
+        assert!((p - Point3::new(0.5, -0.5, 0.0)).length() < 1e-12);
+    }
+
+    #[test]
+    fn miss_and_range() {
+        let [p0, p1, p2, _] = quad();
+        let outside = Ray::new(Point3::new(-0.5, 0.5, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(intersect_triangle(outside, p0, p1, p2, 0.001, Float::INFINITY).is_none());
+
+        let inside = Ray::new(Point3::new(0.5, -0.5, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(intersect_triangle(inside, p0, p1, p2, 0.001, 4.0).is_none());
+    }
+
+    #[test]
+    fn shared_edge_is_watertight() {
+        let [p0, p1, p2, p3] = quad();
+        // Rays along the shared diagonal from p0 to p2, with directions that aren't axis aligned.
+        for i in 1..100 {
+            let s = -1.0 + 2.0 * (i as Float) / 100.0;
+            let origin = Point3::new(s, s, 0.0) - 3.0 * Vec3::new(0.3, -0.2, -1.0);
+            let ray = Ray::new(origin, Vec3::new(0.3, -0.2, -1.0), 0.0);
+            let a = intersect_triangle(ray, p0, p1, p2, 0.0, Float::INFINITY);
+            let b = intersect_triangle(ray, p0, p2, p3, 0.0, Float::INFINITY);
+            assert!(a.is_some() || b.is_some(), "ray {} slipped through the shared edge", i);
+        }
+    }
+}
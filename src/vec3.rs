@@ -1,36 +1,37 @@
 use std::{fmt, ops};
-use crate::rand::{rf64, randrange};
+use crate::float::Float;
+use crate::rand::{rfloat, randrange};
 
 #[derive(Debug, Default, Copy, Clone)]
 pub struct Vec3 {
-    pub x: f64,
-    pub y: f64,
-    pub z: f64
+    pub x: Float,
+    pub y: Float,
+    pub z: Float
 }
 
 impl Vec3 {
 
-    pub fn new(x: f64, y: f64, z: f64) -> Vec3 {
+    pub fn new(x: Float, y: Float, z: Float) -> Vec3 {
         Vec3{x, y, z}
     }
 
-    pub fn from_array(p: [f64; 3]) -> Vec3 {
+    pub fn from_array(p: [Float; 3]) -> Vec3 {
         Vec3{ x: p[0], y: p[1], z: p[2] }
     }
 
-    pub fn length_squared(&self) -> f64 {
+    pub fn length_squared(&self) -> Float {
         self.x*self.x + self.y*self.y + self.z*self.z
     }
 
-    pub fn length(&self) -> f64 {
+    pub fn length(&self) -> Float {
         self.length_squared().sqrt()
     }
 
     pub fn random() -> Vec3 {
-        Vec3::new(rf64(), rf64(), rf64())
+        Vec3::new(rfloat(), rfloat(), rfloat())
     }
 
-    pub fn randrange(min: f64, max: f64) -> Vec3 {
+    pub fn randrange(min: Float, max: Float) -> Vec3 {
         Vec3::new(
             randrange(min, max),
             randrange(min, max),
@@ -78,7 +79,7 @@ impl Vec3 {
         self.z = other.z;
     }
 
-    pub fn set(&mut self, x: f64, y: f64, z: f64) {
+    pub fn set(&mut self, x: Float, y: Float, z: Float) {
         self.x = x;
         self.y = y;
         self.z = z;
@@ -89,7 +90,7 @@ impl Vec3 {
         return self.x.abs() < s && self.y.abs() < s && self.z.abs() < s;
     }
 
-    pub fn as_array(&self) -> [f64; 3] {
+    pub fn as_array(&self) -> [Float; 3] {
         [self.x, self.y, self.z]
     }
 }
@@ -97,7 +98,7 @@ impl Vec3 {
 pub type Color = Vec3;
 pub type Point3 = Vec3;
 
-pub fn dot(u: Vec3, v: Vec3) -> f64 {
+pub fn dot(u: Vec3, v: Vec3) -> Float {
     u.x*v.x + u.y*v.y + u.z*v.z
 }
 
@@ -127,15 +128,15 @@ impl ops::Add<Vec3> for Vec3 {
     }
 }
 
-impl ops::Add<f64> for Vec3 {
+impl ops::Add<Float> for Vec3 {
     type Output = Vec3;
 
-    fn add(self, rhs: f64) -> Vec3 {
+    fn add(self, rhs: Float) -> Vec3 {
         Vec3::new(self.x+rhs, self.y+rhs, self.z+rhs)
     }
 }
 
-impl ops::Add<Vec3> for f64 {
+impl ops::Add<Vec3> for Float {
     type Output = Vec3;
 
     fn add(self, rhs: Vec3) -> Vec3 {
@@ -151,8 +152,8 @@ impl ops::AddAssign<Vec3> for Vec3 {
     }
 }
 
-impl ops::AddAssign<f64> for Vec3 {
-    fn add_assign(&mut self, rhs: f64) {
+impl ops::AddAssign<Float> for Vec3 {
+    fn add_assign(&mut self, rhs: Float) {
         self.x += rhs;
         self.y += rhs;
         self.z += rhs;
@@ -167,15 +168,15 @@ impl ops::Mul<Vec3> for Vec3 {
     }
 }
 
-impl ops::Mul<f64> for Vec3 {
+impl ops::Mul<Float> for Vec3 {
     type Output = Vec3;
 
-    fn mul(self, rhs: f64) -> Vec3 {
+    fn mul(self, rhs: Float) -> Vec3 {
         Vec3::new(self.x*rhs, self.y*rhs, self.z*rhs)
     }
 }
 
-impl ops::Mul<Vec3> for f64 {
+impl ops::Mul<Vec3> for Float {
     type Output = Vec3;
 
     fn mul(self, rhs: Vec3) -> Vec3 {
@@ -191,24 +192,24 @@ impl ops::MulAssign<Vec3> for Vec3 {
     }
 }
 
-impl ops::MulAssign<f64> for Vec3 {
-    fn mul_assign(&mut self, rhs: f64) {
+impl ops::MulAssign<Float> for Vec3 {
+    fn mul_assign(&mut self, rhs: Float) {
         self.x *= rhs;
         self.y *= rhs;
         self.z *= rhs;
     }
 }
 
-impl ops::Div<f64> for Vec3 {
+impl ops::Div<Float> for Vec3 {
     type Output = Vec3;
 
-    fn div(self, rhs: f64) -> Vec3 {
+    fn div(self, rhs: Float) -> Vec3 {
         Vec3::new(self.x/rhs, self.y/rhs, self.z/rhs)
     }
 }
 
-impl ops::DivAssign<f64> for Vec3 {
-    fn div_assign(&mut self, rhs: f64) {
+impl ops::DivAssign<Float> for Vec3 {
+    fn div_assign(&mut self, rhs: Float) {
         self.x /= rhs;
         self.y /= rhs;
         self.z /= rhs;
@@ -235,18 +236,19 @@ impl ops::Neg for Vec3 {
 #[cfg(test)]
 mod tests {
 
+    use crate::float::Float;
     use crate::vec3::Vec3;
 
     #[test]
     fn length_squared() {
         let v = Vec3::new(1., 1., 1.);
-        assert_eq!(v.length_squared(), 3f64)
+        assert_eq!(v.length_squared(), 3.0)
     }
 
     #[test]
     fn length() {
         let v = Vec3::new(1., 1., 1.);
-        assert_eq!(v.length(), 3f64.sqrt())
+        assert_eq!(v.length(), Float::sqrt(3.0))
     }
 
     #[test]
@@ -299,13 +301,13 @@ mod tests {
         assert_eq!(v5.z, 32.);
 
         v5 *= v5;
-        assert_eq!(v5.x, 256f64);
-        assert_eq!(v5.y, 576f64);
-        assert_eq!(v5.z, 1024f64);
+        assert_eq!(v5.x, 256.0);
+        assert_eq!(v5.y, 576.0);
+        assert_eq!(v5.z, 1024.0);
 
         v5 *= 10.;
-        assert_eq!(v5.x, 2560f64);
-        assert_eq!(v5.y, 5760f64);
-        assert_eq!(v5.z, 10240f64);
+        assert_eq!(v5.x, 2560.0);
+        assert_eq!(v5.y, 5760.0);
+        assert_eq!(v5.z, 10240.0);
     }
 }